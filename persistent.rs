@@ -8,17 +8,252 @@ This is a collection of persistent data structures
 modeled after purely functional programming languages
 ( https://en.wikipedia.org/wiki/Persistent_data_structure ).
 
-Currently, only an inductive linked list is implemented.
+So far there are:
+
+* `list::List`, an inductive linked list
+* `hashmap::HashMap`, a hash array mapped trie
+* `vector::Vector`, a bit-partitioned vector trie
+* `treemap::TreeMap`, a balanced (AVL) binary search tree
+* `treeset::TreeSet`, a set built on TreeMap
+* `queue::Queue`, a FIFO queue made of two lists
+* `heap::Heap`, a leftist heap (priority queue)
+* `catlist::CatList`, a list with O(1) append
+* `ralist::RandomAccessList`, a skew binary random-access list
+* `stream::Stream`, a lazy list with memoized tails
+* `fingertree::FingerTree`, a finger tree annotated with monoidal measures
+* `intmap::IntMap`, a Patricia trie keyed by uint
+* `rope::Rope`, a string made of a finger tree of chunks
+* `unionfind::UnionFind`, disjoint sets on rerooting arrays
+* `bag::Bag`, a multiset built on TreeMap
+* `intervalmap::IntervalMap`, a map from intervals, on the finger tree
+* `interned::Interner`, hash-consing for Lists
+* `history::History`, undo and redo over versions of any of them
+* `buffer::UndoableBuffer`, editable text: a Rope with a History
+
+and, for sharing between tasks, `sync::list::List`.
+`arbitrary` has random instances of them, for property tests.
 
 */
 
+extern mod extra;
+
 use persistent::list::List;
 
+/// zip_lists!(a, b, c) zips two, three, or four Lists into a List of tuples,
+/// as long as the shortest of them.  Four is the limit: the tuples are
+/// flat, and each arity needs its own zip.  Any other number of lists
+/// fails to compile, naming the limit.  (For more, zip zips: the
+/// members come out as nested tuples.)
+macro_rules! zip_lists(
+  ($a:expr, $b:expr) => (($a).zip(&($b)));
+  ($a:expr, $b:expr, $c:expr) => (($a).zip3(&($b), &($c)));
+  ($a:expr, $b:expr, $c:expr, $d:expr) => (($a).zip4(&($b), &($c), &($d)));
+  ($($other:expr),*) => (zip_lists_takes_two_to_four_lists!())
+)
+
+/// list!(1, 2, 3) is the List of those members, in that order:
+/// List::cons(1, List::cons(2, List::cons(3, List::nil()))).
+macro_rules! list(
+  () => (::persistent::list::List::nil());
+  ($x:expr) => (::persistent::list::List::cons($x, ::persistent::list::List::nil()));
+  ($x:expr, $($rest:expr),+) => (::persistent::list::List::cons($x, list!($($rest),+)))
+)
+
+/// pvec![1, 2, 3] is the Vector of those members, in that order.
+macro_rules! pvec(
+  ($($x:expr),*) => (::persistent::vector::Vector::from_vec(~[$($x),*]))
+)
+
+/// pmap!{k1 => v1, k2 => v2} is the TreeMap with those entries
+/// (where a key is repeated, the last value wins).
+macro_rules! pmap(
+  ($($k:expr => $v:expr),*) => ({
+    let mut builder = ::persistent::treemap::TreeMapBuilder::new();
+    $(builder.insert($k, $v);)*
+    builder.finish()
+  })
+)
+
+/**
+persistent_record!(Model { items: List<int> => with_items, ... })
+defines, for the struct Model, a method per field that makes a copy
+of the struct with just that field replaced:
+`model.with_items(new_items)`.  The struct has to be Clone;
+since its persistent fields are cheap to clone, so is it.
+*/
+macro_rules! persistent_record(
+  ($name:ident { $($field:ident : $ty:ty => $with:ident),+ }) => (
+    impl $name {
+      $(
+      pub fn $with(&self, value: $ty) -> $name {
+        $name{$field: value, .. self.clone()}
+      }
+      )+
+    }
+  )
+)
+
 pub mod persistent {
+
+use extra::serialize::{Encodable, Decodable, Encoder, Decoder};
+
+/// Version of the format the structures' Encodable impls write.
+/// Each encodes as a struct of this version and its members.
+/// Each Decodable matches on the version it reads, so that snapshots
+/// from older versions still load: when the format of some structure
+/// changes, this goes up by one, and that structure's decode keeps an
+/// arm for the old number that reads the old layout.  Versions newer
+/// than this one fail to decode.
+pub static FORMAT_VERSION : uint = 1;
+
+// Encode what f writes as the members of a versioned struct.
+fn encode_versioned<S: Encoder>(s: &mut S, name: &str, f: |&mut S|) {
+  s.emit_struct(name, 2, |s| {
+    s.emit_struct_field("version", 0, |s| FORMAT_VERSION.encode(s));
+    s.emit_struct_field("members", 1, |s| f(s));
+  })
+}
+
+// Decode the members of a versioned struct with f, which is given
+// the version they were written in.
+fn decode_versioned<D: Decoder, T>(d: &mut D, name: &str, f: |&mut D, uint| -> T) -> T {
+  d.read_struct(name, 2, |d| {
+    let version : uint = d.read_struct_field("version", 0, |d| Decodable::decode(d));
+    d.read_struct_field("members", 1, |d| f(d, version))
+  })
+}
+
+// For the arm of a decode's match that no version it knows matched.
+fn unknown_version<T>(name: &str, version: uint) -> T {
+  fail!("{}: can't decode format version {} (this is version {})",
+        name, version, FORMAT_VERSION)
+}
+
+// Splits at the seps (an ASCII character) that aren't inside any
+// brackets or braces, for the structures' FromStr impls.
+fn split_top_level<'a>(s: &'a str, sep: char) -> ~[&'a str] {
+  let mut pieces = ~[];
+  let mut depth = 0;
+  let mut start = 0;
+  for i in range(0, s.len()) {
+    // Indexing gives bytes, but all the bytes we look for are ASCII,
+    // which never occur inside a multibyte UTF-8 character.
+    match s[i] as char {
+      '[' | '{' | '(' => depth += 1,
+      ']' | '}' | ')' => depth -= 1,
+      c if c == sep && depth == 0 => {
+        pieces.push(s.slice(start, i));
+        start = i + 1;
+      }
+      _ => ()
+    }
+  }
+  pieces.push(s.slice(start, s.len()));
+  pieces
+}
+
+// Encode x as JSON and decode that again, for the structures' tests:
+// the text and the decoded copy.
+#[cfg(test)]
+fn json_round_trip<T: Encodable<::extra::json::Encoder>+Decodable<::extra::json::Decoder>>(x: &T)
+    -> (~str, T) {
+  use extra::json;
+  use std::io::mem::MemWriter;
+  use std::str;
+  let mut writer = MemWriter::new();
+  {
+    let mut encoder = json::Encoder::new(&mut writer as &mut Writer);
+    x.encode(&mut encoder);
+  }
+  let text = str::from_utf8_owned(writer.inner());
+  let mut decoder = json::Decoder::new(json::from_str(text.as_slice()).unwrap());
+  let decoded = Decodable::decode(&mut decoder);
+  (text, decoded)
+}
+
+pub mod traits {
+
+/// Operations that every persistent sequence supports,
+/// so that code can be written without choosing a particular one.
+/// Nothing here modifies self; "changed" sequences are new values
+/// that share structure with the old.
+pub trait PersistentSeq<T> : Container {
+  /// Create an empty sequence.
+  fn empty() -> Self;
+  /// Create a sequence with x added at whichever end is cheap
+  /// for this structure (the front, for a List).  Generic code
+  /// shouldn't depend on which end that is.
+  fn push(&self, x: T) -> Self;
+  /// The member at index i, counting from the front.
+  fn get<'a>(&'a self, i: uint) -> Option<&'a T>;
+  /// Call f on each member, front to back, until it returns false.
+  /// Returns false if f did.
+  fn each(&self, f: |&T| -> bool) -> bool;
+}
+
+/// Operations that every persistent map supports, on top of
+/// the usual lookups, so that code can be written without choosing
+/// a particular one.
+pub trait PersistentMap<K, V> : Map<K, V> {
+  /// Create an empty map.
+  fn empty() -> Self;
+  /// Create a map that maps k to v, and everything else as self does.
+  fn insert(&self, k: K, v: V) -> Self;
+  /// Create a map without k, and everything else as self has it.
+  fn remove(&self, k: &K) -> Self;
+  /// Create a map that maps k to f of its value in self (None if
+  /// self doesn't have it), and everything else as self does.
+  fn update(&self, k: K, f: |Option<&V>| -> V) -> Self {
+    let v = f(self.find(&k));
+    self.insert(k, v)
+  }
+}
+
+/// update for maps of maps: create a copy of outer in which the map
+/// at k1 has k2 mapped to f of its old value there (None if either
+/// map didn't have its key).  Only the two maps' paths to the keys
+/// are rebuilt.  Deeper nestings can be updated by nesting updates
+/// the same way.
+pub fn update_in<K1, K2, V, Inner: PersistentMap<K2, V>, Outer: PersistentMap<K1, Inner>>(
+    outer: &Outer, k1: K1, k2: K2, f: |Option<&V>| -> V) -> Outer {
+  let inner = match outer.find(&k1) {
+    Some(inner) => inner.update(k2, f),
+    None => {
+      let empty : Inner = PersistentMap::empty();
+      empty.update(k2, f)
+    }
+  };
+  outer.insert(k1, inner)
+}
+
+/// One difference between two versions of a map, as the maps'
+/// diff_iter methods find them: a key only the new version has, a key
+/// only the old version has, or a key whose value changed (from the
+/// first value to the second).
+#[deriving(Clone, Eq)]
+pub enum Change<K, V> {
+  Added(K, V),
+  Removed(K, V),
+  Updated(K, V, V)
+}
+
+}
+
 pub mod list {
 
 // Is reference-counting the best choice for the shared immutable data?
 use std::rc::Rc;
+use persistent::traits::PersistentSeq;
+use std::ptr;
+use std::num::{Zero, One};
+use std::iter::{AdditiveIterator, MultiplicativeIterator};
+use std::mem;
+use std::hashmap::{HashMap, HashSet};
+use std::to_bytes::{IterBytes, Cb};
+use std::fmt;
+use std::from_str::{FromStr, from_str};
+use extra::serialize::{Encodable, Decodable, Encoder, Decoder};
+use persistent::{encode_versioned, decode_versioned, unknown_version, split_top_level};
 
 /// Persistent cons/nil list.
 /// O(1) access to the head of the list.
@@ -26,10 +261,11 @@ use std::rc::Rc;
 /// List members cannot be moved from,
 /// because a List might be sharing data with other Lists.
 
-// Rc's Eq/Ord compares the contained data, not the pointer.
-#[deriving(Clone, DeepClone, Eq, Ord, TotalEq, TotalOrd)]
+#[deriving(Clone, DeepClone)]
 pub struct List<T> {
-  priv node : Rc<Node<T>>
+  priv node : Rc<Node<T>>,
+  // The number of members, so that len() is O(1).
+  priv len : uint
 }
 
 #[deriving(Clone, DeepClone, Eq, Ord, TotalEq, TotalOrd)]
@@ -38,6 +274,109 @@ pub enum Node<T> {
   Cons(T, List<T>)
 }
 
+// Comparisons go by the members, in order, but stop as soon as they
+// reach a node the two lists share (everything from there on being
+// the same), so comparing versions of a list that share a long tail
+// is quick.  Lists of different lengths can't be equal.  None of them
+// recurse.
+impl<T: Eq> Eq for List<T> {
+  fn eq(&self, other: &List<T>) -> bool {
+    if self.len != other.len {
+      return false;
+    }
+    let (mut a, mut b) = (self, other);
+    loop {
+      if a.ptr_eq(b) {
+        return true;
+      }
+      match (a.node.borrow(), b.node.borrow()) {
+        (&Cons(ref x, ref xs), &Cons(ref y, ref ys)) => {
+          if *x != *y {
+            return false;
+          }
+          a = xs;
+          b = ys;
+        }
+        _ => return true
+      }
+    }
+  }
+}
+
+impl<T: Ord> Ord for List<T> {
+  fn lt(&self, other: &List<T>) -> bool {
+    let (mut a, mut b) = (self, other);
+    loop {
+      if a.ptr_eq(b) {
+        return false;
+      }
+      match (a.node.borrow(), b.node.borrow()) {
+        (_, &Nil) => return false,
+        (&Nil, _) => return true,
+        (&Cons(ref x, ref xs), &Cons(ref y, ref ys)) => {
+          if *x < *y {
+            return true;
+          }
+          if *y < *x {
+            return false;
+          }
+          a = xs;
+          b = ys;
+        }
+      }
+    }
+  }
+}
+
+impl<T: TotalEq> TotalEq for List<T> {
+  fn equals(&self, other: &List<T>) -> bool {
+    if self.len != other.len {
+      return false;
+    }
+    let (mut a, mut b) = (self, other);
+    loop {
+      if a.ptr_eq(b) {
+        return true;
+      }
+      match (a.node.borrow(), b.node.borrow()) {
+        (&Cons(ref x, ref xs), &Cons(ref y, ref ys)) => {
+          if !x.equals(y) {
+            return false;
+          }
+          a = xs;
+          b = ys;
+        }
+        _ => return true
+      }
+    }
+  }
+}
+
+impl<T: TotalOrd> TotalOrd for List<T> {
+  fn cmp(&self, other: &List<T>) -> Ordering {
+    let (mut a, mut b) = (self, other);
+    loop {
+      if a.ptr_eq(b) {
+        return Equal;
+      }
+      match (a.node.borrow(), b.node.borrow()) {
+        (&Nil, &Nil) => return Equal,
+        (&Nil, _) => return Less,
+        (_, &Nil) => return Greater,
+        (&Cons(ref x, ref xs), &Cons(ref y, ref ys)) => {
+          match x.cmp(y) {
+            Equal => {
+              a = xs;
+              b = ys;
+            }
+            unequal => return unequal
+          }
+        }
+      }
+    }
+  }
+}
+
 impl<'self, T> Iterator<&'self T> for &'self List<T> {
   fn next(&mut self) -> Option<&'self T> {
     match *self.node.borrow() {
@@ -48,23 +387,546 @@ impl<'self, T> Iterator<&'self T> for &'self List<T> {
       }
     }
   }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    (self.len, Some(self.len))
+  }
+}
+
+/// Iterator over a list's members, front to back.
+/// (Its own type, so that List's map and filter don't
+/// hide the iterator adaptors of the same names.)
+pub struct ListIterator<'self, T> {
+  priv rest : &'self List<T>
+}
+
+impl<'self, T> Iterator<&'self T> for ListIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    self.rest.next()
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    (self.rest.len, Some(self.rest.len))
+  }
+}
+
+// std's AdditiveIterator and MultiplicativeIterator cover every
+// iterator of values, which includes MoveIterator but not this one
+// (it yields references), so it forwards to them on copies.
+impl<'self, T: Add<T, T>+Zero+Clone> ListIterator<'self, T> {
+  /// The sum of the members still to come (zero if there are none).
+  pub fn sum(&mut self) -> T {
+    self.map(|x| x.clone()).sum()
+  }
+}
+
+impl<'self, T: Mul<T, T>+One+Clone> ListIterator<'self, T> {
+  /// The product of the members still to come (one if there are none).
+  pub fn product(&mut self) -> T {
+    self.map(|x| x.clone()).product()
+  }
+}
+
+/// Iterator that goes round and round a list forever
+/// (or stops immediately if the list is empty).
+pub struct Cycle<'self, T> {
+  priv start : &'self List<T>,
+  priv rest : &'self List<T>
+}
+
+impl<'self, T> Iterator<&'self T> for Cycle<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    match *self.rest.node.borrow() {
+      Cons(ref x, ref xs) => {
+        self.rest = xs;
+        Some(x)
+      }
+      Nil => match *self.start.node.borrow() {
+        Nil => None,
+        Cons(ref x, ref xs) => {
+          self.rest = xs;
+          Some(x)
+        }
+      }
+    }
+  }
+}
+
+/// Iterator over every suffix of a list, longest first,
+/// ending with the empty list.
+pub struct Tails<'self, T> {
+  priv rest : Option<&'self List<T>>
+}
+
+impl<'self, T: Clone> Iterator<List<T>> for Tails<'self, T> {
+  fn next(&mut self) -> Option<List<T>> {
+    match self.rest {
+      None => None,
+      Some(list) => {
+        self.rest = match *list.node.borrow() {
+          Nil => None,
+          Cons(_, ref xs) => Some(xs)
+        };
+        Some(list.clone())
+      }
+    }
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    let n = match self.rest { None => 0, Some(list) => list.len + 1 };
+    (n, Some(n))
+  }
+}
+
+/// Iterator over all orderings of a list's members;
+/// see List::permutations.
+pub struct Permutations<'self, T> {
+  priv members : ~[&'self T],
+  // Which member is at each position of the current ordering.
+  priv order : ~[uint],
+  // tails[i] is the current ordering from position i on.
+  priv tails : ~[List<T>],
+  priv done : bool
+}
+
+impl<'self, T: Clone+Freeze> Permutations<'self, T> {
+  // Re-cons positions i down to 0 onto the unchanged tail after i.
+  fn rebuild(&mut self, i: uint) {
+    for j in range(0, i + 1).invert() {
+      self.tails[j] = List::cons((*self.members[self.order[j]]).clone(), self.tails[j + 1].clone());
+    }
+  }
+}
+
+// The next ordering is the previous one in lexicographic order of the
+// positions read back to front, which changes only the positions up
+// to the first one (from the front) that's greater than the one
+// before it: swap it with the first position before it that's less,
+// then reverse everything before it.
+impl<'self, T: Clone+Freeze> Iterator<List<T>> for Permutations<'self, T> {
+  fn next(&mut self) -> Option<List<T>> {
+    if self.done {
+      return None;
+    }
+    let result = self.tails[0].clone();
+    let n = self.order.len();
+    match range(1, n).find(|&p| self.order[p] > self.order[p - 1]) {
+      None => self.done = true,
+      Some(p) => {
+        let q = range(0, p).find(|&q| self.order[q] < self.order[p]).unwrap();
+        self.order.swap(p, q);
+        self.order.mut_slice(0, p).reverse();
+        self.rebuild(p);
+      }
+    }
+    Some(result)
+  }
+}
+
+/// Iterator over all ways to choose some of a list's members;
+/// see List::combinations.
+pub struct Combinations<'self, T> {
+  priv members : ~[&'self T],
+  // The positions in members of the current choice, ascending.
+  priv chosen : ~[uint],
+  // tails[i] is the current choice from its i'th member on.
+  priv tails : ~[List<T>],
+  priv done : bool
+}
+
+impl<'self, T: Clone+Freeze> Combinations<'self, T> {
+  // Re-cons members i down to 0 onto the unchanged tail after i.
+  fn rebuild(&mut self, i: uint) {
+    for j in range(0, i + 1).invert() {
+      self.tails[j] = List::cons((*self.members[self.chosen[j]]).clone(), self.tails[j + 1].clone());
+    }
+  }
+}
+
+// The next choice moves the first chosen member that can move one
+// place later (without running into the next chosen one) and puts the
+// ones before it back at the start.
+impl<'self, T: Clone+Freeze> Iterator<List<T>> for Combinations<'self, T> {
+  fn next(&mut self) -> Option<List<T>> {
+    if self.done {
+      return None;
+    }
+    let result = self.tails[0].clone();
+    let (n, k) = (self.members.len(), self.chosen.len());
+    let movable = range(0, k).find(|&i| {
+      let limit = if i + 1 < k { self.chosen[i + 1] } else { n };
+      self.chosen[i] + 1 < limit
+    });
+    match movable {
+      None => self.done = true,
+      Some(i) => {
+        self.chosen[i] += 1;
+        for j in range(0, i) {
+          self.chosen[j] = j;
+        }
+        self.rebuild(i);
+      }
+    }
+    Some(result)
+  }
+}
+
+/// Iterator over every prefix of a list, shortest (empty) first.
+pub struct Inits<'self, T> {
+  // The previous prefix, kept backwards so that extending it is O(1).
+  priv reversed : List<T>,
+  priv rest : Option<&'self List<T>>
+}
+
+impl<'self, T: Clone+Freeze> Iterator<List<T>> for Inits<'self, T> {
+  fn next(&mut self) -> Option<List<T>> {
+    match self.rest {
+      None => None,
+      Some(list) => {
+        let prefix = self.reversed.reverse();
+        match *list.node.borrow() {
+          Nil => self.rest = None,
+          Cons(ref x, ref xs) => {
+            self.reversed = List::cons(x.clone(), self.reversed.clone());
+            self.rest = Some(xs);
+          }
+        }
+        Some(prefix)
+      }
+    }
+  }
+}
+
+/// Iterator over each member of a list paired with the one after it.
+pub struct Pairwise<'self, T> {
+  priv rest : &'self List<T>
+}
+
+impl<'self, T> Iterator<(&'self T, &'self T)> for Pairwise<'self, T> {
+  fn next(&mut self) -> Option<(&'self T, &'self T)> {
+    match *self.rest.node.borrow() {
+      Nil => None,
+      Cons(ref x, ref xs) => match *xs.node.borrow() {
+        Nil => None,
+        Cons(ref y, _) => {
+          self.rest = xs;
+          Some((x, y))
+        }
+      }
+    }
+  }
+}
+
+/// Iterator over a list's members, back to front.
+pub struct RevIterator<'self, T> {
+  // The members still to come, the next one last.
+  priv members : ~[&'self T]
+}
+
+impl<'self, T> Iterator<&'self T> for RevIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    let n = self.members.len();
+    if n == 0 {
+      return None;
+    }
+    let x = self.members[n - 1];
+    self.members.truncate(n - 1);
+    Some(x)
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    (self.members.len(), Some(self.members.len()))
+  }
+}
+
+/// Iterator that consumes a list, yielding its members.
+pub struct MoveIterator<T> {
+  priv rest : List<T>
+}
+
+impl<T: Clone> Iterator<T> for MoveIterator<T> {
+  fn next(&mut self) -> Option<T> {
+    let (x, xs) = match *self.rest.node.borrow() {
+      Nil => return None,
+      Cons(ref x, ref xs) => (x.clone(), xs.clone())
+    };
+    self.rest = xs;
+    Some(x)
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    (self.rest.len, Some(self.rest.len))
+  }
+}
+
+/// Iterator over the distinct nodes of some lists: every node that any
+/// of them is made of, exactly once, each as the list starting there.
+pub struct UniqueNodes<'self, T> {
+  priv versions : ~[&'self List<T>],
+  priv next_version : uint,
+  priv rest : Option<&'self List<T>>,
+  priv seen : HashSet<uint>
+}
+
+impl<'self, T> Iterator<&'self List<T>> for UniqueNodes<'self, T> {
+  fn next(&mut self) -> Option<&'self List<T>> {
+    loop {
+      match self.rest {
+        Some(list) => {
+          // Lists only share suffixes, so once we reach a node that
+          // has been seen, the rest of this list has been too.
+          if !self.seen.insert(node_address(list)) {
+            self.rest = None;
+            continue;
+          }
+          self.rest = match *list.node.borrow() {
+            Nil => None,
+            Cons(_, ref xs) => Some(xs)
+          };
+          return Some(list);
+        }
+        None => {
+          if self.next_version == self.versions.len() {
+            return None;
+          }
+          self.rest = Some(self.versions[self.next_version]);
+          self.next_version += 1;
+        }
+      }
+    }
+  }
 }
 
 impl<T> List<T> {
-  /// Lists are iterable.
-  pub fn iter<'t>(&'t self) -> &'t List<T> {
-    self
+  /// Iterate over the members, front to back.
+  pub fn iter<'t>(&'t self) -> ListIterator<'t, T> {
+    ListIterator{rest: self}
+  }
+  /// The first member, if the list isn't empty.
+  pub fn head<'t>(&'t self) -> Option<&'t T> {
+    match *self.node.borrow() {
+      Nil => None,
+      Cons(ref x, _) => Some(x)
+    }
+  }
+  /// The rest of the list after the first member (shared, not
+  /// copied), if the list isn't empty.
+  pub fn tail(&self) -> Option<List<T>> {
+    match *self.node.borrow() {
+      Nil => None,
+      Cons(_, ref xs) => Some(xs.clone())
+    }
+  }
+  /// The member at index n, counting from 0 at the front: O(n).
+  pub fn get<'t>(&'t self, n: uint) -> Option<&'t T> {
+    self.iter().nth(n)
+  }
+  /// The last member, if the list isn't empty: O(n).
+  pub fn last<'t>(&'t self) -> Option<&'t T> {
+    self.iter().last()
+  }
+  /// Iterate over the members, back to front.  A cons list can only
+  /// be walked forwards, so this first collects a pointer to each
+  /// member: O(n) time and memory before the first one comes out.
+  pub fn rev_iter<'t>(&'t self) -> RevIterator<'t, T> {
+    RevIterator{members: self.iter().collect()}
+  }
+  /// The list after its first n members (empty if it's shorter):
+  /// a tail of self, shared rather than copied.
+  pub fn drop(&self, n: uint) -> List<T> {
+    let mut rest = self;
+    for _ in range(0, n) {
+      match *rest.node.borrow() {
+        Nil => break,
+        Cons(_, ref xs) => rest = xs
+      }
+    }
+    rest.clone()
+  }
+  /// Create a list of f of each member, in order.
+  pub fn map<U: Freeze>(&self, f: |&T| -> U) -> List<U> {
+    let mut result = ListBuilder::new();
+    for x in self.iter() {
+      result.push(f(x));
+    }
+    result.finish()
+  }
+  /// Combine the members front to back: f(... f(f(init, x0), x1) ..., xn).
+  pub fn foldl<A>(&self, init: A, f: |A, &T| -> A) -> A {
+    let mut acc = init;
+    for x in self.iter() {
+      acc = f(acc, x);
+    }
+    acc
+  }
+  /// Combine the members back to front: f(x0, f(x1, ... f(xn, init) ...)).
+  pub fn foldr<A>(&self, init: A, f: |&T, A| -> A) -> A {
+    let members : ~[&T] = self.iter().collect();
+    let mut acc = init;
+    for x in members.rev_iter() {
+      acc = f(*x, acc);
+    }
+    acc
   }
   /// Use this to pattern match on Nil vs Cons.
   pub fn node<'t>(&'t self) -> &'t Node<T> {
     self.node.borrow()
   }
+  /// Use this to pattern match on view::Nil vs view::Cons
+  /// by reference, without going through Node.
+  pub fn view<'t>(&'t self) -> view::ListView<'t, T> {
+    match *self.node.borrow() {
+      Nil => view::Nil,
+      Cons(ref x, ref xs) => view::Cons(x, xs)
+    }
+  }
+  /// Iterate over the list's members, starting over from the
+  /// beginning each time the end is reached.  Never ends unless
+  /// the list is empty.
+  pub fn cycle<'t>(&'t self) -> Cycle<'t, T> {
+    Cycle{start: self, rest: self}
+  }
+  /// Iterate over self, then self's tail, then its tail, and so on,
+  /// down to the empty list.  The suffixes are shared, not copied.
+  pub fn tails<'t>(&'t self) -> Tails<'t, T> {
+    Tails{rest: Some(self)}
+  }
+  /// Something that prints the members with sep between each
+  /// two of them, e.g. `format!("{}", list.format_with(", "))`
+  /// gives "a, b, c", without collecting them anywhere first.
+  pub fn format_with<'t>(&'t self, sep: &'t str) -> FormatWith<'t, T> {
+    FormatWith{list: self, sep: sep}
+  }
+  /// Iterate over (first, second), (second, third), and so on.
+  /// A list with fewer than two members has no pairs.
+  pub fn pairwise<'t>(&'t self) -> Pairwise<'t, T> {
+    Pairwise{rest: self}
+  }
+  /// Create the list of f(previous, next) for each adjacent pair of
+  /// members, e.g. the differences between consecutive snapshots.
+  pub fn deltas<U: Freeze>(&self, f: |&T, &T| -> U) -> List<U> {
+    let mut result = ListBuilder::new();
+    for (x, y) in self.pairwise() {
+      result.push(f(x, y));
+    }
+    result.finish()
+  }
+  /// Map f over the list, stopping at the first Err.
+  pub fn try_map<U: Freeze, E>(&self, f: |&T| -> Result<U, E>) -> Result<List<U>, E> {
+    let mut mapped = ~[];
+    for x in self.iter() {
+      match f(x) {
+        Ok(y) => mapped.push(y),
+        Err(e) => return Err(e)
+      }
+    }
+    Ok(from_owned_vec(mapped))
+  }
+  /// Left fold that stops at the first Err.
+  pub fn try_fold<A, E>(&self, init: A, f: |A, &T| -> Result<A, E>) -> Result<A, E> {
+    let mut acc = init;
+    for x in self.iter() {
+      match f(acc, x) {
+        Ok(a) => acc = a,
+        Err(e) => return Err(e)
+      }
+    }
+    Ok(acc)
+  }
+  /// Call f on each member in order, stopping at the first Err.
+  pub fn try_for_each<E>(&self, f: |&T| -> Result<(), E>) -> Result<(), E> {
+    for x in self.iter() {
+      match f(x) {
+        Ok(()) => (),
+        Err(e) => return Err(e)
+      }
+    }
+    Ok(())
+  }
+  /// Approximate bytes of heap memory held by this list's nodes.
+  /// Memory that the members themselves own isn't included.
+  pub fn heap_size(&self) -> uint {
+    List::heap_size_of_versions([self])
+  }
+  /// Approximate bytes of heap memory held by the nodes of all of the
+  /// lists together, counting each node that they share only once.
+  /// Memory that the members themselves own isn't included.
+  pub fn heap_size_of_versions(versions: &[&List<T>]) -> uint {
+    // An Rc allocation is the node plus its reference count.
+    let node_size = mem::size_of::<Node<T>>() + mem::size_of::<uint>();
+    List::unique_nodes(versions).count() * node_size
+  }
+  /// How many nodes the list is made of (one more than its length,
+  /// since the Nil at the end is a node too).
+  pub fn node_count(&self) -> uint {
+    self.len + 1
+  }
+  /// How many of this list's nodes are also nodes of other: the
+  /// memory the two actually share, found by pointer identity.
+  pub fn shared_node_count_with(&self, other: &List<T>) -> uint {
+    match self.shared_suffix(other) {
+      Some(suffix) => suffix.len + 1,
+      None => 0
+    }
+  }
+  // The longest tail of self that is also (node for node) a tail of
+  // other, if they share any nodes at all.  Lists can only share
+  // suffixes, so this lines up the two lists' ends and walks until
+  // they meet, without a set of addresses.
+  fn shared_suffix<'t>(&'t self, other: &List<T>) -> Option<&'t List<T>> {
+    let mut a = self;
+    let mut b = other;
+    while a.len > b.len { a = a.tail_ref(); }
+    while b.len > a.len { b = b.tail_ref(); }
+    loop {
+      if a.ptr_eq(b) {
+        return Some(a);
+      }
+      if a.len == 0 {
+        return None;
+      }
+      a = a.tail_ref();
+      b = b.tail_ref();
+    }
+  }
+  // The rest of a nonempty list, without cloning it.
+  fn tail_ref<'t>(&'t self) -> &'t List<T> {
+    match *self.node.borrow() {
+      Cons(_, ref xs) => xs,
+      Nil => fail!("tail_ref of an empty list")
+    }
+  }
+  /// Summary of the list's shape, which prints in one line however
+  /// long the list is.
+  pub fn debug_structure(&self) -> ListStructure {
+    List::debug_structure_of_versions([self])
+  }
+  /// Summary of the lists' shapes and how much they share,
+  /// which prints in one line however long they are.
+  pub fn debug_structure_of_versions(versions: &[&List<T>]) -> ListStructure {
+    let mut longest = 0;
+    let mut total_nodes = 0;
+    for version in versions.iter() {
+      let len = version.len();
+      if len > longest { longest = len; }
+      total_nodes += len + 1;
+    }
+    ListStructure{versions: versions.len(), longest: longest, total_nodes: total_nodes,
+                  unique_nodes: List::unique_nodes(versions).count()}
+  }
+  /// Iterate over each node of the lists once, however many of them
+  /// share it.  Nil nodes count too.
+  pub fn unique_nodes<'t>(versions: &[&'t List<T>]) -> UniqueNodes<'t, T> {
+    UniqueNodes{versions: versions.to_owned(), next_version: 0,
+                rest: None, seen: HashSet::new()}
+  }
+  /// Return true if the two lists are literally the same nodes in
+  /// memory (which implies they are equal), as when one was cloned
+  /// from the other or they're a shared tail of two lists.
+  pub fn ptr_eq(&self, other: &List<T>) -> bool {
+    ptr::to_unsafe_ptr(self.node.borrow()) == ptr::to_unsafe_ptr(other.node.borrow())
+  }
 }
 
 // Ought Freeze really be required for members of persistent lists?
 // Generally, yes, because there's shared data; but what if you want
 // a list of Cells or RefCells that deliberately have shared mutable identity?
-// Freeze is forced by Rc.
+// Freeze is forced by Rc.  For that, see nil_send and cons_send below.
 impl<T: Freeze> List<T> {
   /// Create an empty list
   pub fn nil() -> List<T> {
@@ -76,67 +938,842 @@ impl<T: Freeze> List<T> {
   }
   /// Create a list from a node (you probably won't need this function).
   pub fn new(node: Node<T>) -> List<T> {
-    List{node: Rc::new(node)}
+    let len = match node { Nil => 0, Cons(_, ref xs) => xs.len + 1 };
+    List{node: Rc::new(node), len: len}
+  }
+  /// Create a list of the vector's members, in the same order,
+  /// moving them rather than copying.
+  pub fn from_vec(members: ~[T]) -> List<T> {
+    from_owned_vec(members)
   }
 }
 impl<T: Clone+Freeze> List<T> {
-  fn reverse_impl(&self, acc : List<T>) -> List<T> {
-    match *self.node.borrow() {
-      Nil => acc,
-      Cons(ref x, ref xs) => xs.reverse_impl(List::cons(x.clone(), acc))
+  /// Compare self with an older version of it: returns the members
+  /// of self before the part it shares with old (copied), and that
+  /// shared part (not copied).  Sharing is found by pointer identity,
+  /// so this takes time in proportion to the parts of the two lists
+  /// that differ, not to their whole length, and never compares
+  /// members.
+  /// If they share nothing, that is all of self and an empty list.
+  pub fn diff(&self, old: &List<T>) -> (List<T>, List<T>) {
+    match self.shared_suffix(old) {
+      Some(suffix) => (self.take(self.len - suffix.len), suffix.clone()),
+      None => (self.clone(), List::nil())
+    }
+  }
+  /// Create a list of copies of the slice's members, in the same order.
+  /// This conses them on back-to-front, in one pass with no
+  /// intermediate vector.
+  pub fn from_slice(members: &[T]) -> List<T> {
+    let mut result = List::nil();
+    for x in members.rev_iter() {
+      result = List::cons(x.clone(), result);
     }
+    result
   }
   /// Create a copy of this list in reverse order.
   /// It cannot move the members because it can't know whether it has
   /// the only reference to them.  (Rc doesn't even provide a runtime
   /// feature to find out whether you have the last reference.)
   pub fn reverse(&self) -> List<T> {
-    self.reverse_impl(List::nil())
+    let mut acc = List::nil();
+    for x in self.iter() {
+      acc = List::cons(x.clone(), acc);
+    }
+    acc
   }
-}
-
-impl<T> Container for List<T> {
-  fn len(&self) -> uint {
-    let mut result = 0;
-    for _ in self.iter() { result += 1; }
-    result
+  /// Create a list of the first n members of self.cycle().
+  pub fn cycle_take(&self, n: uint) -> List<T> {
+    let mut members = ~[];
+    for x in self.cycle().take(n) {
+      members.push(x.clone());
+    }
+    from_owned_vec(members)
   }
-  fn is_empty(&self) -> bool {
-    match *self.node.borrow() {
-      Nil => true,
-      Cons(_, _) => false
+  /// Create a list of every (x, y) pair with x from self and y from other,
+  /// ordered by x first.
+  pub fn cartesian_product<U: Clone+Freeze>(&self, other: &List<U>) -> List<(T, U)> {
+    let mut pairs = ~[];
+    for x in self.iter() {
+      for y in other.iter() {
+        pairs.push((x.clone(), y.clone()));
+      }
     }
+    from_owned_vec(pairs)
   }
-}
-
-impl<T: Freeze> Default for List<T> {
-  fn default() -> List<T> {
-    List::nil()
+  /// Pair up the members of self and other in order.
+  /// The result is as long as the shorter of the two.
+  pub fn zip<U: Clone+Freeze>(&self, other: &List<U>) -> List<(T, U)> {
+    let mut result = ListBuilder::new();
+    for (x, y) in self.iter().zip(other.iter()) {
+      result.push((x.clone(), y.clone()));
+    }
+    result.finish()
   }
-}
-
-/* Does this even make sense for an immutable container?
-impl<A> Extendable<A> for List<A> {
-  fn extend<T: Iterator<A>>(&mut self, iter: &mut T) {
+  /// Like zip, for three lists.
+  pub fn zip3<U: Clone+Freeze, V: Clone+Freeze>(&self, us: &List<U>, vs: &List<V>)
+      -> List<(T, U, V)> {
+    let mut result = ListBuilder::new();
+    for ((x, u), v) in self.iter().zip(us.iter()).zip(vs.iter()) {
+      result.push((x.clone(), u.clone(), v.clone()));
+    }
+    result.finish()
   }
-}
-*/
-
-impl<A: Freeze> FromIterator<A> for List<A> {
-  // Is it possible to write this function without a
-  // stack (implicit via recursion, or explicit),
-  // and without 'unsafe' code?
-  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> List<A> {
-    match iter.next() {
-      None => List::nil(),
-      Some(a) => List::cons(a, FromIterator::from_iterator(iter))
+  /// Like zip, for four lists.
+  pub fn zip4<U: Clone+Freeze, V: Clone+Freeze, W: Clone+Freeze>(
+      &self, us: &List<U>, vs: &List<V>, ws: &List<W>) -> List<(T, U, V, W)> {
+    let mut result = ListBuilder::new();
+    for (((x, u), v), w) in self.iter().zip(us.iter()).zip(vs.iter()).zip(ws.iter()) {
+      result.push((x.clone(), u.clone(), v.clone(), w.clone()));
     }
+    result.finish()
   }
-}
-
-#[cfg(test)]
-mod test {
+  /// Iterate over all orderings of self's members, starting with
+  /// self's own order.  The front changes most often: each ending is
+  /// kept while every ordering of the members before it goes by, so
+  /// orderings that end the same way share the list nodes of that
+  /// ending, and each step conses only the members that moved
+  /// (amortized O(1) of them).
+  pub fn permutations<'t>(&'t self) -> Permutations<'t, T> {
+    let members : ~[&'t T] = self.iter().collect();
+    let n = members.len();
+    let mut perms = Permutations{members: members, order: range(0, n).collect(),
+                                 tails: range(0, n + 1).map(|_| List::nil()).collect(), done: false};
+    if n > 0 {
+      perms.rebuild(n - 1);
+    }
+    perms
+  }
+  /// Iterate over all ways to choose k of self's members, keeping
+  /// them in their original order: in colex order (the last member
+  /// chosen changes least often), so that, as with permutations,
+  /// choices that end the same way share the nodes of that ending and
+  /// each step conses only the members that changed.
+  pub fn combinations<'t>(&'t self, k: uint) -> Combinations<'t, T> {
+    let members : ~[&'t T] = self.iter().collect();
+    let done = k > members.len();
+    let mut combs = Combinations{members: members, chosen: range(0, k).collect(),
+                                 tails: range(0, k + 1).map(|_| List::nil()).collect(), done: done};
+    if k > 0 && !done {
+      combs.rebuild(k - 1);
+    }
+    combs
+  }
+  /// Iterate over the empty list, then the one-member prefix of self,
+  /// and so on up to a copy of all of self.  Each prefix is a new
+  /// list (O(its length) to build), since a cons list's prefixes
+  /// can't share nodes.
+  pub fn inits<'t>(&'t self) -> Inits<'t, T> {
+    Inits{reversed: List::nil(), rest: Some(self)}
+  }
+  /// Split the list just before the first member matching pred.
+  /// The first part is a copy; the second part is shared with self
+  /// (and is empty if nothing matched).
+  pub fn split_when(&self, pred: |&T| -> bool) -> (List<T>, List<T>) {
+    let mut prefix = ~[];
+    let mut rest = self;
+    loop {
+      match *rest.node.borrow() {
+        Cons(ref x, ref xs) if !pred(x) => {
+          prefix.push(x.clone());
+          rest = xs;
+        }
+        _ => break
+      }
+    }
+    (from_owned_vec(prefix), rest.clone())
+  }
+  /// Create a list of the first n members (all of them, if there
+  /// are fewer).  It's a copy, since a prefix can't share nodes.
+  pub fn take(&self, n: uint) -> List<T> {
+    let (prefix, _) = self.split_at(n);
+    prefix
+  }
+  /// Split the list after its first n members.  The first part is a
+  /// copy; the second part is shared with self, as with drop.
+  pub fn split_at(&self, n: uint) -> (List<T>, List<T>) {
+    let mut prefix = ListBuilder::new();
+    let mut rest = self;
+    for _ in range(0, n) {
+      match *rest.node.borrow() {
+        Nil => break,
+        Cons(ref x, ref xs) => {
+          prefix.push(x.clone());
+          rest = xs;
+        }
+      }
+    }
+    (prefix.finish(), rest.clone())
+  }
+  /// Create a list of the members for which pred is true, in order.
+  /// Everything after the last member left out is shared with self.
+  pub fn filter(&self, pred: |&T| -> bool) -> List<T> {
+    let mut kept : ~[&T] = ~[];
+    // kept[..kept_before_shared] come before shared, which starts
+    // just after the last member left out.
+    let mut kept_before_shared = 0;
+    let mut shared = self;
+    let mut rest = self;
+    loop {
+      match *rest.node.borrow() {
+        Nil => break,
+        Cons(ref x, ref xs) => {
+          if pred(x) {
+            kept.push(x);
+          } else {
+            kept_before_shared = kept.len();
+            shared = xs;
+          }
+          rest = xs;
+        }
+      }
+    }
+    let mut prefix = ListBuilder::new();
+    for x in kept.slice_to(kept_before_shared).iter() {
+      prefix.push((*x).clone());
+    }
+    prefix.finish_onto(shared.clone())
+  }
+  /// Create a list of self's members followed by other's.
+  /// Self is copied; other is shared, not copied.
+  pub fn append(&self, other: &List<T>) -> List<T> {
+    let mut prefix = ListBuilder::new();
+    for x in self.iter() {
+      prefix.push(x.clone());
+    }
+    prefix.finish_onto(other.clone())
+  }
+  /// Create a list of the lists' members, one list after another.
+  /// All but the last are copied; the last is shared.
+  pub fn concat(lists: &[List<T>]) -> List<T> {
+    match lists.last_opt() {
+      None => List::nil(),
+      Some(last) => {
+        let mut prefix = ListBuilder::new();
+        for list in lists.slice_to(lists.len() - 1).iter() {
+          for x in list.iter() {
+            prefix.push(x.clone());
+          }
+        }
+        prefix.finish_onto(last.clone())
+      }
+    }
+  }
+}
+
+impl<T: Eq+Clone+Freeze> List<T> {
+  /// If other is a prefix of self, return the rest of self after it
+  /// (shared with self, not copied).  This stops early if it reaches
+  /// a tail that self and other share.
+  pub fn strip_prefix(&self, other: &List<T>) -> Option<List<T>> {
+    let mut rest = self;
+    let mut prefix = other;
+    loop {
+      if rest.ptr_eq(prefix) {
+        return Some(List::nil());
+      }
+      match (rest.node.borrow(), prefix.node.borrow()) {
+        (_, &Nil) => return Some(rest.clone()),
+        (&Nil, _) => return None,
+        (&Cons(ref x, ref xs), &Cons(ref y, ref ys)) => {
+          if *x != *y {
+            return None;
+          }
+          rest = xs;
+          prefix = ys;
+        }
+      }
+    }
+  }
+  /// Like mapping f over the list, except that the longest suffix
+  /// for which f returned members equal to the originals is shared
+  /// with self rather than rebuilt.  (If f changed nothing, the
+  /// result is self, node for node.)
+  pub fn map_shared(&self, f: |&T| -> T) -> List<T> {
+    let mut mapped = ~[];
+    let mut unchanged_from = 0;
+    let mut shared = self;
+    let mut rest = self;
+    loop {
+      match *rest.node.borrow() {
+        Nil => break,
+        Cons(ref x, ref xs) => {
+          let y = f(x);
+          if y != *x {
+            unchanged_from = mapped.len() + 1;
+            shared = xs;
+          }
+          mapped.push(y);
+          rest = xs;
+        }
+      }
+    }
+    mapped.truncate(unchanged_from);
+    prepend_owned_vec(mapped, shared.clone())
+  }
+}
+
+impl<T: TotalOrd+Clone+Freeze> List<T> {
+  /// Merge two sorted lists into one sorted list.  Where members are
+  /// equal, self's come first.  Once either list runs out, the rest
+  /// of the other is shared rather than copied.
+  pub fn merge(&self, other: &List<T>) -> List<T> {
+    let mut merged = ListBuilder::new();
+    let mut a = self;
+    let mut b = other;
+    loop {
+      match (a.node.borrow(), b.node.borrow()) {
+        (&Nil, _) => return merged.finish_onto(b.clone()),
+        (_, &Nil) => return merged.finish_onto(a.clone()),
+        (&Cons(ref x, ref xs), &Cons(ref y, ref ys)) => {
+          if y.cmp(x) == Less {
+            merged.push(y.clone());
+            b = ys;
+          } else {
+            merged.push(x.clone());
+            a = xs;
+          }
+        }
+      }
+    }
+  }
+  /// Insert x into a sorted list, after any members equal to it.
+  /// The members after x are shared with self.
+  pub fn insert_sorted(&self, x: T) -> List<T> {
+    let mut before = ListBuilder::new();
+    let mut rest = self;
+    loop {
+      match *rest.node.borrow() {
+        Cons(ref y, ref ys) if y.cmp(&x) != Greater => {
+          before.push(y.clone());
+          rest = ys;
+        }
+        _ => break
+      }
+    }
+    before.finish_onto(List::cons(x, rest.clone()))
+  }
+  /// Sort the list (stably), by a bottom-up merge sort of its
+  /// ascending runs.  The last run is a suffix of self and is shared,
+  /// and so are the tails that merges run out on; a list that is
+  /// already sorted comes back as itself.  O(n log r) for r runs.
+  pub fn sort(&self) -> List<T> {
+    let mut runs = ~[];
+    let mut run_start = self;
+    let mut run_len = 0;
+    let mut rest = self;
+    let mut previous : Option<&T> = None;
+    loop {
+      match *rest.node.borrow() {
+        Nil => break,
+        Cons(ref x, ref xs) => {
+          match previous {
+            Some(p) if x.cmp(p) == Less => {
+              runs.push(run_start.take(run_len));
+              run_start = rest;
+              run_len = 0;
+            }
+            _ => ()
+          }
+          previous = Some(x);
+          run_len += 1;
+          rest = xs;
+        }
+      }
+    }
+    runs.push(run_start.clone());
+    while runs.len() > 1 {
+      let mut merged = ~[];
+      for pair in runs.chunks(2) {
+        merged.push(if pair.len() == 2 { pair[0].merge(&pair[1]) } else { pair[0].clone() });
+      }
+      runs = merged;
+    }
+    runs[0].clone()
+  }
+}
+
+fn from_owned_vec<T: Freeze>(members: ~[T]) -> List<T> {
+  prepend_owned_vec(members, List::nil())
+}
+
+// Builds back-to-front so that no recursion is needed.
+fn prepend_owned_vec<T: Freeze>(members: ~[T], tail: List<T>) -> List<T> {
+  let mut result = tail;
+  for x in members.move_rev_iter() {
+    result = List::cons(x, result);
+  }
+  result
+}
+
+impl<T: Str> List<T> {
+  /// Concatenate the members with sep between each two of them.
+  pub fn join(&self, sep: &str) -> ~str {
+    let mut result = ~"";
+    let mut first = true;
+    for x in self.iter() {
+      if !first { result.push_str(sep); }
+      result.push_str(x.as_slice());
+      first = false;
+    }
+    result
+  }
+}
+
+impl<T: Clone> List<T> {
+  /// Create a vector of copies of the members, in order.
+  pub fn to_vec(&self) -> ~[T] {
+    let mut result = ::std::vec::with_capacity(self.len());
+    for x in self.iter() {
+      result.push(x.clone());
+    }
+    result
+  }
+  /// Left fold using the first member as the starting value
+  /// (sometimes called reduce).  None if the list is empty.
+  pub fn fold1(&self, f: |T, &T| -> T) -> Option<T> {
+    match *self.node.borrow() {
+      Nil => None,
+      Cons(ref x, ref xs) => {
+        let mut acc = x.clone();
+        for y in xs.iter() {
+          acc = f(acc, y);
+        }
+        Some(acc)
+      }
+    }
+  }
+  /// Another name for fold1.
+  pub fn reduce(&self, f: |T, &T| -> T) -> Option<T> {
+    self.fold1(f)
+  }
+  /// Consume the list, iterating over its members.
+  /// Each node is let go of as soon as the iterator is past it.
+  ///
+  /// The members are cloned, not moved: Rc doesn't say whether this
+  /// is the only reference to a node, and if it isn't, other lists
+  /// still need the member.
+  pub fn move_iter(self) -> MoveIterator<T> {
+    MoveIterator{rest: self}
+  }
+}
+
+impl<T: Add<T, T>+Zero> List<T> {
+  /// The sum of the members (zero for an empty list).
+  pub fn sum(&self) -> T {
+    let mut acc : T = Zero::zero();
+    for x in self.iter() {
+      acc = acc + *x;
+    }
+    acc
+  }
+}
+
+impl<T: Mul<T, T>+One> List<T> {
+  /// The product of the members (one for an empty list).
+  pub fn product(&self) -> T {
+    let mut acc : T = One::one();
+    for x in self.iter() {
+      acc = acc * *x;
+    }
+    acc
+  }
+}
+
+/// A borrowed look at the front of a List.
+/// Its variants are named like Node's, so import them from here
+/// (`use persistent::list::view::{Nil, Cons};`) instead of from list
+/// when matching on `list.view()`.
+pub mod view {
+use super::List;
+
+pub enum ListView<'self, T> {
+  Nil,
+  Cons(&'self T, &'self List<T>)
+}
+}
+
+/// A position in a List that can be moved left and right and edited
+/// there.  to_list() makes the edited list, sharing everything after
+/// the edits with the original; only the part before the position
+/// is copied.
+pub mod zipper {
 use super::List;
+
+/// A list with a focus: the members before it (kept nearest first),
+/// and the focused member followed by the rest.  The focus can also
+/// be just past the end.
+pub struct Zipper<T> {
+  priv before : List<T>,
+  priv after : List<T>
+}
+
+impl<T> Clone for Zipper<T> {
+  fn clone(&self) -> Zipper<T> {
+    Zipper{before: self.before.clone(), after: self.after.clone()}
+  }
+}
+
+impl<T: Clone+Freeze> Zipper<T> {
+  /// A zipper focused on the first member of list.
+  pub fn new(list: &List<T>) -> Zipper<T> {
+    Zipper{before: List::nil(), after: list.clone()}
+  }
+  /// The focused member, or None if the focus is past the end.
+  pub fn focus<'t>(&'t self) -> Option<&'t T> {
+    self.after.head()
+  }
+  /// How many members there are before the focus.
+  pub fn index(&self) -> uint {
+    self.before.len()
+  }
+  /// The zipper focused on the next member (or the end),
+  /// or None if the focus is already past the end.
+  pub fn right(&self) -> Option<Zipper<T>> {
+    match (self.after.head(), self.after.tail()) {
+      (Some(x), Some(rest)) => Some(Zipper{before: List::cons(x.clone(), self.before.clone()), after: rest}),
+      _ => None
+    }
+  }
+  /// The zipper focused on the member before, or None if the focus
+  /// is already on the first one.
+  pub fn left(&self) -> Option<Zipper<T>> {
+    match (self.before.head(), self.before.tail()) {
+      (Some(x), Some(rest)) => Some(Zipper{before: rest, after: List::cons(x.clone(), self.after.clone())}),
+      _ => None
+    }
+  }
+  /// The zipper with the focused member replaced by x (or x added,
+  /// if the focus is past the end).
+  pub fn replace(&self, x: T) -> Zipper<T> {
+    let rest = match self.after.tail() { None => List::nil(), Some(rest) => rest };
+    Zipper{before: self.before.clone(), after: List::cons(x, rest)}
+  }
+  /// The zipper with x inserted before the focused member,
+  /// and focused on x.
+  pub fn insert(&self, x: T) -> Zipper<T> {
+    Zipper{before: self.before.clone(), after: List::cons(x, self.after.clone())}
+  }
+  /// The zipper with the focused member deleted, and focused on the
+  /// one after it; None if the focus is past the end.
+  pub fn delete(&self) -> Option<Zipper<T>> {
+    match self.after.tail() {
+      None => None,
+      Some(rest) => Some(Zipper{before: self.before.clone(), after: rest})
+    }
+  }
+  /// The whole list, edits included.
+  pub fn to_list(&self) -> List<T> {
+    let mut result = self.after.clone();
+    for x in self.before.iter() {
+      result = List::cons(x.clone(), result);
+    }
+    result
+  }
+}
+}
+
+impl<T: IterBytes+Eq+Clone+Freeze> List<T> {
+  /// Create copies of the lists in which structurally equal suffixes
+  /// are the same nodes in memory, e.g. after loading many lists
+  /// that were built separately.  Nodes that can be kept are kept,
+  /// so a suffix that was already shared stays as it was.
+  pub fn compact(versions: &[List<T>]) -> ~[List<T>] {
+    // canonical: (member, address of the canonical tail) -> canonical node.
+    // done: address of an original node -> its canonical node.
+    let mut canonical : HashMap<(T, uint), List<T>> = HashMap::new();
+    let mut done : HashMap<uint, List<T>> = HashMap::new();
+    let mut nil : Option<List<T>> = None;
+    let mut result = ~[];
+    for version in versions.iter() {
+      let mut spine : ~[&List<T>] = ~[];
+      let mut rest = version;
+      let mut tail;
+      loop {
+        match done.find(&node_address(rest)) {
+          Some(c) => { tail = c.clone(); break; }
+          None => ()
+        }
+        match *rest.node.borrow() {
+          Nil => {
+            if nil.is_none() { nil = Some(rest.clone()); }
+            tail = nil.get_ref().clone();
+            done.insert(node_address(rest), tail.clone());
+            break;
+          }
+          Cons(_, ref xs) => {
+            spine.push(rest);
+            rest = xs;
+          }
+        }
+      }
+      for node in spine.rev_iter() {
+        match *node.node.borrow() {
+          Nil => fail!(),
+          Cons(ref x, ref xs) => {
+            let key = (x.clone(), node_address(&tail));
+            let c = match canonical.find(&key) {
+              Some(c) => Some(c.clone()),
+              None => None
+            };
+            tail = match c {
+              Some(c) => c,
+              None => {
+                let c = if xs.ptr_eq(&tail) { (*node).clone() }
+                        else { List::cons(x.clone(), tail.clone()) };
+                canonical.insert(key, c.clone());
+                c
+              }
+            };
+            done.insert(node_address(*node), tail.clone());
+          }
+        }
+      }
+      result.push(tail);
+    }
+    result
+  }
+}
+
+fn node_address<T>(list: &List<T>) -> uint {
+  ptr::to_unsafe_ptr(list.node.borrow()) as uint
+}
+
+// So lists can be hashed (std::hash::Hash comes with IterBytes),
+// and be keys of a HashMap.  The hash isn't cached in the nodes:
+// that would cost every cons a hash, and a Cell per node, which
+// isn't Freeze.  This is a loop, not recursion.
+impl<T: IterBytes> IterBytes for List<T> {
+  fn iter_bytes(&self, lsb0: bool, f: Cb) -> bool {
+    if !self.len.iter_bytes(lsb0, |b| f(b)) {
+      return false;
+    }
+    for x in self.iter() {
+      if !x.iter_bytes(lsb0, |b| f(b)) {
+        return false;
+      }
+    }
+    true
+  }
+}
+
+impl<T> Container for List<T> {
+  fn len(&self) -> uint {
+    self.len
+  }
+  fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+}
+
+impl<T: Clone+Freeze> PersistentSeq<T> for List<T> {
+  fn empty() -> List<T> {
+    List::nil()
+  }
+  fn push(&self, x: T) -> List<T> {
+    List::cons(x, self.clone())
+  }
+  fn get<'a>(&'a self, i: uint) -> Option<&'a T> {
+    self.iter().nth(i)
+  }
+  fn each(&self, f: |&T| -> bool) -> bool {
+    for x in self.iter() {
+      if !f(x) { return false; }
+    }
+    true
+  }
+}
+
+impl<T: Freeze> Default for List<T> {
+  fn default() -> List<T> {
+    List::nil()
+  }
+}
+
+// Rc::new wants Freeze because a mutable member could be made to point
+// back at a node holding it, a cycle that reference counting would
+// leak.  A member that is Send can't hold a List (Rc isn't Send), so
+// lists of Send members can't be part of a cycle, mutable or not.
+impl<T: Send> List<T> {
+  /// Create an empty list whose members need only be Send, not
+  /// Freeze: so they can be Cells or RefCells, deliberately shared
+  /// and mutable through every list that has them (caches, say).
+  /// The members' interior mutability is the only mutation; the
+  /// lists themselves stay as persistent as ever.
+  pub fn nil_send() -> List<T> {
+    List::new_send(Nil)
+  }
+  /// Create a list from the head and the rest of the list, like
+  /// cons, for members that need only be Send (see nil_send).
+  pub fn cons_send(x: T, xs: List<T>) -> List<T> {
+    List::new_send(Cons(x, xs))
+  }
+  fn new_send(node: Node<T>) -> List<T> {
+    let len = match node { Nil => 0, Cons(_, ref xs) => xs.len + 1 };
+    // Safe because of the Send bound: see above.
+    List{node: unsafe { Rc::new_unchecked(node) }, len: len}
+  }
+}
+
+/// Shape of some lists, for printing instead of their members.
+pub struct ListStructure {
+  /// How many lists were looked at.
+  versions : uint,
+  /// Length of the longest of them.
+  longest : uint,
+  /// Nodes in all of them (counting Nil), as if nothing were shared.
+  total_nodes : uint,
+  /// Nodes actually in memory: shared nodes are counted once.
+  unique_nodes : uint
+}
+
+impl fmt::Default for ListStructure {
+  fn fmt(s: &ListStructure, f: &mut fmt::Formatter) {
+    let shared = if s.total_nodes == 0 { 0 }
+                 else { (s.total_nodes - s.unique_nodes) * 100 / s.total_nodes };
+    write!(f.buf, "{} list(s), longest {}, {} nodes of which {} distinct ({}% shared)",
+           s.versions, s.longest, s.total_nodes, s.unique_nodes, shared);
+  }
+}
+
+/// Prints a list's members with a separator between them;
+/// see List::format_with.
+pub struct FormatWith<'self, T> {
+  priv list : &'self List<T>,
+  priv sep : &'self str
+}
+
+impl<'self, T: fmt::Default> fmt::Default for FormatWith<'self, T> {
+  fn fmt(fw: &FormatWith<'self, T>, f: &mut fmt::Formatter) {
+    let mut first = true;
+    for x in fw.list.iter() {
+      if !first { write!(f.buf, "{}", fw.sep); }
+      write!(f.buf, "{}", *x);
+      first = false;
+    }
+  }
+}
+
+/// Lists print as `[1, 2, 3]`.
+impl<T: fmt::Default> fmt::Default for List<T> {
+  fn fmt(list: &List<T>, f: &mut fmt::Formatter) {
+    write!(f.buf, "[{}]", list.format_with(", "));
+  }
+}
+
+/// Parses the format that lists print in, e.g. `[1, 2, 3]`.
+/// Members may be bracketed themselves (`[[1], [2, 3]]`), but a member
+/// whose own text has a comma outside of brackets can't be parsed.
+impl<T: FromStr+Freeze> FromStr for List<T> {
+  fn from_str(s: &str) -> Option<List<T>> {
+    let s = s.trim();
+    if !s.starts_with("[") || !s.ends_with("]") || s.len() < 2 {
+      return None;
+    }
+    let inner = s.slice(1, s.len() - 1);
+    let mut result = ListBuilder::new();
+    if inner.trim().is_empty() {
+      return Some(result.finish());
+    }
+    for piece in split_top_level(inner, ',').iter() {
+      match from_str(piece.trim()) {
+        Some(x) => result.push(x),
+        None => return None
+      }
+    }
+    Some(result.finish())
+  }
+}
+
+/* Does this even make sense for an immutable container?
+impl<A> Extendable<A> for List<A> {
+  fn extend<T: Iterator<A>>(&mut self, iter: &mut T) {
+  }
+}
+*/
+
+/// Collects members front-to-back and then makes them into a List.
+/// This is the way to build a list from many members in order:
+/// cons can only add to the front, so the builder
+/// buffers the members and conses them on back-to-front in one pass.
+pub struct ListBuilder<T> {
+  priv members : ~[T]
+}
+
+impl<T: Freeze> ListBuilder<T> {
+  /// Create a builder with no members yet.
+  pub fn new() -> ListBuilder<T> {
+    ListBuilder{members: ~[]}
+  }
+  /// Add a member after the ones already added.
+  pub fn push(&mut self, x: T) {
+    self.members.push(x);
+  }
+  /// Create the list of everything that was added, in order.
+  pub fn finish(self) -> List<T> {
+    from_owned_vec(self.members)
+  }
+  /// Create the list of everything that was added, in order,
+  /// followed by (and sharing) tail.
+  pub fn finish_onto(self, tail: List<T>) -> List<T> {
+    prepend_owned_vec(self.members, tail)
+  }
+}
+
+impl<A: Freeze> Extendable<A> for ListBuilder<A> {
+  fn extend<T: Iterator<A>>(&mut self, iter: &mut T) {
+    for a in *iter {
+      self.push(a);
+    }
+  }
+}
+
+impl<A: Freeze> FromIterator<A> for List<A> {
+  // Is it possible to write this function without a
+  // stack (implicit via recursion, or explicit),
+  // and without 'unsafe' code?
+  // (ListBuilder's buffer is an explicit stack; at least it
+  // can't overflow the task's stack.)
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> List<A> {
+    let mut builder = ListBuilder::new();
+    builder.extend(iter);
+    builder.finish()
+  }
+}
+
+// Lists are encoded as (versioned) sequences.  Neither direction recurses, and
+// sharing between lists isn't kept: decoding makes new nodes.
+impl<S: Encoder, T: Encodable<S>> Encodable<S> for List<T> {
+  fn encode(&self, s: &mut S) {
+    encode_versioned(s, "List", |s| {
+      s.emit_seq(self.len(), |s| {
+        for (i, x) in self.iter().enumerate() {
+          s.emit_seq_elt(i, |s| x.encode(s));
+        }
+      })
+    })
+  }
+}
+
+impl<D: Decoder, T: Decodable<D>+Freeze> Decodable<D> for List<T> {
+  fn decode(d: &mut D) -> List<T> {
+    decode_versioned(d, "List", |d, version| match version {
+      // Version 1: the members, front to back.
+      1 => d.read_seq(|d, len| {
+        let mut builder = ListBuilder::new();
+        for i in range(0, len) {
+          builder.push(d.read_seq_elt(i, |d| Decodable::decode(d)));
+        }
+        builder.finish()
+      }),
+      _ => unknown_version("List", version)
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::{List, ListBuilder};
 //use std::cell::RefCell;
 #[test]
 fn test() {
@@ -171,6 +1808,7414 @@ fn test() {
   // doesn't meet Freeze requirement:
   //let sdf : List<RefCell<int>> = List::nil();
 }
+#[test]
+fn test_cycle() {
+  let p0 : List<int> = List::nil();
+  let p2 = List::cons(1, List::cons(2, List::nil()));
+  assert!(p0.cycle().next() == None);
+  let taken : ~[int] = p2.cycle().take(5).map(|x| *x).collect();
+  assert!(taken == ~[1,2,1,2,1]);
+  assert!(p0.cycle_take(3) == p0);
+  assert!(p2.cycle_take(3) == List::cons(1, List::cons(2, List::cons(1, List::nil()))));
+  assert!(p2.cycle_take(0) == p0);
+}
+#[test]
+fn test_cartesian_product() {
+  let p0 : List<int> = List::nil();
+  let xs = List::cons(1, List::cons(2, List::nil()));
+  let ys = List::cons('a', List::cons('b', List::nil()));
+  let pairs : ~[(int, char)] = xs.cartesian_product(&ys).iter().map(|p| *p).collect();
+  assert!(pairs == ~[(1,'a'), (1,'b'), (2,'a'), (2,'b')]);
+  assert!(p0.cartesian_product(&ys).is_empty());
+  assert!(ys.cartesian_product(&p0).is_empty());
+}
+#[test]
+fn test_permutations_combinations() {
+  use std::hashmap::HashSet;
+  let p0 : List<int> = List::nil();
+  let p3 : List<int> = ~[1,2,3].move_iter().collect();
+  let perms : ~[~[int]] = p3.permutations()
+    .map(|l| l.iter().map(|x| *x).collect()).collect();
+  assert!(perms == ~[~[1,2,3], ~[2,1,3], ~[1,3,2], ~[3,1,2], ~[2,3,1], ~[3,2,1]]);
+  let empty : ~[List<int>] = p0.permutations().collect();
+  assert!(empty == ~[List::nil()]);
+  let p4 : List<int> = ~[1,2,3,4].move_iter().collect();
+  let perms4 : ~[List<int>] = p4.permutations().collect();
+  assert!(perms4.len() == 24);
+  let distinct : HashSet<List<int>> = perms4.iter().map(|l| l.clone()).collect();
+  assert!(distinct.len() == 24);
+  // [1,2,3,4] and [2,1,3,4] end the same way, in the same nodes.
+  assert!(perms4[0] == p4 && perms4[1] == List::from_vec(~[2,1,3,4]));
+  assert!(perms4[0].drop(2).ptr_eq(&perms4[1].drop(2)));
+  // 64 conses and a Nil in all, rather than 24 * 5 nodes: each
+  // ending is made once.
+  let versions : ~[&List<int>] = perms4.iter().collect();
+  assert!(List::unique_nodes(versions).count() == 65);
+  let combs : ~[~[int]] = p3.combinations(2)
+    .map(|l| l.iter().map(|x| *x).collect()).collect();
+  assert!(combs == ~[~[1,2], ~[1,3], ~[2,3]]);
+  let none : ~[List<int>] = p3.combinations(0).collect();
+  let all : ~[List<int>] = p3.combinations(3).collect();
+  assert!(none == ~[List::nil()] && all == ~[p3.clone()]);
+  assert!(p3.combinations(4).next().is_none() && p0.combinations(1).next().is_none());
+  let p6 : List<int> = range(0, 6).collect();
+  let combs6 : ~[List<int>] = p6.combinations(3).collect();
+  assert!(combs6.len() == 20 && combs6.iter().all(|c| c.len() == 3));
+  assert!(combs6.iter().all(|c| c.iter().zip(c.iter().skip(1)).all(|(a, b)| a < b)));
+  // Choices ending in 3, 4 share that ending.
+  assert!(combs6[7] == List::from_vec(~[0, 3, 4]) && combs6[8] == List::from_vec(~[1, 3, 4]));
+  assert!(combs6[7].drop(1).ptr_eq(&combs6[8].drop(1)));
+}
+#[test]
+fn test_permutations_combinations_lazy() {
+  // 20! orderings and 40-choose-20 choices: far too many to make all
+  // of, but only the ones taken are made.
+  let p20 : List<int> = range(0, 20).collect();
+  let first : ~[List<int>] = p20.permutations().take(3).collect();
+  assert!(first[0] == p20 && first[1].drop(2).ptr_eq(&first[0].drop(2)));
+  assert!(first[2].drop(3).ptr_eq(&first[0].drop(3)));
+  let p40 : List<int> = range(0, 40).collect();
+  let choices : ~[List<int>] = p40.combinations(20).take(2).collect();
+  let first_20 : List<int> = range(0, 20).collect();
+  let second : List<int> = range(0, 21).filter(|&i| i != 19).collect();
+  assert!(choices[0] == first_20 && choices[1] == second);
+}
+#[test]
+fn test_split_when() {
+  let p3 : List<int> = ~[1,2,3].move_iter().collect();
+  let (before, after) = p3.split_when(|x| *x == 2);
+  assert!(before == List::cons(1, List::nil()));
+  assert!(after == List::cons(2, List::cons(3, List::nil())));
+  let (all, none) = p3.split_when(|x| *x > 5);
+  assert!(all == p3);
+  assert!(none.is_empty());
+  let (none, all) = p3.split_when(|_| true);
+  assert!(none.is_empty());
+  assert!(all == p3);
+}
+#[test]
+fn test_strip_prefix() {
+  let p0 : List<int> = List::nil();
+  let p3 : List<int> = ~[1,2,3].move_iter().collect();
+  let p12 : List<int> = ~[1,2].move_iter().collect();
+  assert!(p3.strip_prefix(&p12) == Some(List::cons(3, List::nil())));
+  assert!(p3.strip_prefix(&p0) == Some(p3.clone()));
+  assert!(p3.strip_prefix(&p3) == Some(p0.clone()));
+  assert!(p3.strip_prefix(&p3.clone()) == Some(p0.clone()));
+  assert!(p12.strip_prefix(&p3) == None);
+  assert!(p3.strip_prefix(&List::cons(2, List::nil())) == None);
+}
+#[test]
+fn test_tails() {
+  let p0 : List<int> = List::nil();
+  let p2 = List::cons(1, List::cons(2, List::nil()));
+  let tails : ~[List<int>] = p2.tails().collect();
+  assert!(tails == ~[p2.clone(), List::cons(2, List::nil()), p0.clone()]);
+  let tails0 : ~[List<int>] = p0.tails().collect();
+  assert!(tails0 == ~[p0.clone()]);
+  let mut iter = p2.tails();
+  assert!(iter.size_hint() == (3, Some(3)));
+  assert!(iter.next().unwrap().ptr_eq(&p2) && iter.next().unwrap().ptr_eq(&p2.tail().unwrap()));
+  assert!(iter.size_hint() == (1, Some(1)));
+}
+#[test]
+fn test_inits() {
+  let p0 : List<int> = List::nil();
+  let p2 = List::cons(1, List::cons(2, List::nil()));
+  let inits : ~[List<int>] = p2.inits().collect();
+  assert!(inits == ~[p0.clone(), List::cons(1, List::nil()), p2.clone()]);
+  let inits0 : ~[List<int>] = p0.inits().collect();
+  assert!(inits0 == ~[p0.clone()]);
+}
+#[test]
+fn test_map_shared() {
+  let p3 : List<int> = ~[1,2,3].move_iter().collect();
+  let same = p3.map_shared(|x| *x);
+  assert!(same.ptr_eq(&p3));
+  let first_changed = p3.map_shared(|x| if *x == 1 { 10 } else { *x });
+  let expected : List<int> = ~[10,2,3].move_iter().collect();
+  assert!(first_changed == expected);
+  match (first_changed.node(), p3.node()) {
+    (&super::Cons(_, ref a), &super::Cons(_, ref b)) => assert!(a.ptr_eq(b)),
+    _ => fail!()
+  }
+  let last_changed = p3.map_shared(|x| if *x == 3 { 30 } else { *x });
+  let expected : List<int> = ~[1,2,30].move_iter().collect();
+  assert!(last_changed == expected);
+}
+#[test]
+fn test_try() {
+  let p3 : List<int> = ~[1,2,3].move_iter().collect();
+  let doubled : Result<List<int>, int> = p3.try_map(|x| Ok(*x * 2));
+  let expected : List<int> = ~[2,4,6].move_iter().collect();
+  assert!(doubled == Ok(expected));
+  let failed : Result<List<int>, int> = p3.try_map(|x| if *x == 2 { Err(*x) } else { Ok(*x) });
+  assert!(failed == Err(2));
+  let sum : Result<int, ()> = p3.try_fold(0, |a, x| Ok(a + *x));
+  assert!(sum == Ok(6));
+  let stopped : Result<int, int> = p3.try_fold(0, |a, x| if a > 0 { Err(*x) } else { Ok(a + *x) });
+  assert!(stopped == Err(2));
+  let mut seen = 0;
+  let r = p3.try_for_each(|x| { seen += 1; if *x == 2 { Err(()) } else { Ok(()) } });
+  assert!(r == Err(()));
+  assert!(seen == 2);
+}
+#[test]
+fn test_aggregates() {
+  let p0 : List<int> = List::nil();
+  let p3 : List<int> = ~[2,3,4].move_iter().collect();
+  assert!(p3.sum() == 9);
+  assert!(p3.product() == 24);
+  assert!(p0.sum() == 0);
+  assert!(p0.product() == 1);
+  assert!(p3.fold1(|a, x| a - *x) == Some(-5));
+  assert!(p0.fold1(|a, x| a - *x) == None);
+  assert!(p3.reduce(|a, x| if a > *x { a } else { *x }) == Some(4) && p0.reduce(|a, x| a + *x) == None);
+  assert!(p3.iter().sum() == 9 && p3.iter().product() == 24 && p0.iter().sum() == 0);
+  let mut rest = p3.iter();
+  rest.next();
+  assert!(rest.product() == 12);
+  {
+    use std::iter::{AdditiveIterator, MultiplicativeIterator};
+    assert!(p3.clone().move_iter().sum() == 9 && p3.clone().move_iter().product() == 24);
+  }
+}
+#[test]
+fn test_builder() {
+  let mut builder = ListBuilder::new();
+  builder.push(1);
+  builder.extend(&mut range(2, 4));
+  builder.push(4);
+  let p4 : List<int> = builder.finish();
+  let expected : List<int> = ~[1,2,3,4].move_iter().collect();
+  assert!(p4 == expected);
+  let empty : List<int> = ListBuilder::new().finish();
+  assert!(empty.is_empty());
+}
+#[test]
+fn test_zip() {
+  let xs : List<int> = ~[1,2,3].move_iter().collect();
+  let ys : List<char> = ~['a','b'].move_iter().collect();
+  let zs : List<bool> = ~[true,false,true].move_iter().collect();
+  let ws : List<uint> = ~[7u,8,9].move_iter().collect();
+  let pairs : ~[(int, char)] = xs.zip(&ys).iter().map(|p| *p).collect();
+  assert!(pairs == ~[(1,'a'), (2,'b')]);
+  let triples : ~[(int, bool, uint)] = zip_lists!(xs, zs, ws).iter().map(|t| *t).collect();
+  assert!(triples == ~[(1,true,7), (2,false,8), (3,true,9)]);
+  assert!(zip_lists!(xs, ys) == xs.zip(&ys));
+  assert!(zip_lists!(xs, ys, zs, ws).len() == 2);
+}
+#[test]
+fn test_pairwise() {
+  let p1 : List<int> = List::cons(1, List::nil());
+  let p3 : List<int> = ~[1,4,9].move_iter().collect();
+  let pairs : ~[(int, int)] = p3.pairwise().map(|(a, b)| (*a, *b)).collect();
+  assert!(pairs == ~[(1,4), (4,9)]);
+  assert!(p1.pairwise().next().is_none());
+  let expected : List<int> = ~[3,5].move_iter().collect();
+  assert!(p3.deltas(|a, b| *b - *a) == expected);
+  assert!(p1.deltas(|a, b| *b - *a).is_empty());
+}
+#[test]
+fn test_heap_size() {
+  let p0 : List<int> = List::nil();
+  let p1 = List::cons(1, p0.clone());
+  let p2a = List::cons(2, p1.clone());
+  let p2b = List::cons(3, p1.clone());
+  let node = p0.heap_size();
+  assert!(node > 0);
+  assert!(p2a.heap_size() == 3 * node);
+  assert!(List::heap_size_of_versions([&p2a, &p2b]) == 4 * node);
+  assert!(List::heap_size_of_versions([&p2a, &p2a.clone()]) == 3 * node);
+  // Equal, but not shared:
+  let separate = List::cons(2, List::cons(1, List::nil()));
+  assert!(List::heap_size_of_versions([&p2a, &separate]) == 6 * node);
+}
+#[test]
+fn test_persistent_seq() {
+  use persistent::traits::PersistentSeq;
+  fn sum_pushed<S: PersistentSeq<int>>(n: int) -> (int, uint) {
+    let mut seq : S = PersistentSeq::empty();
+    for i in range(0, n) { seq = seq.push(i); }
+    let mut total = 0;
+    seq.each(|x| { total += *x; true });
+    (total, seq.len())
+  }
+  assert!(sum_pushed::<List<int>>(4) == (6, 4));
+  let p2 = List::cons(1, List::cons(2, List::nil()));
+  assert!(p2.get(1) == Some(&2));
+  assert!(p2.get(2) == None);
+  assert!(!p2.each(|x| *x < 2));
+}
+#[test]
+fn test_text_format() {
+  use std::from_str::from_str;
+  let p0 : List<int> = List::nil();
+  let p3 = List::from_vec(~[1,2,3]);
+  assert!(format!("{}", p3) == ~"[1, 2, 3]");
+  assert!(format!("{}", p0) == ~"[]");
+  assert!(from_str::<List<int>>("[1, 2, 3]") == Some(p3.clone()));
+  assert!(from_str::<List<int>>(" [ 1,2 ,3 ] ") == Some(p3.clone()));
+  assert!(from_str::<List<int>>("[]") == Some(p0.clone()));
+  assert!(from_str::<List<int>>("[1, x]") == None);
+  assert!(from_str::<List<int>>("1, 2") == None);
+  assert!(from_str::<List<int>>("[1,]") == None);
+  let nested = List::from_vec(~[List::from_vec(~[1]), List::nil(), List::from_vec(~[2, 3])]);
+  let text = format!("{}", nested);
+  assert!(text == ~"[[1], [], [2, 3]]");
+  assert!(from_str::<List<List<int>>>(text) == Some(nested));
+}
+#[test]
+fn test_compact() {
+  let a = List::from_vec(~[1, 2, 3]);
+  let b = List::from_vec(~[0, 2, 3]);
+  let c = List::cons(5, a.clone());
+  let compacted = List::compact([a.clone(), b.clone(), c.clone()]);
+  assert!(compacted == ~[a.clone(), b.clone(), c.clone()]);
+  assert!(List::heap_size_of_versions([&a, &b, &c]) == 9 * List::<int>::nil().heap_size());
+  assert!(List::heap_size_of_versions([&compacted[0], &compacted[1], &compacted[2]])
+          == 6 * List::<int>::nil().heap_size());
+  // The suffix a and c already shared is kept as it was.
+  assert!(compacted[0].ptr_eq(&a));
+}
+// Applies random operations to random versions of a list and of a
+// vector model side by side, then checks every version (old ones
+// included) still matches its model.
+#[test]
+fn test_against_vector_model() {
+  use std::rand::{Rng, task_rng};
+  let mut rng = task_rng();
+  for _ in range(0, 20) {
+    let mut lists : ~[List<int>] = ~[List::nil()];
+    let mut models : ~[~[int]] = ~[~[]];
+    for _ in range(0, 200) {
+      let i = rng.gen_range(0u, lists.len());
+      let x = rng.gen_range(0, 10);
+      let (list, model) = match rng.gen_range(0u, 5) {
+        0 => (List::cons(x, lists[i].clone()), ~[x] + models[i]),
+        1 => match *lists[i].node() {
+          super::Nil => (lists[i].clone(), models[i].clone()),
+          super::Cons(_, ref xs) => (xs.clone(), models[i].slice_from(1).to_owned())
+        },
+        2 => {
+          let mut reversed = models[i].clone();
+          reversed.reverse();
+          (lists[i].reverse(), reversed)
+        }
+        3 => (lists[i].map_shared(|y| if *y == x { 0 } else { *y }),
+              models[i].iter().map(|y| if *y == x { 0 } else { *y }).collect()),
+        _ => {
+          let (_, after) = lists[i].split_when(|y| *y == x);
+          let at = match models[i].iter().position(|y| *y == x) {
+            Some(at) => at,
+            None => models[i].len()
+          };
+          (after, models[i].slice_from(at).to_owned())
+        }
+      };
+      lists.push(list);
+      models.push(model);
+    }
+    for (list, model) in lists.iter().zip(models.iter()) {
+      assert!(list.to_vec() == *model);
+      assert!(list.len() == model.len());
+    }
+  }
+}
+#[test]
+fn test_sort() {
+  let sorted = List::from_vec(~[1, 3, 5]);
+  let merged = sorted.merge(&List::from_vec(~[2, 3, 4, 6, 7]));
+  assert!(merged == List::from_vec(~[1, 2, 3, 3, 4, 5, 6, 7]));
+  assert!(sorted.merge(&List::nil()).ptr_eq(&sorted));
+  let inserted = sorted.insert_sorted(4);
+  assert!(inserted == List::from_vec(~[1, 3, 4, 5]) && inserted.drop(3).ptr_eq(&sorted.drop(2)));
+  assert!(sorted.insert_sorted(9) == List::from_vec(~[1, 3, 5, 9]));
+  assert!(sorted.sort().ptr_eq(&sorted));
+  let tail = List::from_vec(~[2, 8, 9]);
+  let unsorted = List::cons(7, List::cons(5, List::cons(6, tail.clone())));
+  let result = unsorted.sort();
+  assert!(result == List::from_vec(~[2, 5, 6, 7, 8, 9]) && result.drop(4).ptr_eq(&tail.drop(1)));
+  let members : ~[uint] = range(0u, 1000).map(|i| i * 7919 % 1000).collect();
+  let expected : List<uint> = range(0u, 1000).collect();
+  assert!(List::from_vec(members).sort() == expected);
+  // Stable: pairs compare by their first part only.
+  #[deriving(Clone, Eq)]
+  struct Keyed(int, int);
+  impl TotalEq for Keyed {
+    fn equals(&self, other: &Keyed) -> bool { self.cmp(other) == Equal }
+  }
+  impl TotalOrd for Keyed {
+    fn cmp(&self, other: &Keyed) -> Ordering {
+      let (Keyed(a, _), Keyed(b, _)) = (*self, *other);
+      a.cmp(&b)
+    }
+  }
+  let keyed = List::from_vec(~[Keyed(2, 0), Keyed(1, 0), Keyed(2, 1), Keyed(1, 1)]);
+  assert!(keyed.sort() == List::from_vec(~[Keyed(1, 0), Keyed(1, 1), Keyed(2, 0), Keyed(2, 1)]));
+}
+#[test]
+fn test_mutable_members() {
+  use std::cell::{Cell, RefCell};
+  let p2 = List::cons_send(Cell::new(1), List::cons_send(Cell::new(2), List::nil_send()));
+  let longer = List::cons_send(Cell::new(0), p2.clone());
+  p2.head().unwrap().set(10);
+  let members : ~[int] = longer.iter().map(|c| c.get()).collect();
+  assert!(members == ~[0, 10, 2] && longer.len() == 3);
+  let caches = List::cons_send(RefCell::new(~[1]), List::nil_send());
+  let shared = caches.clone();
+  caches.head().unwrap().borrow_mut().get().push(2);
+  assert!(*shared.head().unwrap().borrow().get() == ~[1, 2]);
+}
+#[test]
+fn test_list_macro() {
+  let empty : List<int> = list!();
+  assert!(empty.is_empty());
+  assert!(list!(1) == List::cons(1, List::nil()));
+  assert!(list!(1, 2, 3) == List::from_vec(~[1, 2, 3]));
+  assert!(list!(~"a", ~"b").join(",") == ~"a,b");
+}
+#[test]
+fn test_diff() {
+  let old = List::from_vec(~[3, 4, 5]);
+  let new = List::cons(1, List::cons(2, old.tail().unwrap()));
+  let (changed, shared) = new.diff(&old);
+  assert!(changed == List::from_vec(~[1, 2]) && shared.ptr_eq(&old.tail().unwrap()));
+  let (changed, shared) = old.diff(&old.clone());
+  assert!(changed.is_empty() && shared.ptr_eq(&old));
+  let (changed, shared) = old.diff(&List::from_vec(~[3, 4, 5]));
+  assert!(changed == old && shared.is_empty());
+}
+#[test]
+fn test_shared_node_count() {
+  let p3 = List::from_vec(~[1, 2, 3]);
+  let q4 = List::cons(0, p3.tail().unwrap());
+  assert!(p3.node_count() == 4 && q4.node_count() == 4);
+  assert!(p3.shared_node_count_with(&q4) == 3 && q4.shared_node_count_with(&p3) == 3);
+  assert!(p3.shared_node_count_with(&List::cons(9, p3.clone())) == 4);
+  assert!(p3.shared_node_count_with(&List::from_vec(~[1, 2, 3])) == 0);
+}
+#[test]
+fn test_unique_nodes() {
+  let p1 = List::cons(1, List::nil());
+  let p2a = List::cons(2, p1.clone());
+  let p2b = List::cons(3, p1.clone());
+  let heads : ~[Option<int>] = List::unique_nodes([&p2a, &p2b, &p1]).map(|l| match *l.node() {
+    super::Nil => None,
+    super::Cons(x, _) => Some(x)
+  }).collect();
+  assert!(heads == ~[Some(2), Some(1), None, Some(3)]);
+  assert!(List::<int>::unique_nodes([]).next().is_none());
+}
+#[test]
+fn test_debug_structure() {
+  let p1 = List::cons(1, List::nil());
+  let p2a = List::cons(2, p1.clone());
+  let p2b = List::cons(3, p1.clone());
+  assert!(format!("{}", p2a.debug_structure())
+          == ~"1 list(s), longest 2, 3 nodes of which 3 distinct (0% shared)");
+  assert!(format!("{}", List::debug_structure_of_versions([&p2a, &p2b]))
+          == ~"2 list(s), longest 2, 6 nodes of which 4 distinct (33% shared)");
+}
+#[test]
+fn test_format_with() {
+  let p0 : List<int> = List::nil();
+  let p3 = List::from_vec(~[1, 2, 3]);
+  assert!(format!("{}", p3.format_with(" + ")) == ~"1 + 2 + 3");
+  assert!(format!("{}", p0.format_with(" + ")) == ~"");
+  let words = List::from_vec(~["a", "b", "c"]);
+  assert!(words.join(", ") == ~"a, b, c");
+  let owned = List::from_vec(~[~"x"]);
+  assert!(owned.join(", ") == ~"x");
+}
+#[test]
+fn test_persistent_record() {
+  #[deriving(Clone, Eq)]
+  struct Model {
+    items : List<int>,
+    count : uint
+  }
+  persistent_record!(Model { items: List<int> => with_items, count: uint => with_count })
+  let m0 = Model{items: List::nil(), count: 0};
+  let m1 = m0.with_items(List::cons(1, m0.items.clone())).with_count(1);
+  assert!(m0 == Model{items: List::nil(), count: 0});
+  assert!(m1.items == List::cons(1, List::nil()));
+  assert!(m1.count == 1);
+}
+#[test]
+fn test_vec_conversions() {
+  let p3 = List::from_vec(~[1,2,3]);
+  assert!(p3 == List::cons(1, List::cons(2, List::cons(3, List::nil()))));
+  assert!(p3.to_vec() == ~[1,2,3]);
+  let p0 : List<int> = List::from_vec(~[]);
+  assert!(p0.is_empty());
+  assert!(p0.to_vec().is_empty());
+}
+#[test]
+fn test_view() {
+  use super::view;
+  let p0 : List<int> = List::nil();
+  let p2 = List::cons(1, List::cons(2, List::nil()));
+  match p0.view() {
+    view::Nil => (),
+    view::Cons(_, _) => fail!()
+  }
+  match p2.view() {
+    view::Nil => fail!(),
+    view::Cons(x, xs) => {
+      assert!(*x == 1);
+      assert!(*xs == List::cons(2, List::nil()));
+    }
+  }
+}
+#[test]
+fn test_move_iter() {
+  let p3 = List::from_vec(~[~"a", ~"b", ~"c"]);
+  let p4 = List::cons(~"z", p3.clone());
+  let moved : ~[~str] = p4.move_iter().collect();
+  assert!(moved == ~[~"z", ~"a", ~"b", ~"c"]);
+  assert!(p3.len() == 3);
+  let empty : List<int> = List::nil();
+  assert!(empty.move_iter().next().is_none());
+}
+#[test]
+fn test_long_reverse() {
+  // (Not much longer: dropping a list still recurses.)
+  let long : List<int> = range(0, 10000).collect();
+  let reversed = long.reverse();
+  assert!(reversed.iter().next() == Some(&9999));
+  assert!(reversed.reverse() == long);
+}
+#[test]
+fn test_append_concat() {
+  let p2 = List::from_vec(~[1, 2]);
+  let p3 = List::from_vec(~[3, 4, 5]);
+  let joined = p2.append(&p3);
+  assert!(joined == List::from_vec(~[1, 2, 3, 4, 5]));
+  assert!(joined.strip_prefix(&p2).unwrap().ptr_eq(&p3));
+  assert!(List::nil().append(&p3) == p3 && p3.append(&List::nil()) == p3);
+  let all = List::concat([p2.clone(), List::nil(), p3.clone(), p2.clone()]);
+  assert!(all == List::from_vec(~[1, 2, 3, 4, 5, 1, 2]));
+  let none : List<int> = List::concat([]);
+  assert!(none.is_empty());
+}
+#[test]
+fn test_map_filter_fold() {
+  let p4 = List::from_vec(~[1, 2, 3, 4]);
+  assert!(p4.map(|x| *x * 10) == List::from_vec(~[10, 20, 30, 40]));
+  let evens = p4.filter(|x| *x % 2 == 0);
+  assert!(evens == List::from_vec(~[2, 4]));
+  let from_3 = p4.filter(|x| *x != 2);
+  assert!(from_3 == List::from_vec(~[1, 3, 4]));
+  let shared = p4.strip_prefix(&List::from_vec(~[1, 2])).unwrap();
+  assert!(from_3.strip_prefix(&List::from_vec(~[1])).unwrap().ptr_eq(&shared));
+  assert!(p4.filter(|_| true).ptr_eq(&p4));
+  assert!(p4.filter(|_| false).is_empty());
+  assert!(p4.foldl(0, |acc, x| acc * 10 + *x) == 1234);
+  assert!(p4.foldr(0, |x, acc| acc * 10 + *x) == 4321);
+  let joined = p4.foldr(List::nil(), |x, acc| List::cons(*x, acc));
+  assert!(joined == p4);
+  let mapped : ~[int] = p4.iter().map(|x| *x + 1).collect();
+  assert!(mapped == ~[2, 3, 4, 5]);
+}
+#[test]
+fn test_take_drop_split_at() {
+  let p4 = List::from_vec(~[1, 2, 3, 4]);
+  let rest = p4.drop(1);
+  assert!(rest == List::from_vec(~[2, 3, 4]));
+  assert!(rest.ptr_eq(&p4.tails().nth(1).unwrap()));
+  assert!(p4.drop(0).ptr_eq(&p4) && p4.drop(10).is_empty());
+  assert!(p4.take(2) == List::from_vec(~[1, 2]) && p4.take(10) == p4);
+  let (front, back) = p4.split_at(3);
+  assert!(front == List::from_vec(~[1, 2, 3]) && back.ptr_eq(&p4.drop(3)));
+  let (all, none) = p4.split_at(4);
+  assert!(all == p4 && none.is_empty());
+}
+#[test]
+fn test_rev_iter() {
+  let p3 = List::from_vec(~[1, 2, 3]);
+  let backwards : ~[int] = p3.rev_iter().map(|x| *x).collect();
+  assert!(backwards == ~[3, 2, 1]);
+  assert!(p3.rev_iter().size_hint() == (3, Some(3)));
+  let p0 : List<int> = List::nil();
+  assert!(p0.rev_iter().next().is_none());
+}
+#[test]
+fn test_cached_len() {
+  let p3 = List::from_vec(~[1, 2, 3]);
+  let p4 = List::cons(0, p3.clone());
+  assert!(p4.len() == 4 && p3.len() == 3 && p4.drop(3).len() == 1);
+  assert!(p4.iter().size_hint() == (4, Some(4)));
+  let mut it = p4.iter();
+  it.next();
+  assert!(it.size_hint() == (3, Some(3)));
+  // Order is still lexicographic, not by length.
+  assert!(List::from_vec(~[2]) > List::from_vec(~[1, 1]));
+  assert!(List::from_vec(~[1]).cmp(&List::from_vec(~[1, 0])) == Less);
+}
+#[test]
+fn test_compare_shared() {
+  let tail : List<int> = range(0, 10000).collect();
+  let a = List::cons(1, tail.clone());
+  let b = List::cons(1, tail.clone());
+  let c = List::cons(2, tail.clone());
+  assert!(a == b && a.equals(&b) && a != c);
+  assert!(a < c && !(c < a) && a.cmp(&c) == Less && a.cmp(&b) == Equal);
+  assert!(a.ptr_eq(&a.clone()) && !a.ptr_eq(&b));
+  assert!(List::from_vec(~[1, 2]) < List::from_vec(~[1, 2, 0]));
+  assert!(List::from_vec(~[1, 3]) > List::from_vec(~[1, 2, 0]));
+}
+#[test]
+fn test_hash() {
+  use std::hashmap::HashMap;
+  use std::hash::Hash;
+  let tail = List::from_vec(~[2, 3]);
+  let a = List::cons(1, tail.clone());
+  let b = List::from_vec(~[1, 2, 3]);
+  assert!(a.hash() == b.hash());
+  assert!(a.hash() != tail.hash());
+  let mut memo = HashMap::new();
+  memo.insert(a.clone(), ~"a");
+  memo.insert(tail.clone(), ~"tail");
+  assert!(memo.find(&b) == Some(&~"a"));
+  assert!(memo.find(&List::from_vec(~[2, 3])) == Some(&~"tail"));
+  assert!(memo.find(&List::nil()).is_none());
+}
+#[test]
+fn test_accessors() {
+  let p3 = List::from_vec(~[1, 2, 3]);
+  let p0 : List<int> = List::nil();
+  assert!(p3.head() == Some(&1) && p0.head().is_none());
+  assert!(p3.tail() == Some(List::from_vec(~[2, 3])) && p0.tail().is_none());
+  assert!(p3.tail().unwrap().ptr_eq(&p3.drop(1)));
+  assert!(p3.get(0) == Some(&1) && p3.get(2) == Some(&3) && p3.get(3).is_none());
+  assert!(p3.last() == Some(&3) && p0.last().is_none());
+}
+#[test]
+fn test_zipper() {
+  use super::zipper::Zipper;
+  let p5 = List::from_vec(~[1, 2, 3, 4, 5]);
+  let z = Zipper::new(&p5).right().unwrap();
+  assert!(z.focus() == Some(&2) && z.index() == 1);
+  let edited = z.replace(20).right().unwrap().insert(25).to_list();
+  assert!(edited == List::from_vec(~[1, 20, 25, 3, 4, 5]));
+  assert!(edited.drop(3).ptr_eq(&p5.drop(2)));
+  let deleted = z.delete().unwrap();
+  assert!(deleted.focus() == Some(&3) && deleted.to_list() == List::from_vec(~[1, 3, 4, 5]));
+  assert!(z.left().unwrap().left().is_none());
+  let mut end = Zipper::new(&p5);
+  while end.focus().is_some() {
+    end = end.right().unwrap();
+  }
+  assert!(end.index() == 5 && end.right().is_none() && end.delete().is_none());
+  assert!(end.insert(6).to_list() == List::from_vec(~[1, 2, 3, 4, 5, 6]));
+  assert!(Zipper::new(&p5).to_list().ptr_eq(&p5));
+}
+#[test]
+fn test_serialize() {
+  use persistent::json_round_trip;
+  let p3 = List::from_vec(~[1, 2, 3]);
+  let (text, decoded) = json_round_trip(&p3);
+  assert!(text == ~"{\"version\":1,\"members\":[1,2,3]}");
+  assert!(decoded == p3);
+  // Decoding builds the list without recursing, so length doesn't matter.
+  let long : List<uint> = range(0u, 100000).collect();
+  let (_, decoded) = json_round_trip(&long);
+  assert!(decoded.len() == 100000 && decoded == long);
+}
+#[test]
+#[should_fail]
+fn test_decode_other_version() {
+  use extra::json;
+  use extra::serialize::Decodable;
+  let text = "{\"version\":2,\"members\":[1,2,3]}";
+  let mut decoder = json::Decoder::new(json::from_str(text).unwrap());
+  let _ : List<int> = Decodable::decode(&mut decoder);
+}
+#[test]
+fn test_from_slice() {
+  let members = [1, 2, 3];
+  let p3 = List::from_slice(members);
+  assert!(p3 == List::from_vec(~[1, 2, 3]) && p3.to_vec() == ~[1, 2, 3]);
+  let empty : List<int> = List::from_slice([]);
+  assert!(empty.is_empty());
+}
+}
+
+}
+
+pub mod hashmap {
+
+use std::rc::Rc;
+use std::ptr;
+use std::hash::Hash;
+use std::util;
+use persistent::traits::{PersistentMap, Change, Added, Removed, Updated};
+use extra::serialize::{Encodable, Decodable, Encoder, Decoder};
+use persistent::{encode_versioned, decode_versioned, unknown_version};
+
+// Each level of the trie uses this many bits of the hash.
+static BITS : uint = 5;
+static MASK : u64 = 31;
+
+/// Persistent hash map, a hash array mapped trie: lookups, insert and
+/// remove are O(log32 n), and the new maps made by insert and remove
+/// share all but one path through the trie with the original.
+pub struct HashMap<K, V> {
+  priv root : Rc<Node<K, V>>,
+  priv size : uint
+}
+
+enum Node<K, V> {
+  // Which of the 32 possible children are present, and those
+  // children, in order.
+  Branch(u32, ~[Rc<Node<K, V>>]),
+  Leaf(u64, K, V),
+  // Leaves for different keys whose hashes are entirely the same.
+  Collision(u64, ~[Rc<Node<K, V>>])
+}
+
+impl<K, V> Clone for HashMap<K, V> {
+  fn clone(&self) -> HashMap<K, V> {
+    HashMap{root: self.root.clone(), size: self.size}
+  }
+}
+
+fn bit_for(hash: u64, shift: uint) -> u32 {
+  1u32 << (((hash >> (shift as u64)) & MASK) as u32)
+}
+
+// Position of a child in a branch's children.
+fn index(bitmap: u32, bit: u32) -> uint {
+  let mut x = bitmap & (bit - 1);
+  let mut count = 0;
+  while x != 0 {
+    x &= x - 1;
+    count += 1;
+  }
+  count
+}
+
+fn is_branch<K, V>(node: &Rc<Node<K, V>>) -> bool {
+  match *node.borrow() {
+    Branch(_, _) => true,
+    _ => false
+  }
+}
+
+// A node holding both a and b (whose hashes differ), from this depth down.
+fn merge<K: Freeze, V: Freeze>(shift: uint, a: Rc<Node<K, V>>, a_hash: u64,
+                               b: Rc<Node<K, V>>, b_hash: u64) -> Rc<Node<K, V>> {
+  let a_bit = bit_for(a_hash, shift);
+  let b_bit = bit_for(b_hash, shift);
+  if a_bit == b_bit {
+    Rc::new(Branch(a_bit, ~[merge(shift + BITS, a, a_hash, b, b_hash)]))
+  } else if a_bit < b_bit {
+    Rc::new(Branch(a_bit | b_bit, ~[a, b]))
+  } else {
+    Rc::new(Branch(a_bit | b_bit, ~[b, a]))
+  }
+}
+
+// Returns the new node, and whether the key wasn't there before.
+fn insert_into<K: Hash+Eq+Freeze, V: Freeze>(node: &Rc<Node<K, V>>, shift: uint,
+                                              hash: u64, key: K, value: V)
+    -> (Rc<Node<K, V>>, bool) {
+  match *node.borrow() {
+    Branch(bitmap, ref children) => {
+      let bit = bit_for(hash, shift);
+      let i = index(bitmap, bit);
+      let mut new_children = children.clone();
+      if bitmap & bit == 0 {
+        new_children.insert(i, Rc::new(Leaf(hash, key, value)));
+        (Rc::new(Branch(bitmap | bit, new_children)), true)
+      } else {
+        let (child, added) = insert_into(&children[i], shift + BITS, hash, key, value);
+        new_children[i] = child;
+        (Rc::new(Branch(bitmap, new_children)), added)
+      }
+    }
+    Leaf(h, ref k, _) => {
+      if h != hash {
+        (merge(shift, node.clone(), h, Rc::new(Leaf(hash, key, value)), hash), true)
+      } else if *k == key {
+        (Rc::new(Leaf(hash, key, value)), false)
+      } else {
+        (Rc::new(Collision(hash, ~[node.clone(), Rc::new(Leaf(hash, key, value))])), true)
+      }
+    }
+    Collision(h, ref leaves) => {
+      if h != hash {
+        return (merge(shift, node.clone(), h, Rc::new(Leaf(hash, key, value)), hash), true);
+      }
+      let mut new_leaves = leaves.clone();
+      let existing = leaves.iter().position(|leaf| match *leaf.borrow() {
+        Leaf(_, ref k, _) => *k == key,
+        _ => false
+      });
+      let added = match existing {
+        Some(j) => { new_leaves[j] = Rc::new(Leaf(hash, key, value)); false }
+        None => { new_leaves.push(Rc::new(Leaf(hash, key, value))); true }
+      };
+      (Rc::new(Collision(hash, new_leaves)), added)
+    }
+  }
+}
+
+// None if the key isn't there; otherwise the new node,
+// or None if the node became empty.
+fn remove_from<K: Hash+Eq+Freeze, V: Freeze>(node: &Rc<Node<K, V>>, shift: uint,
+                                              hash: u64, key: &K)
+    -> Option<Option<Rc<Node<K, V>>>> {
+  match *node.borrow() {
+    Branch(bitmap, ref children) => {
+      let bit = bit_for(hash, shift);
+      if bitmap & bit == 0 {
+        return None;
+      }
+      let i = index(bitmap, bit);
+      match remove_from(&children[i], shift + BITS, hash, key) {
+        None => None,
+        Some(None) => {
+          if bitmap == bit {
+            return Some(None);
+          }
+          let mut new_children = children.clone();
+          new_children.remove(i);
+          // A branch with only a leaf under it can be that leaf.
+          if new_children.len() == 1 && !is_branch(&new_children[0]) {
+            Some(Some(new_children[0].clone()))
+          } else {
+            Some(Some(Rc::new(Branch(bitmap & !bit, new_children))))
+          }
+        }
+        Some(Some(child)) => {
+          if children.len() == 1 && !is_branch(&child) {
+            return Some(Some(child));
+          }
+          let mut new_children = children.clone();
+          new_children[i] = child;
+          Some(Some(Rc::new(Branch(bitmap, new_children))))
+        }
+      }
+    }
+    Leaf(h, ref k, _) => {
+      if h == hash && *k == *key { Some(None) } else { None }
+    }
+    Collision(h, ref leaves) => {
+      if h != hash {
+        return None;
+      }
+      let found = leaves.iter().position(|leaf| match *leaf.borrow() {
+        Leaf(_, ref k, _) => *k == *key,
+        _ => false
+      });
+      match found {
+        None => None,
+        Some(j) => {
+          let mut new_leaves = leaves.clone();
+          new_leaves.remove(j);
+          if new_leaves.len() == 1 {
+            Some(Some(new_leaves[0].clone()))
+          } else {
+            Some(Some(Rc::new(Collision(hash, new_leaves))))
+          }
+        }
+      }
+    }
+  }
+}
+
+impl<K: Hash+Eq+Freeze, V: Freeze> HashMap<K, V> {
+  /// Create an empty map.
+  pub fn new() -> HashMap<K, V> {
+    HashMap{root: Rc::new(Branch(0, ~[])), size: 0}
+  }
+  /// Create a map that maps key to value, and everything else as self does.
+  pub fn insert(&self, key: K, value: V) -> HashMap<K, V> {
+    let hash = key.hash();
+    let (root, added) = insert_into(&self.root, 0, hash, key, value);
+    HashMap{root: root, size: if added { self.size + 1 } else { self.size }}
+  }
+  /// Create a map without key, and everything else as self has it.
+  /// (If key isn't in self, the result is just a copy of self.)
+  pub fn remove(&self, key: &K) -> HashMap<K, V> {
+    match remove_from(&self.root, 0, key.hash(), key) {
+      None => self.clone(),
+      Some(None) => HashMap::new(),
+      Some(Some(root)) => HashMap{root: root, size: self.size - 1}
+    }
+  }
+}
+
+impl<K: Hash+Eq+Clone, V: Eq+Clone> HashMap<K, V> {
+  /// Iterate over the changes that turn self into new, in no
+  /// particular order.  Subtries the maps share are skipped without
+  /// looking inside.
+  pub fn diff_iter(&self, new: &HashMap<K, V>) -> Diff<K, V> {
+    Diff{stack: ~[Compare(Some(self.root.clone()), Some(new.root.clone()), 0)]}
+  }
+}
+
+impl<K, V> HashMap<K, V> {
+  /// Iterate over the (key, value) pairs, in no particular order.
+  pub fn iter<'t>(&'t self) -> Entries<'t, K, V> {
+    Entries{root: Some(self.root.borrow()), stack: ~[], remaining: self.size}
+  }
+}
+
+impl<K, V> Container for HashMap<K, V> {
+  fn len(&self) -> uint {
+    self.size
+  }
+}
+
+impl<K: Hash+Eq, V> Map<K, V> for HashMap<K, V> {
+  fn contains_key(&self, key: &K) -> bool {
+    self.find(key).is_some()
+  }
+  fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+    let hash = key.hash();
+    let mut node : &'a Node<K, V> = self.root.borrow();
+    let mut shift = 0;
+    loop {
+      match *node {
+        Branch(bitmap, ref children) => {
+          let bit = bit_for(hash, shift);
+          if bitmap & bit == 0 {
+            return None;
+          }
+          node = children[index(bitmap, bit)].borrow();
+          shift += BITS;
+        }
+        Leaf(h, ref k, ref v) => {
+          return if h == hash && *k == *key { Some(v) } else { None };
+        }
+        Collision(h, ref leaves) => {
+          if h != hash {
+            return None;
+          }
+          for leaf in leaves.iter() {
+            match *leaf.borrow() {
+              Leaf(_, ref k, ref v) if *k == *key => return Some(v),
+              _ => ()
+            }
+          }
+          return None;
+        }
+      }
+    }
+  }
+}
+
+impl<K: Hash+Eq+Freeze, V: Freeze> PersistentMap<K, V> for HashMap<K, V> {
+  fn empty() -> HashMap<K, V> {
+    HashMap::new()
+  }
+  fn insert(&self, k: K, v: V) -> HashMap<K, V> {
+    self.insert(k, v)
+  }
+  fn remove(&self, k: &K) -> HashMap<K, V> {
+    self.remove(k)
+  }
+}
+
+impl<K: Hash+Eq, V: Eq> Eq for HashMap<K, V> {
+  fn eq(&self, other: &HashMap<K, V>) -> bool {
+    self.len() == other.len() && self.iter().all(|(k, v)| other.find(k) == Some(v))
+  }
+}
+
+impl<K: Hash+Eq+Freeze, V: Freeze> Default for HashMap<K, V> {
+  fn default() -> HashMap<K, V> {
+    HashMap::new()
+  }
+}
+
+impl<K: Hash+Eq+Freeze, V: Freeze> FromIterator<(K, V)> for HashMap<K, V> {
+  fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> HashMap<K, V> {
+    let mut map = HashMap::new();
+    for (k, v) in *iter {
+      map = map.insert(k, v);
+    }
+    map
+  }
+}
+
+impl<S: Encoder, K: Encodable<S>, V: Encodable<S>> Encodable<S> for HashMap<K, V> {
+  fn encode(&self, s: &mut S) {
+    encode_versioned(s, "HashMap", |s| {
+      s.emit_map(self.len(), |s| {
+        for (i, (k, v)) in self.iter().enumerate() {
+          s.emit_map_elt_key(i, |s| k.encode(s));
+          s.emit_map_elt_val(i, |s| v.encode(s));
+        }
+      })
+    })
+  }
+}
+
+impl<D: Decoder, K: Decodable<D>+Hash+Eq+Freeze, V: Decodable<D>+Freeze> Decodable<D> for HashMap<K, V> {
+  fn decode(d: &mut D) -> HashMap<K, V> {
+    decode_versioned(d, "HashMap", |d, version| match version {
+      // Version 1: the entries, in no particular order.
+      1 => d.read_map(|d, len| {
+        let mut map = HashMap::new();
+        for i in range(0, len) {
+          let k = d.read_map_elt_key(i, |d| Decodable::decode(d));
+          let v = d.read_map_elt_val(i, |d| Decodable::decode(d));
+          map = map.insert(k, v);
+        }
+        map
+      }),
+      _ => unknown_version("HashMap", version)
+    })
+  }
+}
+
+/// Iterator over a HashMap's (key, value) pairs.
+pub struct Entries<'self, K, V> {
+  priv root : Option<&'self Node<K, V>>,
+  // The not-yet-visited children at each level we're inside.
+  priv stack : ~[&'self [Rc<Node<K, V>>]],
+  priv remaining : uint
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self V)> for Entries<'self, K, V> {
+  fn next(&mut self) -> Option<(&'self K, &'self V)> {
+    loop {
+      let node = match util::replace(&mut self.root, None) {
+        Some(root) => root,
+        None => {
+          let depth = self.stack.len();
+          if depth == 0 {
+            return None;
+          }
+          let siblings = self.stack[depth - 1];
+          if siblings.is_empty() {
+            self.stack.truncate(depth - 1);
+            continue;
+          }
+          self.stack[depth - 1] = siblings.slice_from(1);
+          siblings[0].borrow()
+        }
+      };
+      match *node {
+        Branch(_, ref children) => self.stack.push(children.slice(0, children.len())),
+        Collision(_, ref leaves) => self.stack.push(leaves.slice(0, leaves.len())),
+        Leaf(_, ref k, ref v) => {
+          self.remaining -= 1;
+          return Some((k, v));
+        }
+      }
+    }
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    (self.remaining, Some(self.remaining))
+  }
+}
+
+/// Iterator over the changes from one HashMap to another.
+pub struct Diff<K, V> {
+  // Work still to do, the next last.
+  priv stack : ~[DiffStep<K, V>]
+}
+
+enum DiffStep<K, V> {
+  // Find the changes from the first node to the second, which are at
+  // the depth where hashes are shifted by this much.
+  Compare(Option<Rc<Node<K, V>>>, Option<Rc<Node<K, V>>>, uint),
+  Emit(Change<K, V>)
+}
+
+// A node's children as a branch at this depth would hold them: a leaf
+// or collision is the only child, at its hash's bit.
+fn as_branch<K, V>(t: &Option<Rc<Node<K, V>>>, shift: uint) -> (u32, ~[Rc<Node<K, V>>]) {
+  match *t {
+    None => (0, ~[]),
+    Some(ref n) => match *n.borrow() {
+      Branch(bitmap, ref children) => (bitmap, children.clone()),
+      Leaf(h, _, _) | Collision(h, _) => (bit_for(h, shift), ~[n.clone()])
+    }
+  }
+}
+
+// The leaves of a leaf or collision.
+fn leaves<K, V>(t: &Option<Rc<Node<K, V>>>) -> ~[Rc<Node<K, V>>] {
+  match *t {
+    None => ~[],
+    Some(ref n) => match *n.borrow() {
+      Collision(_, ref leaves) => leaves.clone(),
+      Leaf(_, _, _) => ~[n.clone()],
+      Branch(_, _) => fail!("HashMap: expected a leaf, found a branch")
+    }
+  }
+}
+
+fn find_leaf<'a, K: Eq, V>(leaves: &'a [Rc<Node<K, V>>], key: &K) -> Option<&'a V> {
+  for leaf in leaves.iter() {
+    match *leaf.borrow() {
+      Leaf(_, ref k, ref v) if *k == *key => return Some(v),
+      _ => ()
+    }
+  }
+  None
+}
+
+impl<K: Hash+Eq+Clone, V: Eq+Clone> Diff<K, V> {
+  fn expand(&mut self, old: &Option<Rc<Node<K, V>>>, new: &Option<Rc<Node<K, V>>>, shift: uint) {
+    let either_branch = match (old, new) {
+      (&Some(ref a), &Some(ref b)) => {
+        if ptr::to_unsafe_ptr(a.borrow()) == ptr::to_unsafe_ptr(b.borrow()) {
+          return;
+        }
+        is_branch(a) || is_branch(b)
+      }
+      (&Some(ref a), &None) => is_branch(a),
+      (&None, &Some(ref b)) => is_branch(b),
+      (&None, &None) => return
+    };
+    if either_branch {
+      // Line the children up by bit, and compare each pair.
+      let (old_bits, old_kids) = as_branch(old, shift);
+      let (new_bits, new_kids) = as_branch(new, shift);
+      for i in range(0u32, 32).invert() {
+        let bit = 1u32 << i;
+        let a = if old_bits & bit == 0 { None } else { Some(old_kids[index(old_bits, bit)].clone()) };
+        let b = if new_bits & bit == 0 { None } else { Some(new_kids[index(new_bits, bit)].clone()) };
+        if a.is_some() || b.is_some() {
+          self.stack.push(Compare(a, b, shift + BITS));
+        }
+      }
+    } else {
+      // Leaves for at most a few keys on each side: match them up by key.
+      let (old_leaves, new_leaves) = (leaves(old), leaves(new));
+      for leaf in old_leaves.iter() {
+        match *leaf.borrow() {
+          Leaf(_, ref k, ref x) => match find_leaf(new_leaves, k) {
+            None => self.stack.push(Emit(Removed(k.clone(), x.clone()))),
+            Some(y) => if *x != *y {
+              self.stack.push(Emit(Updated(k.clone(), x.clone(), y.clone())));
+            }
+          },
+          _ => ()
+        }
+      }
+      for leaf in new_leaves.iter() {
+        match *leaf.borrow() {
+          Leaf(_, ref k, ref y) => if find_leaf(old_leaves, k).is_none() {
+            self.stack.push(Emit(Added(k.clone(), y.clone())));
+          },
+          _ => ()
+        }
+      }
+    }
+  }
+}
+
+impl<K: Hash+Eq+Clone, V: Eq+Clone> Iterator<Change<K, V>> for Diff<K, V> {
+  fn next(&mut self) -> Option<Change<K, V>> {
+    while !self.stack.is_empty() {
+      let n = self.stack.len();
+      match self.stack.swap_remove(n - 1) {
+        Emit(change) => return Some(change),
+        Compare(old, new, shift) => self.expand(&old, &new, shift)
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::HashMap;
+use std::hash::Hash;
+use std::to_bytes::{IterBytes, Cb};
+
+// Keys that all hash the same, to test collisions.
+#[deriving(Eq, Clone)]
+struct Colliding(int);
+impl IterBytes for Colliding {
+  fn iter_bytes(&self, lsb0: bool, f: Cb) -> bool {
+    0u8.iter_bytes(lsb0, f)
+  }
+}
+
+#[test]
+fn test_insert_find_remove() {
+  let m0 : HashMap<int, ~str> = HashMap::new();
+  let m1 = m0.insert(1, ~"one");
+  let m2 = m1.insert(2, ~"two");
+  let m2b = m2.insert(1, ~"uno");
+  assert!(m0.is_empty());
+  assert!(m1.len() == 1);
+  assert!(m2.len() == 2);
+  assert!(m2b.len() == 2);
+  assert!(m2.find(&1) == Some(&~"one"));
+  assert!(m2b.find(&1) == Some(&~"uno"));
+  assert!(m1.find(&2) == None);
+  let m3 = m2.remove(&1);
+  assert!(m3.len() == 1);
+  assert!(!m3.contains_key(&1));
+  assert!(m2.contains_key(&1));
+  assert!(m3.remove(&5).len() == 1);
+  assert!(m3.remove(&2).is_empty());
+  assert!(m1 == m2.remove(&2));
+  assert!(m1 != m2);
+}
+
+#[test]
+fn test_many() {
+  let mut maps = ~[HashMap::new()];
+  for i in range(0, 2000) {
+    let m = maps[maps.len() - 1].insert(i, i * 10);
+    maps.push(m);
+  }
+  for (n, m) in maps.iter().enumerate() {
+    assert!(m.len() == n);
+    assert!(m.iter().count() == n);
+  }
+  let full = maps[2000].clone();
+  for i in range(0, 2000) {
+    assert!(full.find(&i) == Some(&(i * 10)));
+    assert!(maps[i as uint].find(&i) == None);
+  }
+  let mut m = full.clone();
+  for i in range(0, 2000) {
+    m = m.remove(&i);
+    assert!(m.len() == 1999 - i as uint);
+    assert!(!m.contains_key(&i));
+  }
+  assert!(full.len() == 2000);
+  let collected : HashMap<int, int> = range(0, 2000).map(|i| (i, i * 10)).collect();
+  assert!(collected == full);
+}
+
+#[test]
+fn test_collisions() {
+  let m : HashMap<Colliding, int> =
+    range(0, 5).map(|i| (Colliding(i), i)).collect();
+  assert!(m.len() == 5);
+  for i in range(0, 5) {
+    assert!(m.find(&Colliding(i)) == Some(&i));
+  }
+  let m2 = m.insert(Colliding(2), 20).remove(&Colliding(0));
+  assert!(m2.len() == 4);
+  assert!(m2.find(&Colliding(2)) == Some(&20));
+  assert!(m.find(&Colliding(2)) == Some(&2));
+  let mut small = m2;
+  for i in range(1, 5) {
+    small = small.remove(&Colliding(i));
+  }
+  assert!(small.is_empty());
+}
+
+#[test]
+fn test_serialize() {
+  use persistent::json_round_trip;
+  let original : HashMap<~str, int> = range(0, 100).map(|i| (i.to_str(), i)).collect();
+  let (_, decoded) = json_round_trip(&original);
+  assert!(decoded == original);
+}
+
+// Applies the changes from old to new to a copy of old, checking that
+// each one makes sense, and returns how many there were.
+fn check_diff<K: Hash+Eq+Clone+Freeze, V: Eq+Clone+Freeze>(old: &HashMap<K, V>, new: &HashMap<K, V>) -> uint {
+  use persistent::traits::{Added, Removed, Updated};
+  let mut patched = old.clone();
+  let mut count = 0;
+  for change in old.diff_iter(new) {
+    patched = match change {
+      Added(k, v) => { assert!(!old.contains_key(&k)); patched.insert(k, v) }
+      Removed(k, v) => { assert!(old.find(&k) == Some(&v)); patched.remove(&k) }
+      Updated(k, x, y) => { assert!(x != y && old.find(&k) == Some(&x)); patched.insert(k, y) }
+    };
+    count += 1;
+  }
+  assert!(patched == *new);
+  count
+}
+
+#[test]
+fn test_diff_iter() {
+  use std::rand::{Rng, task_rng};
+  let m : HashMap<int, int> = range(0, 1000).map(|i| (i, i)).collect();
+  assert!(check_diff(&m, &m.clone()) == 0 && check_diff(&m, &m.insert(5, 5)) == 0);
+  assert!(check_diff(&m, &m.insert(5, 6).remove(&7).insert(-1, 0)) == 3);
+  assert!(check_diff(&m, &HashMap::new()) == 1000 && check_diff(&HashMap::new(), &m) == 1000);
+  let mut rng = task_rng();
+  for _ in range(0, 50) {
+    let old : HashMap<int, int> = range(0, 100).map(|_| (rng.gen_range(0, 200), rng.gen_range(0, 3))).collect();
+    let mut new = old.clone();
+    for _ in range(0, rng.gen_range(0, 20)) {
+      let k = rng.gen_range(0, 200);
+      new = if rng.gen() { new.insert(k, rng.gen_range(0, 3)) } else { new.remove(&k) };
+    }
+    check_diff(&old, &new);
+  }
+}
+
+#[test]
+fn test_diff_iter_collisions() {
+  let m : HashMap<Colliding, int> = range(0, 5).map(|i| (Colliding(i), i)).collect();
+  assert!(check_diff(&m, &m.insert(Colliding(2), 20).remove(&Colliding(0))) == 2);
+  // A lone leaf against a whole trie, and back.
+  let mixed : HashMap<int, int> = range(0, 100).map(|i| (i, i)).collect();
+  let single = HashMap::new().insert(7, 0);
+  assert!(check_diff(&single, &mixed) == 100 && check_diff(&mixed, &single) == 100);
+  assert!(check_diff(&m, &m.remove(&Colliding(1)).remove(&Colliding(3)).remove(&Colliding(4))) == 3);
+}
+}
+
+}
+
+pub mod vector {
+
+use std::rc::Rc;
+use std::util;
+use std::ptr;
+use std::mem;
+use std::cmp::min;
+use std::hashmap::HashSet;
+use persistent::traits::PersistentSeq;
+use extra::serialize::{Encodable, Decodable, Encoder, Decoder};
+use persistent::{encode_versioned, decode_versioned, unknown_version};
+
+// Each level of the trie uses this many bits of the index.
+static BITS : uint = 5;
+static WIDTH : uint = 32;
+static MASK : uint = 31;
+
+/// Persistent vector, a bit-partitioned trie with 32-way branching
+/// (as in Clojure): get, set and push are O(log32 n), which is
+/// effectively constant, and the new vectors made by set and push share
+/// all but one path through the trie with the original.
+pub struct Vector<T> {
+  priv root : Rc<Node<T>>,
+  priv size : uint,
+  // How far to shift an index to get the root's child for it.
+  priv shift : uint
+}
+
+enum Node<T> {
+  Internal(~[Rc<Node<T>>]),
+  Leaf(~[T])
+}
+
+impl<T> Clone for Vector<T> {
+  fn clone(&self) -> Vector<T> {
+    Vector{root: self.root.clone(), size: self.size, shift: self.shift}
+  }
+}
+
+// A path down to a leaf holding only x.
+fn new_path<T: Freeze>(shift: uint, x: T) -> Rc<Node<T>> {
+  if shift == 0 {
+    Rc::new(Leaf(~[x]))
+  } else {
+    Rc::new(Internal(~[new_path(shift - BITS, x)]))
+  }
+}
+
+fn push_into<T: Clone+Freeze>(node: &Rc<Node<T>>, shift: uint, index: uint, x: T) -> Rc<Node<T>> {
+  match *node.borrow() {
+    Leaf(ref members) => {
+      let mut new_members = members.clone();
+      new_members.push(x);
+      Rc::new(Leaf(new_members))
+    }
+    Internal(ref children) => {
+      let i = (index >> shift) & MASK;
+      let mut new_children = children.clone();
+      if i < children.len() {
+        new_children[i] = push_into(&children[i], shift - BITS, index, x);
+      } else {
+        new_children.push(new_path(shift - BITS, x));
+      }
+      Rc::new(Internal(new_children))
+    }
+  }
+}
+
+fn set_in<T: Clone+Freeze>(node: &Rc<Node<T>>, shift: uint, index: uint, x: T) -> Rc<Node<T>> {
+  match *node.borrow() {
+    Leaf(ref members) => {
+      let mut new_members = members.clone();
+      new_members[index & MASK] = x;
+      Rc::new(Leaf(new_members))
+    }
+    Internal(ref children) => {
+      let i = (index >> shift) & MASK;
+      let mut new_children = children.clone();
+      new_children[i] = set_in(&children[i], shift - BITS, index, x);
+      Rc::new(Internal(new_children))
+    }
+  }
+}
+
+impl<T: Freeze> Vector<T> {
+  /// Create an empty vector.
+  pub fn new() -> Vector<T> {
+    Vector{root: Rc::new(Leaf(~[])), size: 0, shift: 0}
+  }
+  /// Create a vector of the members, in the same order,
+  /// moving them rather than copying.  This builds the trie
+  /// bottom-up in one pass, rather than pushing one at a time.
+  pub fn from_vec(members: ~[T]) -> Vector<T> {
+    let size = members.len();
+    if size == 0 {
+      return Vector::new();
+    }
+    let mut nodes = ~[];
+    let mut chunk = ~[];
+    for x in members.move_iter() {
+      chunk.push(x);
+      if chunk.len() == WIDTH {
+        nodes.push(Rc::new(Leaf(util::replace(&mut chunk, ~[]))));
+      }
+    }
+    if !chunk.is_empty() {
+      nodes.push(Rc::new(Leaf(chunk)));
+    }
+    let mut shift = 0;
+    while nodes.len() > 1 {
+      let mut parents = ~[];
+      let mut children = ~[];
+      for node in nodes.move_iter() {
+        children.push(node);
+        if children.len() == WIDTH {
+          parents.push(Rc::new(Internal(util::replace(&mut children, ~[]))));
+        }
+      }
+      if !children.is_empty() {
+        parents.push(Rc::new(Internal(children)));
+      }
+      nodes = parents;
+      shift += BITS;
+    }
+    Vector{root: nodes[0].clone(), size: size, shift: shift}
+  }
+}
+
+impl<T: Clone+Freeze> Vector<T> {
+  /// Create a vector with x added at the end.
+  pub fn push(&self, x: T) -> Vector<T> {
+    if self.size == 1 << (self.shift + BITS) {
+      // The trie is full; it needs another level.
+      let root = Rc::new(Internal(~[self.root.clone(), new_path(self.shift, x)]));
+      Vector{root: root, size: self.size + 1, shift: self.shift + BITS}
+    } else {
+      Vector{root: push_into(&self.root, self.shift, self.size, x),
+             size: self.size + 1, shift: self.shift}
+    }
+  }
+  /// Create a vector with the member at index replaced by x.
+  /// Fails if index is out of bounds.
+  pub fn set(&self, index: uint, x: T) -> Vector<T> {
+    if index >= self.size {
+      fail!("Vector::set: index {} out of bounds for length {}", index, self.size);
+    }
+    Vector{root: set_in(&self.root, self.shift, index, x), size: self.size, shift: self.shift}
+  }
+  /// Create a vector with the member at index replaced by f of it.
+  /// Fails if index is out of bounds.
+  pub fn update(&self, index: uint, f: |&T| -> T) -> Vector<T> {
+    let x = match self.get(index) {
+      Some(old) => f(old),
+      None => fail!("Vector::update: index {} out of bounds for length {}", index, self.size)
+    };
+    self.set(index, x)
+  }
+  /// Create a vector of copies of the slice's members, in the same order.
+  pub fn from_slice(members: &[T]) -> Vector<T> {
+    Vector::from_vec(members.to_owned())
+  }
+  /// Create a vector (the ordinary kind) of copies of the members, in order.
+  pub fn to_vec(&self) -> ~[T] {
+    let mut result = ::std::vec::with_capacity(self.size);
+    for x in self.iter() {
+      result.push(x.clone());
+    }
+    result
+  }
+}
+
+impl<T> Vector<T> {
+  /// The member at index, if index is in bounds.
+  pub fn get<'t>(&'t self, index: uint) -> Option<&'t T> {
+    if index >= self.size {
+      return None;
+    }
+    Some(&self.leaf_containing(index)[index & MASK])
+  }
+  /// Iterate over the members in order.
+  pub fn iter<'t>(&'t self) -> VectorIterator<'t, T> {
+    VectorIterator{vector: self, leaf: &[], front: 0, back: self.size}
+  }
+  // The members of the leaf that index (which must be in bounds) is in.
+  fn leaf_containing<'t>(&'t self, index: uint) -> &'t [T] {
+    let mut node : &'t Node<T> = self.root.borrow();
+    let mut shift = self.shift;
+    loop {
+      match *node {
+        Internal(ref children) => {
+          node = children[(index >> shift) & MASK].borrow();
+          shift -= BITS;
+        }
+        Leaf(ref members) => return members.slice(0, members.len())
+      }
+    }
+  }
+  /// How many trie nodes (internal and leaf) the vector is made of.
+  pub fn node_count(&self) -> uint {
+    count_nodes_outside(&self.root, &HashSet::new())
+  }
+  /// How many of this vector's trie nodes are also nodes of other:
+  /// the memory the two actually share, found by pointer identity.
+  pub fn shared_node_count_with(&self, other: &Vector<T>) -> uint {
+    let mut theirs = HashSet::new();
+    collect_addresses(&other.root, &mut theirs);
+    self.node_count() - count_nodes_outside(&self.root, &theirs)
+  }
+  /// Approximate bytes of heap memory held by the vector's trie nodes.
+  /// Memory that the members themselves own isn't included.
+  pub fn heap_size(&self) -> uint {
+    heap_size_of(&self.root)
+  }
+}
+
+fn address<T>(node: &Rc<Node<T>>) -> uint {
+  ptr::to_unsafe_ptr(node.borrow()) as uint
+}
+
+fn collect_addresses<T>(node: &Rc<Node<T>>, addresses: &mut HashSet<uint>) {
+  addresses.insert(address(node));
+  match *node.borrow() {
+    Internal(ref children) => {
+      for child in children.iter() {
+        collect_addresses(child, addresses);
+      }
+    }
+    Leaf(_) => ()
+  }
+}
+
+// Nodes of the trie that aren't in addresses.  A node that is in it
+// brings its whole subtree with it, so that isn't walked.
+fn count_nodes_outside<T>(node: &Rc<Node<T>>, addresses: &HashSet<uint>) -> uint {
+  if addresses.contains(&address(node)) {
+    return 0;
+  }
+  match *node.borrow() {
+    Internal(ref children) => children.iter().fold(1, |n, child| n + count_nodes_outside(child, addresses)),
+    Leaf(_) => 1
+  }
+}
+
+fn heap_size_of<T>(node: &Rc<Node<T>>) -> uint {
+  // An Rc allocation is the node plus its reference count; the
+  // node's vector is another allocation.
+  let rc_size = mem::size_of::<Node<T>>() + mem::size_of::<uint>();
+  match *node.borrow() {
+    Internal(ref children) => children.iter().fold(
+      rc_size + children.len() * mem::size_of::<Rc<Node<T>>>(),
+      |n, child| n + heap_size_of(child)),
+    Leaf(ref members) => rc_size + members.len() * mem::size_of::<T>()
+  }
+}
+
+impl<T> Container for Vector<T> {
+  fn len(&self) -> uint {
+    self.size
+  }
+}
+
+impl<T: Eq> Eq for Vector<T> {
+  fn eq(&self, other: &Vector<T>) -> bool {
+    self.len() == other.len() && self.iter().zip(other.iter()).all(|(a, b)| *a == *b)
+  }
+}
+
+impl<T: Freeze> Default for Vector<T> {
+  fn default() -> Vector<T> {
+    Vector::new()
+  }
+}
+
+impl<A: Freeze> FromIterator<A> for Vector<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> Vector<A> {
+    let mut builder = VectorBuilder::new();
+    builder.extend(iter);
+    builder.finish()
+  }
+}
+
+impl<S: Encoder, T: Encodable<S>> Encodable<S> for Vector<T> {
+  fn encode(&self, s: &mut S) {
+    encode_versioned(s, "Vector", |s| {
+      s.emit_seq(self.len(), |s| {
+        for (i, x) in self.iter().enumerate() {
+          s.emit_seq_elt(i, |s| x.encode(s));
+        }
+      })
+    })
+  }
+}
+
+impl<D: Decoder, T: Decodable<D>+Freeze> Decodable<D> for Vector<T> {
+  fn decode(d: &mut D) -> Vector<T> {
+    decode_versioned(d, "Vector", |d, version| match version {
+      // Version 1: the members, in index order.
+      1 => d.read_seq(|d, len| {
+        let mut builder = VectorBuilder::new();
+        for i in range(0, len) {
+          builder.push(d.read_seq_elt(i, |d| Decodable::decode(d)));
+        }
+        builder.finish()
+      }),
+      _ => unknown_version("Vector", version)
+    })
+  }
+}
+
+/// Collects members with ordinary mutation, then makes a Vector of
+/// them all at once (bottom-up, so each node is allocated once,
+/// rather than a path of the trie per push).
+pub struct VectorBuilder<T> {
+  priv members : ~[T]
+}
+
+impl<T: Freeze> VectorBuilder<T> {
+  /// Create a builder with no members yet.
+  pub fn new() -> VectorBuilder<T> {
+    VectorBuilder{members: ~[]}
+  }
+  /// Add a member after the ones already added.
+  pub fn push(&mut self, x: T) {
+    self.members.push(x);
+  }
+  /// Create the vector of everything that was added, in order.
+  pub fn finish(self) -> Vector<T> {
+    Vector::from_vec(self.members)
+  }
+}
+
+impl<A: Freeze> Extendable<A> for VectorBuilder<A> {
+  fn extend<T: Iterator<A>>(&mut self, iter: &mut T) {
+    for a in *iter {
+      self.push(a);
+    }
+  }
+}
+
+impl<T: Clone+Freeze> PersistentSeq<T> for Vector<T> {
+  fn empty() -> Vector<T> {
+    Vector::new()
+  }
+  fn push(&self, x: T) -> Vector<T> {
+    self.push(x)
+  }
+  fn get<'a>(&'a self, i: uint) -> Option<&'a T> {
+    self.get(i)
+  }
+  fn each(&self, f: |&T| -> bool) -> bool {
+    for x in self.iter() {
+      if !f(x) { return false; }
+    }
+    true
+  }
+}
+
+/// Iterator over a Vector's members, in order.
+pub struct VectorIterator<'self, T> {
+  priv vector : &'self Vector<T>,
+  // The not-yet-visited members of the current leaf.
+  priv leaf : &'self [T],
+  // The index of the member after leaf, and the index to stop at.
+  priv front : uint,
+  priv back : uint
+}
+
+impl<'self, T> VectorIterator<'self, T> {
+  /// Divide the members still to come into two iterators, the first
+  /// yielding the earlier half and the second the later half, so
+  /// they can be walked separately (by a fork/join traversal, say).
+  /// O(1): each half goes down to its leaves when it gets to them.
+  pub fn split(self) -> (VectorIterator<'self, T>, VectorIterator<'self, T>) {
+    let half = (self.leaf.len() + self.back - self.front) / 2;
+    if half <= self.leaf.len() {
+      (VectorIterator{vector: self.vector, leaf: self.leaf.slice_to(half),
+                      front: self.front, back: self.front},
+       VectorIterator{vector: self.vector, leaf: self.leaf.slice_from(half),
+                      front: self.front, back: self.back})
+    } else {
+      let middle = self.front + half - self.leaf.len();
+      (VectorIterator{vector: self.vector, leaf: self.leaf, front: self.front, back: middle},
+       VectorIterator{vector: self.vector, leaf: &[], front: middle, back: self.back})
+    }
+  }
+}
+
+impl<'self, T> Iterator<&'self T> for VectorIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    if self.leaf.is_empty() {
+      if self.front >= self.back {
+        return None;
+      }
+      let start = self.front & MASK;
+      let leaf = self.vector.leaf_containing(self.front);
+      let end = min(leaf.len(), start + self.back - self.front);
+      self.leaf = leaf.slice(start, end);
+      self.front += end - start;
+    }
+    let x = &self.leaf[0];
+    self.leaf = self.leaf.slice_from(1);
+    Some(x)
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    let remaining = self.leaf.len() + self.back - self.front;
+    (remaining, Some(remaining))
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::Vector;
+#[test]
+fn test_push_get_set() {
+  let v0 : Vector<int> = Vector::new();
+  let v1 = v0.push(1);
+  let v2 = v1.push(2);
+  let v2b = v2.set(0, 10);
+  assert!(v0.is_empty());
+  assert!(v2.len() == 2);
+  assert!(v2.get(0) == Some(&1));
+  assert!(v2b.get(0) == Some(&10));
+  assert!(v2b.get(1) == Some(&2));
+  assert!(v2.get(2) == None);
+  assert!(v1.get(1) == None);
+}
+#[test]
+fn test_many() {
+  let mut v = Vector::new();
+  let mut versions = ~[];
+  for i in range(0u, 40000) {
+    versions.push(v.clone());
+    v = v.push(i);
+  }
+  for i in range(0u, 40000) {
+    assert!(v.get(i) == Some(&i));
+  }
+  for (n, old) in versions.iter().enumerate() {
+    assert!(old.len() == n);
+  }
+  assert!(versions[1057].get(1056) == Some(&1056));
+  let changed = v.set(33000, 0);
+  assert!(changed.get(33000) == Some(&0));
+  assert!(v.get(33000) == Some(&33000));
+  let collected : ~[uint] = v.iter().map(|x| *x).collect();
+  let expected : ~[uint] = range(0u, 40000).collect();
+  assert!(collected == expected);
+  let bulk : Vector<uint> = range(0u, 40000).collect();
+  assert!(bulk == v);
+  assert!(bulk.push(1) == v.push(1));
+}
+#[test]
+#[should_fail]
+fn test_set_out_of_bounds() {
+  let v : Vector<int> = Vector::from_vec(~[1, 2]);
+  v.set(2, 3);
+}
+#[test]
+fn test_builder() {
+  use super::VectorBuilder;
+  let mut builder = VectorBuilder::new();
+  for i in range(0u, 1500) {
+    builder.push(i * 3);
+  }
+  builder.extend(&mut range(0u, 10));
+  let v = builder.finish();
+  assert!(v.len() == 1510 && v.get(1000) == Some(&3000) && v.get(1509) == Some(&9));
+}
+#[test]
+fn test_update() {
+  let v : Vector<int> = Vector::from_vec(~[1, 2, 3]);
+  let w = v.update(1, |x| *x * 10);
+  assert!(w.get(1) == Some(&20) && v.get(1) == Some(&2) && w.get(2) == Some(&3));
+}
+#[test]
+fn test_conversions() {
+  let members : ~[uint] = range(0u, 100).collect();
+  let v = Vector::from_slice(members);
+  assert!(v.len() == 100 && v.get(99) == Some(&99));
+  assert!(v.to_vec() == members);
+  let empty : Vector<int> = Vector::from_slice([]);
+  assert!(empty.to_vec().is_empty());
+}
+#[test]
+fn test_split_iter() {
+  let v : Vector<uint> = range(0u, 1000).collect();
+  let mut iter = v.iter();
+  iter.next();
+  let (first, second) = iter.split();
+  assert!(first.size_hint() == (499, Some(499)) && second.size_hint() == (500, Some(500)));
+  let (a, b) = first.split();
+  let mut seen : ~[uint] = a.map(|x| *x).collect();
+  seen.extend(&mut b.map(|x| *x));
+  seen.extend(&mut second.map(|x| *x));
+  let expected : ~[uint] = range(1u, 1000).collect();
+  assert!(seen == expected);
+  let (left, _) = v.iter().split();
+  let (mut c, mut d) = left.split();
+  assert!(c.next() == Some(&0) && d.next() == Some(&250));
+}
+#[test]
+fn test_serialize() {
+  use persistent::json_round_trip;
+  let original : Vector<uint> = range(0u, 100).collect();
+  let (_, decoded) = json_round_trip(&original);
+  assert!(decoded == original);
+}
+#[test]
+fn test_pvec_macro() {
+  let empty : Vector<int> = pvec![];
+  assert!(empty.is_empty());
+  let v = pvec![1, 2, 3];
+  assert!(v.len() == 3 && v.get(2) == Some(&3));
+}
+#[test]
+fn test_shared_node_count() {
+  use std::mem;
+  // Four leaves under a root.
+  let v : Vector<uint> = range(0u, 100).collect();
+  assert!(v.node_count() == 5);
+  let w = v.set(0, 7);
+  assert!(w.shared_node_count_with(&v) == 3 && v.shared_node_count_with(&v.clone()) == 5);
+  let separate : Vector<uint> = range(0u, 100).collect();
+  assert!(v.shared_node_count_with(&separate) == 0);
+  assert!(v.heap_size() > 100 * mem::size_of::<uint>());
+}
+}
+
+}
+
+pub mod treemap {
+
+use std::rc::Rc;
+use std::ptr;
+use std::mem;
+use std::cmp::max;
+use std::hashmap::HashSet;
+use std::fmt;
+use std::from_str::{FromStr, from_str};
+use StdTreeMap = extra::treemap::TreeMap;
+use persistent::traits::{PersistentMap, Change, Added, Removed, Updated};
+use extra::serialize::{Encodable, Decodable, Encoder, Decoder};
+use persistent::{encode_versioned, decode_versioned, unknown_version, split_top_level};
+
+/// Persistent ordered map, an AVL tree: lookups, insert and remove
+/// are O(log n), iteration is in key order, and the new maps made by
+/// insert and remove share all but one path through the tree with
+/// the original.
+pub struct TreeMap<K, V> {
+  priv root : Tree<K, V>
+}
+
+type Tree<K, V> = Option<Rc<Node<K, V>>>;
+
+struct Node<K, V> {
+  key : K,
+  value : V,
+  left : Tree<K, V>,
+  right : Tree<K, V>,
+  height : uint,
+  // Number of entries in this subtree.
+  size : uint
+}
+
+impl<K, V> Clone for TreeMap<K, V> {
+  fn clone(&self) -> TreeMap<K, V> {
+    TreeMap{root: self.root.clone()}
+  }
+}
+
+fn height<K, V>(t: &Tree<K, V>) -> uint {
+  match *t { None => 0, Some(ref n) => n.borrow().height }
+}
+
+fn size<K, V>(t: &Tree<K, V>) -> uint {
+  match *t { None => 0, Some(ref n) => n.borrow().size }
+}
+
+fn make<K: Freeze, V: Freeze>(left: Tree<K, V>, key: K, value: V, right: Tree<K, V>) -> Tree<K, V> {
+  let h = max(height(&left), height(&right)) + 1;
+  let s = size(&left) + size(&right) + 1;
+  Some(Rc::new(Node{key: key, value: value, left: left, right: right, height: h, size: s}))
+}
+
+// Like make, but rotates to restore the AVL balance, as long as
+// left's and right's heights differ by at most 2.
+fn balance<K: Clone+Freeze, V: Clone+Freeze>(left: Tree<K, V>, key: K, value: V, right: Tree<K, V>)
+    -> Tree<K, V> {
+  let hl = height(&left);
+  let hr = height(&right);
+  if hl > hr + 1 {
+    let l = left.get_ref().borrow();
+    if height(&l.left) >= height(&l.right) {
+      make(l.left.clone(), l.key.clone(), l.value.clone(), make(l.right.clone(), key, value, right))
+    } else {
+      let lr = l.right.get_ref().borrow();
+      make(make(l.left.clone(), l.key.clone(), l.value.clone(), lr.left.clone()),
+           lr.key.clone(), lr.value.clone(),
+           make(lr.right.clone(), key, value, right))
+    }
+  } else if hr > hl + 1 {
+    let r = right.get_ref().borrow();
+    if height(&r.right) >= height(&r.left) {
+      make(make(left, key, value, r.left.clone()), r.key.clone(), r.value.clone(), r.right.clone())
+    } else {
+      let rl = r.left.get_ref().borrow();
+      make(make(left, key, value, rl.left.clone()),
+           rl.key.clone(), rl.value.clone(),
+           make(rl.right.clone(), r.key.clone(), r.value.clone(), r.right.clone()))
+    }
+  } else {
+    make(left, key, value, right)
+  }
+}
+
+// A tree of everything in left, then key, then everything in right,
+// whatever their heights.  All of left's keys must be less than key,
+// and all of right's greater.  O(difference in heights).
+fn join<K: Clone+Freeze, V: Clone+Freeze>(left: Tree<K, V>, key: K, value: V, right: Tree<K, V>)
+    -> Tree<K, V> {
+  let hl = height(&left);
+  let hr = height(&right);
+  if hl > hr + 1 {
+    let l = left.get_ref().borrow();
+    balance(l.left.clone(), l.key.clone(), l.value.clone(), join(l.right.clone(), key, value, right))
+  } else if hr > hl + 1 {
+    let r = right.get_ref().borrow();
+    balance(join(left, key, value, r.left.clone()), r.key.clone(), r.value.clone(), r.right.clone())
+  } else {
+    make(left, key, value, right)
+  }
+}
+
+// The smallest entry, and the tree without it.
+fn remove_min<K: Clone+Freeze, V: Clone+Freeze>(node: &Rc<Node<K, V>>) -> (K, V, Tree<K, V>) {
+  let n = node.borrow();
+  match n.left {
+    None => (n.key.clone(), n.value.clone(), n.right.clone()),
+    Some(ref l) => {
+      let (k, v, rest) = remove_min(l);
+      (k, v, balance(rest, n.key.clone(), n.value.clone(), n.right.clone()))
+    }
+  }
+}
+
+// A tree of everything in left, then everything in right.
+// All of left's keys must be less than all of right's.
+fn join2<K: Clone+Freeze, V: Clone+Freeze>(left: Tree<K, V>, right: Tree<K, V>) -> Tree<K, V> {
+  match right {
+    None => left,
+    Some(ref r) => {
+      let (k, v, rest) = remove_min(r);
+      join(left, k, v, rest)
+    }
+  }
+}
+
+fn insert<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(t: &Tree<K, V>, key: K, value: V) -> Tree<K, V> {
+  match *t {
+    None => make(None, key, value, None),
+    Some(ref node) => {
+      let n = node.borrow();
+      match key.cmp(&n.key) {
+        Less => balance(insert(&n.left, key, value), n.key.clone(), n.value.clone(), n.right.clone()),
+        Greater => balance(n.left.clone(), n.key.clone(), n.value.clone(), insert(&n.right, key, value)),
+        Equal => make(n.left.clone(), key, value, n.right.clone())
+      }
+    }
+  }
+}
+
+// None if key isn't in the tree.
+fn remove<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(t: &Tree<K, V>, key: &K) -> Option<Tree<K, V>> {
+  match *t {
+    None => None,
+    Some(ref node) => {
+      let n = node.borrow();
+      match key.cmp(&n.key) {
+        Less => match remove(&n.left, key) {
+          None => None,
+          Some(l) => Some(balance(l, n.key.clone(), n.value.clone(), n.right.clone()))
+        },
+        Greater => match remove(&n.right, key) {
+          None => None,
+          Some(r) => Some(balance(n.left.clone(), n.key.clone(), n.value.clone(), r))
+        },
+        Equal => Some(join2(n.left.clone(), n.right.clone()))
+      }
+    }
+  }
+}
+
+// Whether the trees are literally the same nodes in memory.
+fn same<K, V>(a: &Tree<K, V>, b: &Tree<K, V>) -> bool {
+  match (a, b) {
+    (&None, &None) => true,
+    (&Some(ref a), &Some(ref b)) => ptr::to_unsafe_ptr(a.borrow()) == ptr::to_unsafe_ptr(b.borrow()),
+    _ => false
+  }
+}
+
+// The entries less than key, key's value if it's there,
+// and the entries greater than key.
+fn split<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(t: &Tree<K, V>, key: &K)
+    -> (Tree<K, V>, Option<V>, Tree<K, V>) {
+  match *t {
+    None => (None, None, None),
+    Some(ref node) => {
+      let n = node.borrow();
+      match key.cmp(&n.key) {
+        Less => {
+          let (l, found, r) = split(&n.left, key);
+          (l, found, join(r, n.key.clone(), n.value.clone(), n.right.clone()))
+        }
+        Greater => {
+          let (l, found, r) = split(&n.right, key);
+          (join(n.left.clone(), n.key.clone(), n.value.clone(), l), found, r)
+        }
+        Equal => (n.left.clone(), Some(n.value.clone()), n.right.clone())
+      }
+    }
+  }
+}
+
+// node's entry between left and right, reusing node itself if
+// left and right are its own subtrees.
+fn rejoin<K: Clone+Freeze, V: Clone+Freeze>(node: &Rc<Node<K, V>>, left: Tree<K, V>, right: Tree<K, V>)
+    -> Tree<K, V> {
+  let n = node.borrow();
+  if same(&left, &n.left) && same(&right, &n.right) {
+    Some(node.clone())
+  } else {
+    join(left, n.key.clone(), n.value.clone(), right)
+  }
+}
+
+// These set operations split the second tree by the first's root, and
+// recurse on the halves.  Subtrees of the first that come through
+// unchanged are reused, and identical subtrees are recognized at once.
+
+fn union<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(a: &Tree<K, V>, b: &Tree<K, V>) -> Tree<K, V> {
+  if same(a, b) {
+    return a.clone();
+  }
+  match (a, b) {
+    (&None, _) => b.clone(),
+    (_, &None) => a.clone(),
+    (&Some(ref node), _) => {
+      let n = node.borrow();
+      let (bl, _, br) = split(b, &n.key);
+      rejoin(node, union(&n.left, &bl), union(&n.right, &br))
+    }
+  }
+}
+
+fn intersection<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(a: &Tree<K, V>, b: &Tree<K, V>)
+    -> Tree<K, V> {
+  if same(a, b) {
+    return a.clone();
+  }
+  match (a, b) {
+    (&None, _) | (_, &None) => None,
+    (&Some(ref node), _) => {
+      let n = node.borrow();
+      let (bl, found, br) = split(b, &n.key);
+      let l = intersection(&n.left, &bl);
+      let r = intersection(&n.right, &br);
+      if found.is_some() { rejoin(node, l, r) } else { join2(l, r) }
+    }
+  }
+}
+
+fn difference<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(a: &Tree<K, V>, b: &Tree<K, V>)
+    -> Tree<K, V> {
+  if same(a, b) {
+    return None;
+  }
+  match (a, b) {
+    (&None, _) => None,
+    (_, &None) => a.clone(),
+    (&Some(ref node), _) => {
+      let n = node.borrow();
+      let (bl, found, br) = split(b, &n.key);
+      let l = difference(&n.left, &bl);
+      let r = difference(&n.right, &br);
+      if found.is_some() { join2(l, r) } else { rejoin(node, l, r) }
+    }
+  }
+}
+
+impl<K: TotalOrd, V> TreeMap<K, V> {
+  /// Create an empty map.
+  pub fn new() -> TreeMap<K, V> {
+    TreeMap{root: None}
+  }
+  /// Iterate over the (key, value) pairs in key order,
+  /// starting at the first key that is not less than key.
+  pub fn lower_bound<'t>(&'t self, key: &K) -> Entries<'t, K, V> {
+    let mut stack = ~[];
+    let mut t = &self.root;
+    loop {
+      match *t {
+        None => break,
+        Some(ref node) => {
+          let n = node.borrow();
+          if n.key.cmp(key) == Less {
+            t = &n.right;
+          } else {
+            stack.push(n);
+            t = &n.left;
+          }
+        }
+      }
+    }
+    Entries::new(stack)
+  }
+}
+
+impl<K: TotalOrd+Freeze, V: Freeze> TreeMap<K, V> {
+  /// Create a map with the entries of a mutable extra::treemap::TreeMap,
+  /// moving them.  This builds a balanced tree in one pass, since the
+  /// entries come out in order.
+  pub fn from_std(entries: StdTreeMap<K, V>) -> TreeMap<K, V> {
+    let n = entries.len();
+    TreeMap{root: build_sorted(n, &mut entries.move_iter())}
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> TreeMap<K, V> {
+  /// Create a mutable extra::treemap::TreeMap with copies of the entries.
+  pub fn to_std(&self) -> StdTreeMap<K, V> {
+    self.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+  }
+  /// Create a map that maps key to value, and everything else as self does.
+  pub fn insert(&self, key: K, value: V) -> TreeMap<K, V> {
+    TreeMap{root: insert(&self.root, key, value)}
+  }
+  /// Create a map without key, and everything else as self has it.
+  /// (If key isn't in self, the result shares everything with self.)
+  pub fn remove(&self, key: &K) -> TreeMap<K, V> {
+    match remove(&self.root, key) {
+      None => self.clone(),
+      Some(root) => TreeMap{root: root}
+    }
+  }
+  /// Create a map of every key in either map.  Where both have a key,
+  /// self's value is used.
+  pub fn union(&self, other: &TreeMap<K, V>) -> TreeMap<K, V> {
+    TreeMap{root: union(&self.root, &other.root)}
+  }
+  /// Create a map of self's entries whose keys are also in other.
+  pub fn intersection(&self, other: &TreeMap<K, V>) -> TreeMap<K, V> {
+    TreeMap{root: intersection(&self.root, &other.root)}
+  }
+  /// Create a map of self's entries whose keys aren't in other.
+  pub fn difference(&self, other: &TreeMap<K, V>) -> TreeMap<K, V> {
+    TreeMap{root: difference(&self.root, &other.root)}
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Eq+Clone+Freeze> TreeMap<K, V> {
+  /// Iterate over the changes that turn self into new, in key order.
+  /// Subtrees the maps share are skipped without looking inside, so
+  /// comparing a map with a version a few edits away from it takes
+  /// O(log n) per edit, not O(n).
+  pub fn diff_iter(&self, new: &TreeMap<K, V>) -> Diff<K, V> {
+    Diff{stack: ~[Compare(self.root.clone(), new.root.clone())]}
+  }
+}
+
+impl<K, V> TreeMap<K, V> {
+  /// Iterate over the (key, value) pairs in key order.
+  pub fn iter<'t>(&'t self) -> Entries<'t, K, V> {
+    let mut entries = Entries{stack: ~[], remaining: size(&self.root)};
+    entries.push_left_spine(&self.root);
+    entries
+  }
+  /// Use this to pattern match on the root of the map's tree:
+  /// view::Empty, or view::Branch with the root's entry and its
+  /// two subtrees.
+  pub fn view<'t>(&'t self) -> view::TreeMapView<'t, K, V> {
+    match self.root {
+      None => view::Empty,
+      Some(ref node) => {
+        let n = node.borrow();
+        view::Branch(&n.key, &n.value, TreeMap{root: n.left.clone()}, TreeMap{root: n.right.clone()})
+      }
+    }
+  }
+  /// How many of this map's nodes (there is one per entry) are also
+  /// nodes of other: the memory the two actually share, found by
+  /// pointer identity.
+  pub fn shared_node_count_with(&self, other: &TreeMap<K, V>) -> uint {
+    let mut theirs = HashSet::new();
+    collect_addresses(&other.root, &mut theirs);
+    count_nodes_in(&self.root, &theirs)
+  }
+  /// Approximate bytes of heap memory held by the map's nodes.
+  /// Memory that the keys and values themselves own isn't included.
+  pub fn heap_size(&self) -> uint {
+    // An Rc allocation is the node plus its reference count.
+    size(&self.root) * (mem::size_of::<Node<K, V>>() + mem::size_of::<uint>())
+  }
+}
+
+fn collect_addresses<K, V>(t: &Tree<K, V>, addresses: &mut HashSet<uint>) {
+  match *t {
+    None => (),
+    Some(ref node) => {
+      let n = node.borrow();
+      addresses.insert(ptr::to_unsafe_ptr(n) as uint);
+      collect_addresses(&n.left, addresses);
+      collect_addresses(&n.right, addresses);
+    }
+  }
+}
+
+// Nodes of the tree that are in addresses.  A node that is in it
+// brings its whole subtree with it, which the cached size counts.
+fn count_nodes_in<K, V>(t: &Tree<K, V>, addresses: &HashSet<uint>) -> uint {
+  match *t {
+    None => 0,
+    Some(ref node) => {
+      let n = node.borrow();
+      if addresses.contains(&(ptr::to_unsafe_ptr(n) as uint)) {
+        n.size
+      } else {
+        count_nodes_in(&n.left, addresses) + count_nodes_in(&n.right, addresses)
+      }
+    }
+  }
+}
+
+impl<K, V> Container for TreeMap<K, V> {
+  fn len(&self) -> uint {
+    size(&self.root)
+  }
+}
+
+impl<K: TotalOrd, V> Map<K, V> for TreeMap<K, V> {
+  fn contains_key(&self, key: &K) -> bool {
+    self.find(key).is_some()
+  }
+  fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+    let mut t = &self.root;
+    loop {
+      match *t {
+        None => return None,
+        Some(ref node) => {
+          let n = node.borrow();
+          match key.cmp(&n.key) {
+            Less => t = &n.left,
+            Greater => t = &n.right,
+            Equal => return Some(&n.value)
+          }
+        }
+      }
+    }
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> PersistentMap<K, V> for TreeMap<K, V> {
+  fn empty() -> TreeMap<K, V> {
+    TreeMap::new()
+  }
+  fn insert(&self, k: K, v: V) -> TreeMap<K, V> {
+    self.insert(k, v)
+  }
+  fn remove(&self, k: &K) -> TreeMap<K, V> {
+    self.remove(k)
+  }
+}
+
+// Comparisons go by the entries in key order, like comparing lists of
+// (key, value) pairs: keys first, then values.  Two maps that are the
+// same tree (a clone, say) compare equal without looking at it.
+impl<K: TotalOrd, V: Eq> Eq for TreeMap<K, V> {
+  fn eq(&self, other: &TreeMap<K, V>) -> bool {
+    if same(&self.root, &other.root) {
+      return true;
+    }
+    self.len() == other.len() &&
+      self.iter().zip(other.iter()).all(|((k1, v1), (k2, v2))| k1.cmp(k2) == Equal && *v1 == *v2)
+  }
+}
+
+impl<K: TotalOrd, V: Ord> Ord for TreeMap<K, V> {
+  fn lt(&self, other: &TreeMap<K, V>) -> bool {
+    if same(&self.root, &other.root) {
+      return false;
+    }
+    let (mut a, mut b) = (self.iter(), other.iter());
+    loop {
+      match (a.next(), b.next()) {
+        (_, None) => return false,
+        (None, _) => return true,
+        (Some((k1, v1)), Some((k2, v2))) => {
+          match k1.cmp(k2) {
+            Less => return true,
+            Greater => return false,
+            Equal => {
+              if *v1 < *v2 {
+                return true;
+              }
+              if *v2 < *v1 {
+                return false;
+              }
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+impl<K: TotalOrd, V: TotalEq> TotalEq for TreeMap<K, V> {
+  fn equals(&self, other: &TreeMap<K, V>) -> bool {
+    if same(&self.root, &other.root) {
+      return true;
+    }
+    self.len() == other.len() &&
+      self.iter().zip(other.iter()).all(|((k1, v1), (k2, v2))| k1.cmp(k2) == Equal && v1.equals(v2))
+  }
+}
+
+impl<K: TotalOrd, V: TotalOrd> TotalOrd for TreeMap<K, V> {
+  fn cmp(&self, other: &TreeMap<K, V>) -> Ordering {
+    if same(&self.root, &other.root) {
+      return Equal;
+    }
+    let (mut a, mut b) = (self.iter(), other.iter());
+    loop {
+      match (a.next(), b.next()) {
+        (None, None) => return Equal,
+        (None, _) => return Less,
+        (_, None) => return Greater,
+        (Some((k1, v1)), Some((k2, v2))) => {
+          match k1.cmp(k2) {
+            Equal => match v1.cmp(v2) {
+              Equal => (),
+              unequal => return unequal
+            },
+            unequal => return unequal
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Maps print as `{1: a, 2: b}`, in key order.
+impl<K: fmt::Default, V: fmt::Default> fmt::Default for TreeMap<K, V> {
+  fn fmt(map: &TreeMap<K, V>, f: &mut fmt::Formatter) {
+    write!(f.buf, "{}", '{');
+    for (i, (k, v)) in map.iter().enumerate() {
+      if i > 0 { write!(f.buf, ", "); }
+      write!(f.buf, "{}: {}", *k, *v);
+    }
+    write!(f.buf, "{}", '}');
+  }
+}
+
+/// Parses the format that maps print in, e.g. `{1: a, 2: b}`.
+/// Keys and values may be bracketed themselves (`{1: [2, 3]}`), but
+/// ones whose own text has a comma or colon outside of brackets can't
+/// be parsed.  Of entries with the same key, the last is kept.
+impl<K: FromStr+TotalOrd+Clone+Freeze, V: FromStr+Clone+Freeze> FromStr for TreeMap<K, V> {
+  fn from_str(s: &str) -> Option<TreeMap<K, V>> {
+    let s = s.trim();
+    if !s.starts_with("{") || !s.ends_with("}") || s.len() < 2 {
+      return None;
+    }
+    let inner = s.slice(1, s.len() - 1);
+    let mut builder = TreeMapBuilder::new();
+    if inner.trim().is_empty() {
+      return Some(builder.finish());
+    }
+    for entry in split_top_level(inner, ',').iter() {
+      let parts = split_top_level(*entry, ':');
+      if parts.len() != 2 {
+        return None;
+      }
+      match (from_str(parts[0].trim()), from_str(parts[1].trim())) {
+        (Some(k), Some(v)) => builder.insert(k, v),
+        _ => return None
+      }
+    }
+    Some(builder.finish())
+  }
+}
+
+impl<K: TotalOrd, V> Default for TreeMap<K, V> {
+  fn default() -> TreeMap<K, V> {
+    TreeMap::new()
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> FromIterator<(K, V)> for TreeMap<K, V> {
+  fn from_iterator<T: Iterator<(K, V)>>(iter: &mut T) -> TreeMap<K, V> {
+    let mut builder = TreeMapBuilder::new();
+    builder.extend(iter);
+    builder.finish()
+  }
+}
+
+/// Collects entries with ordinary mutation, then makes a TreeMap of
+/// them all at once, allocating each node once instead of copying a
+/// path of the tree per insert.
+pub struct TreeMapBuilder<K, V> {
+  priv entries : StdTreeMap<K, V>
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> TreeMapBuilder<K, V> {
+  /// Create a builder with no entries yet.
+  pub fn new() -> TreeMapBuilder<K, V> {
+    TreeMapBuilder{entries: StdTreeMap::new()}
+  }
+  /// Map key to value (replacing what an earlier insert mapped it to).
+  pub fn insert(&mut self, key: K, value: V) {
+    self.entries.insert(key, value);
+  }
+  /// Create the map of everything that was inserted.
+  pub fn finish(self) -> TreeMap<K, V> {
+    TreeMap::from_std(self.entries)
+  }
+}
+
+// A tree of the next n entries, which are in order.  Halving the
+// sizes at each level makes it as balanced as can be.
+fn build_sorted<K: Freeze, V: Freeze, I: Iterator<(K, V)>>(n: uint, entries: &mut I) -> Tree<K, V> {
+  if n == 0 {
+    return None;
+  }
+  let left = build_sorted(n / 2, entries);
+  let (k, v) = entries.next().unwrap();
+  let right = build_sorted(n - n / 2 - 1, entries);
+  make(left, k, v, right)
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> Extendable<(K, V)> for TreeMapBuilder<K, V> {
+  fn extend<T: Iterator<(K, V)>>(&mut self, iter: &mut T) {
+    for (k, v) in *iter {
+      self.insert(k, v);
+    }
+  }
+}
+
+impl<S: Encoder, K: Encodable<S>, V: Encodable<S>> Encodable<S> for TreeMap<K, V> {
+  fn encode(&self, s: &mut S) {
+    encode_versioned(s, "TreeMap", |s| {
+      s.emit_map(self.len(), |s| {
+        for (i, (k, v)) in self.iter().enumerate() {
+          s.emit_map_elt_key(i, |s| k.encode(s));
+          s.emit_map_elt_val(i, |s| v.encode(s));
+        }
+      })
+    })
+  }
+}
+
+impl<D: Decoder, K: Decodable<D>+TotalOrd+Clone+Freeze, V: Decodable<D>+Clone+Freeze>
+    Decodable<D> for TreeMap<K, V> {
+  fn decode(d: &mut D) -> TreeMap<K, V> {
+    decode_versioned(d, "TreeMap", |d, version| match version {
+      // Version 1: the entries, in key order.
+      1 => d.read_map(|d, len| {
+        let mut builder = TreeMapBuilder::new();
+        for i in range(0, len) {
+          let k = d.read_map_elt_key(i, |d| Decodable::decode(d));
+          let v = d.read_map_elt_val(i, |d| Decodable::decode(d));
+          builder.insert(k, v);
+        }
+        builder.finish()
+      }),
+      _ => unknown_version("TreeMap", version)
+    })
+  }
+}
+
+/// Iterator over a TreeMap's (key, value) pairs, in key order.
+pub struct Entries<'self, K, V> {
+  // Nodes whose entries (and right subtrees) are still to come,
+  // the next one last.
+  priv stack : ~[&'self Node<K, V>],
+  // How many entries to yield before stopping (fewer than the stack
+  // holds, for the earlier half of a split).
+  priv remaining : uint
+}
+
+impl<'self, K, V> Entries<'self, K, V> {
+  fn new(stack: ~[&'self Node<K, V>]) -> Entries<'self, K, V> {
+    let remaining = stack.iter().fold(0, |n, node| n + 1 + size(&node.right));
+    Entries{stack: stack, remaining: remaining}
+  }
+  /// Divide the entries still to come into two iterators, the first
+  /// yielding the earlier half and the second the later half, so
+  /// they can be walked separately (by a fork/join traversal, say).
+  /// This uses the cached subtree sizes to skip the first half
+  /// without visiting it: O((log n)^2) rather than O(n).
+  pub fn split(self) -> (Entries<'self, K, V>, Entries<'self, K, V>) {
+    let half = self.remaining / 2;
+    let first = Entries{stack: self.stack.clone(), remaining: half};
+    let mut second = self;
+    second.skip(half);
+    (first, second)
+  }
+  // Advance past the next n entries (n must be at most remaining).
+  fn skip(&mut self, n: uint) {
+    let mut n = n;
+    self.remaining -= n;
+    while n > 0 {
+      let depth = self.stack.len();
+      let node = self.stack[depth - 1];
+      self.stack.truncate(depth - 1);
+      let with_right = 1 + size(&node.right);
+      if n >= with_right {
+        n -= with_right;
+      } else {
+        n -= 1;
+        self.push_left_spine(&node.right);
+      }
+    }
+  }
+  fn push_left_spine(&mut self, t: &'self Tree<K, V>) {
+    let mut t = t;
+    loop {
+      match *t {
+        None => break,
+        Some(ref node) => {
+          let n = node.borrow();
+          self.stack.push(n);
+          t = &n.left;
+        }
+      }
+    }
+  }
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self V)> for Entries<'self, K, V> {
+  fn next(&mut self) -> Option<(&'self K, &'self V)> {
+    if self.remaining == 0 {
+      return None;
+    }
+    let depth = self.stack.len();
+    let n = self.stack[depth - 1];
+    self.stack.truncate(depth - 1);
+    self.push_left_spine(&n.right);
+    self.remaining -= 1;
+    Some((&n.key, &n.value))
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    (self.remaining, Some(self.remaining))
+  }
+}
+
+/// Iterator over the changes from one TreeMap to another, in key order.
+pub struct Diff<K, V> {
+  // Work still to do, the next last.
+  priv stack : ~[DiffStep<K, V>]
+}
+
+enum DiffStep<K, V> {
+  // Find the changes from the first tree to the second.
+  Compare(Tree<K, V>, Tree<K, V>),
+  Emit(Change<K, V>)
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Eq+Clone+Freeze> Diff<K, V> {
+  // Like union, this splits the new tree by the old one's root, so
+  // that subtrees of the old one that come through unchanged line up
+  // with (and are skipped as the same as) the new one's.
+  fn expand(&mut self, old: &Tree<K, V>, new: &Tree<K, V>) {
+    if same(old, new) {
+      return;
+    }
+    match (old, new) {
+      (&Some(ref node), _) => {
+        let n = node.borrow();
+        let (left, found, right) = split(new, &n.key);
+        self.stack.push(Compare(n.right.clone(), right));
+        match found {
+          None => self.stack.push(Emit(Removed(n.key.clone(), n.value.clone()))),
+          Some(v) => if v != n.value {
+            self.stack.push(Emit(Updated(n.key.clone(), n.value.clone(), v)));
+          }
+        }
+        self.stack.push(Compare(n.left.clone(), left));
+      }
+      (&None, &Some(ref node)) => {
+        let n = node.borrow();
+        self.stack.push(Compare(None, n.right.clone()));
+        self.stack.push(Emit(Added(n.key.clone(), n.value.clone())));
+        self.stack.push(Compare(None, n.left.clone()));
+      }
+      (&None, &None) => ()
+    }
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Eq+Clone+Freeze> Iterator<Change<K, V>> for Diff<K, V> {
+  fn next(&mut self) -> Option<Change<K, V>> {
+    while !self.stack.is_empty() {
+      let n = self.stack.len();
+      match self.stack.swap_remove(n - 1) {
+        Emit(change) => return Some(change),
+        Compare(old, new) => self.expand(&old, &new)
+      }
+    }
+    None
+  }
+}
+
+/// A look at the root of a TreeMap's tree, for code that follows the
+/// tree's shape (like recursion::tree_cata).  The subtrees are maps
+/// of their own, sharing the original's nodes.
+pub mod view {
+use super::TreeMap;
+
+pub enum TreeMapView<'self, K, V> {
+  Empty,
+  Branch(&'self K, &'self V, TreeMap<K, V>, TreeMap<K, V>)
+}
+}
+
+#[cfg(test)]
+mod test {
+use super::{TreeMap, Tree};
+use std::cmp::max;
+use std::rand::{Rng, task_rng};
+
+// Checks ordering, AVL balance and cached sizes; returns the height.
+fn check<K: TotalOrd, V>(t: &Tree<K, V>, lo: Option<&K>, hi: Option<&K>) -> uint {
+  match *t {
+    None => 0,
+    Some(ref node) => {
+      let n = node.borrow();
+      match lo { Some(lo) => assert!(lo.cmp(&n.key) == Less), None => () }
+      match hi { Some(hi) => assert!(n.key.cmp(hi) == Less), None => () }
+      let hl = check(&n.left, lo, Some(&n.key));
+      let hr = check(&n.right, Some(&n.key), hi);
+      assert!(hl <= hr + 1 && hr <= hl + 1);
+      assert!(n.height == max(hl, hr) + 1);
+      assert!(n.size == super::size(&n.left) + super::size(&n.right) + 1);
+      n.height
+    }
+  }
+}
+
+#[test]
+fn test_update() {
+  use persistent::traits::{PersistentMap, update_in};
+  let m : TreeMap<~str, int> = TreeMap::new().insert(~"a", 1);
+  let m2 = m.update(~"a", |old| *old.unwrap() + 1).update(~"b", |old| old.map_default(0, |x| *x) + 5);
+  assert!(m2.find(&~"a") == Some(&2) && m2.find(&~"b") == Some(&5) && m.find(&~"a") == Some(&1));
+  let nested : TreeMap<int, TreeMap<int, int>> = TreeMap::new();
+  let n1 = update_in(&nested, 1, 10, |old| { assert!(old.is_none()); 100 });
+  let n2 = update_in(&n1, 1, 10, |old| *old.unwrap() + 1);
+  let n3 = update_in(&n2, 1, 20, |_| 7);
+  assert!(n3.find(&1).unwrap().find(&10) == Some(&101));
+  assert!(n3.find(&1).unwrap().find(&20) == Some(&7));
+  assert!(n1.find(&1).unwrap().find(&10) == Some(&100) && nested.is_empty());
+}
+#[test]
+fn test_insert_find_remove() {
+  let m0 : TreeMap<int, ~str> = TreeMap::new();
+  let m1 = m0.insert(2, ~"two");
+  let m2 = m1.insert(1, ~"one");
+  let m2b = m2.insert(2, ~"deux");
+  assert!(m0.is_empty());
+  assert!(m2.len() == 2);
+  assert!(m2b.len() == 2);
+  assert!(m2.find(&2) == Some(&~"two"));
+  assert!(m2b.find(&2) == Some(&~"deux"));
+  assert!(m1.find(&1) == None);
+  let keys : ~[int] = m2.iter().map(|(k, _)| *k).collect();
+  assert!(keys == ~[1, 2]);
+  assert!(m2.remove(&2) == m0.insert(1, ~"one"));
+  assert!(m2.remove(&3) == m2);
+  assert!(m2.len() == 2);
+}
+
+#[test]
+fn test_random() {
+  let mut rng = task_rng();
+  let mut m = TreeMap::new();
+  let mut model = ~[false, ..200];
+  for _ in range(0, 2000) {
+    let k = rng.gen_range(0u, 200);
+    if rng.gen() {
+      m = m.insert(k, k * 2);
+      model[k] = true;
+    } else {
+      m = m.remove(&k);
+      model[k] = false;
+    }
+    check(&m.root, None, None);
+  }
+  let expected : ~[uint] = range(0u, 200).filter(|k| model[*k]).collect();
+  let keys : ~[uint] = m.iter().map(|(k, v)| { assert!(*v == *k * 2); *k }).collect();
+  assert!(keys == expected);
+  assert!(m.len() == expected.len());
+}
+
+#[test]
+fn test_builder() {
+  use super::TreeMapBuilder;
+  let mut rng = task_rng();
+  let mut builder = TreeMapBuilder::new();
+  let mut m = TreeMap::new();
+  for _ in range(0, 1000) {
+    let k = rng.gen_range(0u, 300);
+    let v = rng.gen_range(0u, 10);
+    builder.insert(k, v);
+    m = m.insert(k, v);
+  }
+  let built = builder.finish();
+  check(&built.root, None, None);
+  assert!(built == m);
+  let collected : TreeMap<int, int> = ~[(2, 20), (1, 10), (2, 21)].move_iter().collect();
+  assert!(collected.find(&2) == Some(&21) && collected.len() == 2);
+  let empty : TreeMap<int, int> = TreeMapBuilder::new().finish();
+  assert!(empty.is_empty());
+}
+
+#[test]
+fn test_ord() {
+  let m : TreeMap<int, int> = range(0, 100).map(|i| (i, i)).collect();
+  assert!(m == m.clone() && m.equals(&m.clone()) && m.cmp(&m.clone()) == Equal);
+  // Keys decide first, then values, then length.
+  assert!(m.remove(&50) > m && m.remove(&50).cmp(&m) == Greater);
+  assert!(m.insert(50, 0) < m && m.insert(50, 0).cmp(&m) == Less);
+  assert!(m.remove(&99) < m && !(m < m.remove(&99)));
+  let empty : TreeMap<int, int> = TreeMap::new();
+  assert!(empty < m && empty.cmp(&m) == Less && empty.equals(&TreeMap::new()));
+  // Equal, though built differently and so sharing no nodes.
+  let rebuilt : TreeMap<int, int> = range(0, 100).invert().map(|i| (i, i)).collect();
+  assert!(rebuilt.equals(&m) && !(rebuilt < m) && !(m < rebuilt));
+}
+
+#[test]
+fn test_text_format() {
+  use std::from_str::from_str;
+  use persistent::list::List;
+  let m : TreeMap<int, List<int>> = ~[(2, List::nil()), (1, List::from_vec(~[1, 2]))].move_iter().collect();
+  let text = format!("{}", m);
+  assert!(text == ~"{1: [1, 2], 2: []}");
+  assert!(from_str::<TreeMap<int, List<int>>>(text) == Some(m.clone()));
+  let nested = TreeMap::new().insert(List::from_vec(~[3]), m.clone());
+  let text = format!("{}", nested);
+  assert!(text == ~"{[3]: {1: [1, 2], 2: []}}");
+  assert!(from_str::<TreeMap<List<int>, TreeMap<int, List<int>>>>(text) == Some(nested));
+  let empty : TreeMap<int, int> = TreeMap::new();
+  assert!(format!("{}", empty) == ~"{}" && from_str::<TreeMap<int, int>>(" { } ") == Some(empty));
+  let parsed : Option<TreeMap<int, int>> = from_str("{3 : 4,1:2, 3: 5}");
+  assert!(parsed == Some(TreeMap::new().insert(1, 2).insert(3, 5)));
+  assert!(from_str::<TreeMap<int, int>>("{1: 2, 3}").is_none());
+  assert!(from_str::<TreeMap<int, int>>("{1: 2: 3}").is_none());
+  assert!(from_str::<TreeMap<int, int>>("{1: x}").is_none());
+  assert!(from_str::<TreeMap<int, int>>("[1: 2]").is_none());
+}
+
+#[test]
+fn test_diff_iter() {
+  use persistent::traits::{Change, Added, Removed, Updated};
+  let m : TreeMap<int, int> = range(0, 100).map(|i| (i, i)).collect();
+  let edited = m.remove(&10).insert(20, -20).insert(200, 200).insert(-1, -1).remove(&500);
+  let changes : ~[Change<int, int>] = m.diff_iter(&edited).collect();
+  assert!(changes == ~[Added(-1, -1), Removed(10, 10), Updated(20, 20, -20), Added(200, 200)]);
+  let back : ~[Change<int, int>] = edited.diff_iter(&m).collect();
+  assert!(back == ~[Removed(-1, -1), Added(10, 10), Updated(20, -20, 20), Removed(200, 200)]);
+  assert!(m.diff_iter(&m.clone()).next().is_none() && m.diff_iter(&m.insert(5, 5)).next().is_none());
+  // Equal maps that share no nodes have no changes either.
+  let rebuilt : TreeMap<int, int> = range(0, 100).invert().map(|i| (i, i)).collect();
+  assert!(m.diff_iter(&rebuilt).next().is_none());
+  let empty = TreeMap::new();
+  let added : ~[int] = empty.diff_iter(&m).map(|c| match c { Added(k, _) => k, _ => fail!() }).collect();
+  let expected : ~[int] = range(0, 100).collect();
+  assert!(added == expected);
+  assert!(m.diff_iter(&empty).all(|c| match c { Removed(_, _) => true, _ => false }));
+}
+
+// Keys that count how many times they're compared.
+static mut COMPARISONS : uint = 0;
+
+#[deriving(Clone, Eq)]
+struct Counted(uint);
+
+impl TotalEq for Counted {
+  fn equals(&self, other: &Counted) -> bool { self.cmp(other) == Equal }
+}
+
+impl TotalOrd for Counted {
+  fn cmp(&self, other: &Counted) -> Ordering {
+    unsafe { COMPARISONS += 1; }
+    let (Counted(a), Counted(b)) = (*self, *other);
+    a.cmp(&b)
+  }
+}
+
+#[test]
+fn test_diff_iter_skips_shared() {
+  let m : TreeMap<Counted, uint> = range(0u, 10000).map(|i| (Counted(i * 2), i)).collect();
+  let edits = ~[m.insert(Counted(5001), 0), m.insert(Counted(5000), 0), m.remove(&Counted(5000)),
+                m.insert(Counted(0), 1), m.remove(&Counted(19998))];
+  for edited in edits.iter() {
+    unsafe { COMPARISONS = 0; }
+    assert!(m.diff_iter(edited).count() == 1);
+    // Visiting every node would take at least 10000 comparisons;
+    // following the edited path takes a few per level.
+    assert!(unsafe { COMPARISONS } < 100);
+  }
+}
+
+#[test]
+fn test_split_iter() {
+  let mut m = TreeMap::new();
+  for i in range(0u, 500) {
+    m = m.insert(i * 7 % 500, i);
+  }
+  let (first, second) = m.iter().split();
+  assert!(first.size_hint() == (250, Some(250)) && second.size_hint() == (250, Some(250)));
+  let (a, b) = second.split();
+  let mut keys : ~[uint] = first.map(|(k, _)| *k).collect();
+  keys.extend(&mut a.map(|(k, _)| *k));
+  keys.extend(&mut b.map(|(k, _)| *k));
+  let expected : ~[uint] = range(0u, 500).collect();
+  assert!(keys == expected);
+  let mut from_200 = m.lower_bound(&200);
+  assert!(from_200.size_hint() == (300, Some(300)));
+  from_200.next();
+  let (mut c, mut d) = from_200.split();
+  assert!(c.next() == Some((&201, m.find(&201).unwrap())));
+  assert!(d.next().map(|(k, _)| *k) == Some(350));
+}
+
+#[test]
+fn test_shared_node_count() {
+  let mut m = TreeMap::new();
+  for i in range(0u, 100) {
+    m = m.insert(i, i);
+  }
+  assert!(m.shared_node_count_with(&m.clone()) == 100);
+  let changed = m.insert(50, 0);
+  let shared = changed.shared_node_count_with(&m);
+  // Only the path down to 50 was copied.
+  assert!(shared < 100 && shared >= 100 - check(&m.root, None, None));
+  let separate : TreeMap<uint, uint> = range(0u, 100).map(|i| (i, i)).collect();
+  assert!(separate.shared_node_count_with(&m) == 0);
+  assert!(m.heap_size() == 100 * TreeMap::<uint, uint>::new().insert(0, 0).heap_size());
+}
+
+#[test]
+fn test_pmap_macro() {
+  let m = pmap!{1 => ~"one", 2 => ~"two", 1 => ~"uno"};
+  assert!(m.len() == 2 && m.find(&1) == Some(&~"uno"));
+  let empty : TreeMap<int, int> = pmap!{};
+  assert!(empty.is_empty());
+}
+
+#[test]
+fn test_std_conversions() {
+  use StdTreeMap = extra::treemap::TreeMap;
+  let mut std_map = StdTreeMap::new();
+  for i in range(0u, 100) {
+    std_map.insert(i, i * 2);
+  }
+  let m = TreeMap::from_std(std_map.clone());
+  check(&m.root, None, None);
+  assert!(m.len() == 100 && m.find(&40) == Some(&80));
+  assert!(m.to_std() == std_map);
+}
+
+#[test]
+fn test_serialize() {
+  use persistent::json_round_trip;
+  let m : TreeMap<~str, int> = TreeMap::new().insert(~"b", 2).insert(~"a", 1);
+  let (text, decoded) = json_round_trip(&m);
+  assert!(text == ~"{\"version\":1,\"members\":{\"a\":1,\"b\":2}}");
+  assert!(decoded == m);
+}
+
+#[test]
+fn test_serialize_stable() {
+  use persistent::json_round_trip;
+  // Inserted out of order, so the tree's shape differs from the one
+  // decoding builds; the text depends only on the entries.
+  let mut m = TreeMap::new();
+  for i in range(0u, 200) {
+    m = m.insert((i * 37 % 200).to_str(), i);
+  }
+  let (text, decoded) = json_round_trip(&m);
+  check(&decoded.root, None, None);
+  let (again, _) = json_round_trip(&decoded);
+  assert!(again == text);
+}
+
+#[test]
+fn test_lower_bound() {
+  let m : TreeMap<int, ()> = range(0, 10).map(|i| (i * 10, ())).collect();
+  let from_35 : ~[int] = m.lower_bound(&35).map(|(k, _)| *k).collect();
+  assert!(from_35 == ~[40, 50, 60, 70, 80, 90]);
+  let from_40 : ~[int] = m.lower_bound(&40).map(|(k, _)| *k).collect();
+  assert!(from_40 == ~[40, 50, 60, 70, 80, 90]);
+  assert!(m.lower_bound(&91).next().is_none());
+  assert!(m.lower_bound(&-5).count() == 10);
+}
+
+#[test]
+fn test_set_operations() {
+  let evens : TreeMap<int, char> = range(0, 50).map(|i| (i * 2, 'e')).collect();
+  let threes : TreeMap<int, char> = range(0, 34).map(|i| (i * 3, 't')).collect();
+  let union = evens.union(&threes);
+  let keys : ~[int] = union.iter().map(|(k, _)| *k).collect();
+  let expected : ~[int] = range(0, 100).filter(|i| *i % 2 == 0 || *i % 3 == 0).collect();
+  assert!(keys == expected);
+  assert!(union.find(&6) == Some(&'e'));
+  assert!(union.find(&9) == Some(&'t'));
+  check(&union.root, None, None);
+  let both = evens.intersection(&threes);
+  let keys : ~[int] = both.iter().map(|(k, _)| *k).collect();
+  let expected : ~[int] = range(0, 17).map(|i| i * 6).collect();
+  assert!(keys == expected);
+  check(&both.root, None, None);
+  let only_evens = evens.difference(&threes);
+  let keys : ~[int] = only_evens.iter().map(|(k, _)| *k).collect();
+  let expected : ~[int] = range(0, 100).filter(|i| *i % 2 == 0 && *i % 3 != 0).collect();
+  assert!(keys == expected);
+  check(&only_evens.root, None, None);
+  // Versions that share structure:
+  let more = evens.insert(1, 'o');
+  assert!(super::same(&evens.union(&evens).root, &evens.root));
+  assert!(more.union(&evens) == more);
+  assert!(more.difference(&evens) == TreeMap::new().insert(1, 'o'));
+  assert!(more.intersection(&evens) == evens);
+  assert!(evens.difference(&evens).is_empty());
+}
+}
+
+}
+
+pub mod treeset {
+
+use std::fmt;
+use std::from_str::{FromStr, from_str};
+use persistent::treemap::{TreeMap, TreeMapBuilder, Entries};
+use extra::serialize::{Encodable, Decodable, Encoder, Decoder};
+use persistent::{encode_versioned, decode_versioned, unknown_version, split_top_level};
+
+/// Persistent ordered set: a TreeMap whose values are all ().
+/// Union, intersection and difference reuse the subtrees that the
+/// result has in common with the sets.
+pub struct TreeSet<T> {
+  priv map : TreeMap<T, ()>
+}
+
+impl<T> Clone for TreeSet<T> {
+  fn clone(&self) -> TreeSet<T> {
+    TreeSet{map: self.map.clone()}
+  }
+}
+
+impl<T: TotalOrd> TreeSet<T> {
+  /// Create an empty set.
+  pub fn new() -> TreeSet<T> {
+    TreeSet{map: TreeMap::new()}
+  }
+}
+
+impl<T: TotalOrd+Clone+Freeze> TreeSet<T> {
+  /// Create a set with x in it, and everything self has.
+  pub fn insert(&self, x: T) -> TreeSet<T> {
+    TreeSet{map: self.map.insert(x, ())}
+  }
+  /// Create a set without x, and everything else self has.
+  pub fn remove(&self, x: &T) -> TreeSet<T> {
+    TreeSet{map: self.map.remove(x)}
+  }
+  /// Create the set of everything in either set.
+  pub fn union(&self, other: &TreeSet<T>) -> TreeSet<T> {
+    TreeSet{map: self.map.union(&other.map)}
+  }
+  /// Create the set of everything in both sets.
+  pub fn intersection(&self, other: &TreeSet<T>) -> TreeSet<T> {
+    TreeSet{map: self.map.intersection(&other.map)}
+  }
+  /// Create the set of everything in self but not in other.
+  pub fn difference(&self, other: &TreeSet<T>) -> TreeSet<T> {
+    TreeSet{map: self.map.difference(&other.map)}
+  }
+}
+
+impl<T> TreeSet<T> {
+  /// Iterate over the members in order.
+  pub fn iter<'t>(&'t self) -> SetItems<'t, T> {
+    SetItems{entries: self.map.iter()}
+  }
+}
+
+impl<T> Container for TreeSet<T> {
+  fn len(&self) -> uint {
+    self.map.len()
+  }
+}
+
+impl<T: TotalOrd> Set<T> for TreeSet<T> {
+  fn contains(&self, x: &T) -> bool {
+    self.map.contains_key(x)
+  }
+  fn is_disjoint(&self, other: &TreeSet<T>) -> bool {
+    self.iter().all(|x| !other.contains(x))
+  }
+  fn is_subset(&self, other: &TreeSet<T>) -> bool {
+    self.len() <= other.len() && self.iter().all(|x| other.contains(x))
+  }
+  fn is_superset(&self, other: &TreeSet<T>) -> bool {
+    other.is_subset(self)
+  }
+}
+
+// Sets compare like their members in order.
+impl<T: TotalOrd> Eq for TreeSet<T> {
+  fn eq(&self, other: &TreeSet<T>) -> bool {
+    self.map == other.map
+  }
+}
+
+impl<T: TotalOrd> Ord for TreeSet<T> {
+  fn lt(&self, other: &TreeSet<T>) -> bool {
+    self.map < other.map
+  }
+}
+
+impl<T: TotalOrd> TotalEq for TreeSet<T> {
+  fn equals(&self, other: &TreeSet<T>) -> bool {
+    self.map.equals(&other.map)
+  }
+}
+
+impl<T: TotalOrd> TotalOrd for TreeSet<T> {
+  fn cmp(&self, other: &TreeSet<T>) -> Ordering {
+    self.map.cmp(&other.map)
+  }
+}
+
+/// Sets print as `{1, 2, 3}`, in order.
+impl<T: fmt::Default> fmt::Default for TreeSet<T> {
+  fn fmt(set: &TreeSet<T>, f: &mut fmt::Formatter) {
+    write!(f.buf, "{}", '{');
+    for (i, x) in set.iter().enumerate() {
+      if i > 0 { write!(f.buf, ", "); }
+      write!(f.buf, "{}", *x);
+    }
+    write!(f.buf, "{}", '}');
+  }
+}
+
+/// Parses the format that sets print in, e.g. `{1, 2, 3}`, with the
+/// same limits as a TreeMap's.
+impl<T: FromStr+TotalOrd+Clone+Freeze> FromStr for TreeSet<T> {
+  fn from_str(s: &str) -> Option<TreeSet<T>> {
+    let s = s.trim();
+    if !s.starts_with("{") || !s.ends_with("}") || s.len() < 2 {
+      return None;
+    }
+    let inner = s.slice(1, s.len() - 1);
+    let mut builder = TreeMapBuilder::new();
+    if !inner.trim().is_empty() {
+      for piece in split_top_level(inner, ',').iter() {
+        match from_str(piece.trim()) {
+          Some(x) => builder.insert(x, ()),
+          None => return None
+        }
+      }
+    }
+    Some(TreeSet{map: builder.finish()})
+  }
+}
+
+impl<T: TotalOrd> Default for TreeSet<T> {
+  fn default() -> TreeSet<T> {
+    TreeSet::new()
+  }
+}
+
+impl<A: TotalOrd+Clone+Freeze> FromIterator<A> for TreeSet<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> TreeSet<A> {
+    let mut set = TreeSet::new();
+    for x in *iter {
+      set = set.insert(x);
+    }
+    set
+  }
+}
+
+impl<S: Encoder, T: Encodable<S>> Encodable<S> for TreeSet<T> {
+  fn encode(&self, s: &mut S) {
+    encode_versioned(s, "TreeSet", |s| {
+      s.emit_seq(self.len(), |s| {
+        for (i, x) in self.iter().enumerate() {
+          s.emit_seq_elt(i, |s| x.encode(s));
+        }
+      })
+    })
+  }
+}
+
+impl<D: Decoder, T: Decodable<D>+TotalOrd+Clone+Freeze> Decodable<D> for TreeSet<T> {
+  fn decode(d: &mut D) -> TreeSet<T> {
+    decode_versioned(d, "TreeSet", |d, version| match version {
+      // Version 1: the members, in order.
+      1 => d.read_seq(|d, len| {
+        let mut builder = TreeMapBuilder::new();
+        for i in range(0, len) {
+          builder.insert(d.read_seq_elt(i, |d| Decodable::decode(d)), ());
+        }
+        TreeSet{map: builder.finish()}
+      }),
+      _ => unknown_version("TreeSet", version)
+    })
+  }
+}
+
+/// Iterator over a TreeSet's members, in order.
+pub struct SetItems<'self, T> {
+  priv entries : Entries<'self, T, ()>
+}
+
+impl<'self, T> SetItems<'self, T> {
+  /// Divide the members still to come into two iterators, the first
+  /// yielding the earlier half and the second the later half.
+  pub fn split(self) -> (SetItems<'self, T>, SetItems<'self, T>) {
+    let (first, second) = self.entries.split();
+    (SetItems{entries: first}, SetItems{entries: second})
+  }
+}
+
+impl<'self, T> Iterator<&'self T> for SetItems<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    match self.entries.next() {
+      None => None,
+      Some((x, _)) => Some(x)
+    }
+  }
+  fn size_hint(&self) -> (uint, Option<uint>) {
+    self.entries.size_hint()
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::TreeSet;
+#[test]
+fn test_set() {
+  let s0 : TreeSet<int> = TreeSet::new();
+  let s1 = s0.insert(3).insert(1).insert(2);
+  assert!(s1.len() == 3);
+  assert!(s1.contains(&2));
+  assert!(!s0.contains(&2));
+  assert!(!s1.remove(&2).contains(&2));
+  assert!(s1.contains(&2));
+  let items : ~[int] = s1.iter().map(|x| *x).collect();
+  assert!(items == ~[1, 2, 3]);
+  let s2 : TreeSet<int> = range(2, 6).collect();
+  let union : ~[int] = s1.union(&s2).iter().map(|x| *x).collect();
+  assert!(union == ~[1, 2, 3, 4, 5]);
+  let both : ~[int] = s1.intersection(&s2).iter().map(|x| *x).collect();
+  assert!(both == ~[2, 3]);
+  let only : ~[int] = s1.difference(&s2).iter().map(|x| *x).collect();
+  assert!(only == ~[1]);
+  assert!(s1.is_subset(&s1.union(&s2)));
+  assert!(s1.union(&s2).is_superset(&s2));
+  assert!(!s1.is_disjoint(&s2));
+  assert!(s1.difference(&s2).is_disjoint(&s2));
+}
+#[test]
+fn test_text_format() {
+  use std::from_str::from_str;
+  let s : TreeSet<int> = ~[3, 1, 2].move_iter().collect();
+  let empty : TreeSet<int> = TreeSet::new();
+  assert!(format!("{}", s) == ~"{1, 2, 3}" && format!("{}", empty) == ~"{}");
+  assert!(from_str::<TreeSet<int>>(" {3,1, 2 } ") == Some(s.clone()));
+  assert!(from_str::<TreeSet<int>>("{}") == Some(TreeSet::new()));
+  assert!(from_str::<TreeSet<int>>("{1, x}").is_none() && from_str::<TreeSet<int>>("[1]").is_none());
+}
+#[test]
+fn test_ord() {
+  let a : TreeSet<int> = range(0, 3).collect();
+  let b = a.insert(5);
+  assert!(a < b && !(b < a));
+  assert!(a.remove(&0) > b);
+  assert!(a.cmp(&b) == Less && b.cmp(&a) == Greater);
+  assert!(a.cmp(&a.clone()) == Equal && a.equals(&a.remove(&9)));
+}
+#[test]
+fn test_split_iter() {
+  let s : TreeSet<int> = range(0, 10).collect();
+  let (first, second) = s.iter().split();
+  let low : ~[int] = first.map(|x| *x).collect();
+  let high : ~[int] = second.map(|x| *x).collect();
+  assert!(low == ~[0, 1, 2, 3, 4] && high == ~[5, 6, 7, 8, 9]);
+}
+#[test]
+fn test_serialize() {
+  use persistent::json_round_trip;
+  let original : TreeSet<int> = range(0, 50).map(|i| i * 3).collect();
+  let (_, decoded) = json_round_trip(&original);
+  assert!(decoded == original);
+}
+}
+
+}
+
+pub mod queue {
+
+use persistent::list::{List, ListIterator, Nil, Cons};
+
+/// Persistent FIFO queue (Okasaki's two-list queue): members are
+/// pushed onto the front of one list, and popped off the front of
+/// another, which is refilled by reversing the first when it runs out.
+/// push_back and pop_front are O(1) amortized, as long as you don't keep
+/// popping the same old version over and over (each of those pops
+/// might repeat the same O(n) reversal).
+#[deriving(Clone)]
+pub struct Queue<T> {
+  // Never empty unless the whole queue is.
+  priv front : List<T>,
+  // In reverse order: the most recently pushed member is first.
+  priv back : List<T>,
+  priv len : uint
+}
+
+impl<T: Clone+Freeze> Queue<T> {
+  /// Create an empty queue.
+  pub fn new() -> Queue<T> {
+    Queue{front: List::nil(), back: List::nil(), len: 0}
+  }
+  /// Create a queue with x added at the back.
+  pub fn push_back(&self, x: T) -> Queue<T> {
+    Queue::make(self.front.clone(), List::cons(x, self.back.clone()), self.len + 1)
+  }
+  /// The front member, and the queue of the members after it;
+  /// None if the queue is empty.
+  pub fn pop_front<'t>(&'t self) -> Option<(&'t T, Queue<T>)> {
+    match *self.front.node() {
+      Nil => None,
+      Cons(ref x, ref xs) => Some((x, Queue::make(xs.clone(), self.back.clone(), self.len - 1)))
+    }
+  }
+  fn make(front: List<T>, back: List<T>, len: uint) -> Queue<T> {
+    if front.is_empty() {
+      Queue{front: back.reverse(), back: List::nil(), len: len}
+    } else {
+      Queue{front: front, back: back, len: len}
+    }
+  }
+}
+
+impl<T> Queue<T> {
+  /// The member at the front, if the queue isn't empty.
+  pub fn front<'t>(&'t self) -> Option<&'t T> {
+    match *self.front.node() {
+      Nil => None,
+      Cons(ref x, _) => Some(x)
+    }
+  }
+  /// Iterate over the members from front to back.
+  pub fn iter<'t>(&'t self) -> QueueIterator<'t, T> {
+    QueueIterator{front: self.front.iter(), back: self.back.iter().collect()}
+  }
+}
+
+impl<T> Container for Queue<T> {
+  fn len(&self) -> uint {
+    self.len
+  }
+}
+
+impl<T: Eq> Eq for Queue<T> {
+  fn eq(&self, other: &Queue<T>) -> bool {
+    self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| *a == *b)
+  }
+}
+
+impl<T: Clone+Freeze> Default for Queue<T> {
+  fn default() -> Queue<T> {
+    Queue::new()
+  }
+}
+
+impl<A: Clone+Freeze> FromIterator<A> for Queue<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> Queue<A> {
+    let front : List<A> = iter.collect();
+    let len = front.len();
+    Queue{front: front, back: List::nil(), len: len}
+  }
+}
+
+/// Iterator over a Queue's members, from front to back.
+pub struct QueueIterator<'self, T> {
+  priv front : ListIterator<'self, T>,
+  // The back list's members, which come out last first.
+  priv back : ~[&'self T]
+}
+
+impl<'self, T> Iterator<&'self T> for QueueIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    match self.front.next() {
+      Some(x) => Some(x),
+      None => {
+        let n = self.back.len();
+        if n == 0 {
+          None
+        } else {
+          let x = self.back[n - 1];
+          self.back.truncate(n - 1);
+          Some(x)
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::Queue;
+#[test]
+fn test_queue() {
+  let q0 : Queue<int> = Queue::new();
+  let q1 = q0.push_back(1);
+  let q3 = q1.push_back(2).push_back(3);
+  assert!(q0.pop_front().is_none());
+  assert!(q3.len() == 3);
+  assert!(q3.front() == Some(&1));
+  let (x, q2) = q3.pop_front().unwrap();
+  assert!(*x == 1);
+  assert!(q2.front() == Some(&2));
+  let q4 = q2.push_back(4);
+  let items : ~[int] = q4.iter().map(|x| *x).collect();
+  assert!(items == ~[2, 3, 4]);
+  // Old versions are unchanged:
+  let items : ~[int] = q3.iter().map(|x| *x).collect();
+  assert!(items == ~[1, 2, 3]);
+  assert!(q1.len() == 1);
+  let mut q = q4;
+  let mut popped = ~[];
+  loop {
+    let next = match q.pop_front() {
+      None => break,
+      Some((x, rest)) => { popped.push(*x); rest }
+    };
+    q = next;
+  }
+  assert!(popped == ~[2, 3, 4]);
+  assert!(q.is_empty());
+  let collected : Queue<int> = range(2, 5).collect();
+  assert!(collected == q2.push_back(4));
+}
+}
+
+}
+
+pub mod heap {
+
+use std::rc::Rc;
+
+/// Persistent priority queue, a leftist heap: find_min is O(1), and
+/// insert, delete_min and merge are O(log n), making new heaps that
+/// share all but one path of nodes with the originals.
+pub struct Heap<T> {
+  priv root : Tree<T>,
+  priv len : uint
+}
+
+type Tree<T> = Option<Rc<Node<T>>>;
+
+struct Node<T> {
+  value : T,
+  // Length of the path down the rightmost children; never
+  // more on the right than on the left.
+  rank : uint,
+  left : Tree<T>,
+  right : Tree<T>
+}
+
+impl<T> Clone for Heap<T> {
+  fn clone(&self) -> Heap<T> {
+    Heap{root: self.root.clone(), len: self.len}
+  }
+}
+
+fn rank<T>(t: &Tree<T>) -> uint {
+  match *t { None => 0, Some(ref n) => n.borrow().rank }
+}
+
+fn make<T: Freeze>(value: T, a: Tree<T>, b: Tree<T>) -> Tree<T> {
+  if rank(&a) >= rank(&b) {
+    let r = rank(&b) + 1;
+    Some(Rc::new(Node{value: value, rank: r, left: a, right: b}))
+  } else {
+    let r = rank(&a) + 1;
+    Some(Rc::new(Node{value: value, rank: r, left: b, right: a}))
+  }
+}
+
+fn merge<T: TotalOrd+Clone+Freeze>(a: &Tree<T>, b: &Tree<T>) -> Tree<T> {
+  match (a, b) {
+    (&None, _) => b.clone(),
+    (_, &None) => a.clone(),
+    (&Some(ref an), &Some(ref bn)) => {
+      let (x, y) = (an.borrow(), bn.borrow());
+      if x.value.cmp(&y.value) != Greater {
+        make(x.value.clone(), x.left.clone(), merge(&x.right, b))
+      } else {
+        make(y.value.clone(), y.left.clone(), merge(a, &y.right))
+      }
+    }
+  }
+}
+
+impl<T: TotalOrd+Clone+Freeze> Heap<T> {
+  /// Create an empty heap.
+  pub fn new() -> Heap<T> {
+    Heap{root: None, len: 0}
+  }
+  /// Create a heap with x in it, and everything self has.
+  pub fn insert(&self, x: T) -> Heap<T> {
+    let single = Some(Rc::new(Node{value: x, rank: 1, left: None, right: None}));
+    Heap{root: merge(&self.root, &single), len: self.len + 1}
+  }
+  /// Create a heap of everything self has except (one of) its
+  /// smallest member(s).  Deleting from an empty heap gives an empty heap.
+  pub fn delete_min(&self) -> Heap<T> {
+    match self.root {
+      None => self.clone(),
+      Some(ref n) => Heap{root: merge(&n.borrow().left, &n.borrow().right), len: self.len - 1}
+    }
+  }
+  /// Create a heap of everything in either heap.
+  pub fn merge(&self, other: &Heap<T>) -> Heap<T> {
+    Heap{root: merge(&self.root, &other.root), len: self.len + other.len}
+  }
+}
+
+impl<T> Heap<T> {
+  /// The smallest member, if the heap isn't empty.
+  pub fn find_min<'t>(&'t self) -> Option<&'t T> {
+    match self.root {
+      None => None,
+      Some(ref n) => Some(&n.borrow().value)
+    }
+  }
+}
+
+impl<T> Container for Heap<T> {
+  fn len(&self) -> uint {
+    self.len
+  }
+}
+
+impl<T: TotalOrd+Clone+Freeze> Default for Heap<T> {
+  fn default() -> Heap<T> {
+    Heap::new()
+  }
+}
+
+impl<A: TotalOrd+Clone+Freeze> FromIterator<A> for Heap<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> Heap<A> {
+    let mut heap = Heap::new();
+    for x in *iter {
+      heap = heap.insert(x);
+    }
+    heap
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::Heap;
+#[test]
+fn test_heap() {
+  let h0 : Heap<int> = Heap::new();
+  assert!(h0.find_min().is_none());
+  assert!(h0.delete_min().is_empty());
+  let h3 = h0.insert(5).insert(2).insert(8);
+  assert!(h3.len() == 3);
+  assert!(h3.find_min() == Some(&2));
+  let h2 = h3.delete_min();
+  assert!(h2.find_min() == Some(&5));
+  assert!(h3.find_min() == Some(&2));
+  let other : Heap<int> = ~[7, 1, 9].move_iter().collect();
+  let mut merged = h3.merge(&other);
+  assert!(merged.len() == 6);
+  let mut sorted = ~[];
+  while !merged.is_empty() {
+    sorted.push(*merged.find_min().unwrap());
+    merged = merged.delete_min();
+  }
+  assert!(sorted == ~[1, 2, 5, 7, 8, 9]);
+  assert!(other.len() == 3);
+}
+}
+
+}
+
+pub mod catlist {
+
+use std::rc::Rc;
+use persistent::list::{List, ListIterator};
+
+/// Persistent catenable list: append is O(1), because it just makes a
+/// node pointing at both lists; iterating flattens the tree of appends
+/// as it goes.  A List can be made into one in O(1) too.
+pub struct CatList<T> {
+  priv node : Rc<Node<T>>,
+  priv len : uint
+}
+
+enum Node<T> {
+  Empty,
+  Single(T),
+  Whole(List<T>),
+  Append(CatList<T>, CatList<T>)
+}
+
+impl<T> Clone for CatList<T> {
+  fn clone(&self) -> CatList<T> {
+    CatList{node: self.node.clone(), len: self.len}
+  }
+}
+
+impl<T: Freeze> CatList<T> {
+  /// Create an empty list.
+  pub fn new() -> CatList<T> {
+    CatList{node: Rc::new(Empty), len: 0}
+  }
+  /// Create a list of just x.
+  pub fn singleton(x: T) -> CatList<T> {
+    CatList{node: Rc::new(Single(x)), len: 1}
+  }
+  /// Create a list of the List's members, sharing it.
+  pub fn from_list(list: List<T>) -> CatList<T> {
+    let len = list.len();
+    CatList{node: Rc::new(Whole(list)), len: len}
+  }
+  /// Create the list of self's members followed by other's.  O(1).
+  pub fn append(&self, other: &CatList<T>) -> CatList<T> {
+    if other.len == 0 {
+      self.clone()
+    } else if self.len == 0 {
+      other.clone()
+    } else {
+      CatList{node: Rc::new(Append(self.clone(), other.clone())), len: self.len + other.len}
+    }
+  }
+  /// Create a list with x added at the front.
+  pub fn cons(&self, x: T) -> CatList<T> {
+    CatList::singleton(x).append(self)
+  }
+  /// Create a list with x added at the back.
+  pub fn push_back(&self, x: T) -> CatList<T> {
+    self.append(&CatList::singleton(x))
+  }
+}
+
+impl<T> CatList<T> {
+  /// Iterate over the members in order.
+  pub fn iter<'t>(&'t self) -> CatListIterator<'t, T> {
+    CatListIterator{stack: ~[self.node.borrow()], list: None}
+  }
+}
+
+impl<T> Container for CatList<T> {
+  fn len(&self) -> uint {
+    self.len
+  }
+}
+
+impl<T: Eq> Eq for CatList<T> {
+  fn eq(&self, other: &CatList<T>) -> bool {
+    self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| *a == *b)
+  }
+}
+
+impl<T: Freeze> Default for CatList<T> {
+  fn default() -> CatList<T> {
+    CatList::new()
+  }
+}
+
+impl<A: Freeze> FromIterator<A> for CatList<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> CatList<A> {
+    CatList::from_list(iter.collect())
+  }
+}
+
+/// Iterator over a CatList's members, in order.
+pub struct CatListIterator<'self, T> {
+  // Parts still to visit, the next one last.
+  priv stack : ~[&'self Node<T>],
+  // The List being visited, if any.
+  priv list : Option<ListIterator<'self, T>>
+}
+
+impl<'self, T> Iterator<&'self T> for CatListIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    loop {
+      let from_list = match self.list {
+        Some(ref mut rest) => rest.next(),
+        None => None
+      };
+      if from_list.is_some() {
+        return from_list;
+      }
+      self.list = None;
+      let depth = self.stack.len();
+      if depth == 0 {
+        return None;
+      }
+      let node = self.stack[depth - 1];
+      self.stack.truncate(depth - 1);
+      match *node {
+        Empty => (),
+        Single(ref x) => return Some(x),
+        Whole(ref list) => self.list = Some(list.iter()),
+        Append(ref a, ref b) => {
+          self.stack.push(b.node.borrow());
+          self.stack.push(a.node.borrow());
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::CatList;
+use persistent::list::List;
+#[test]
+fn test_catlist() {
+  let c0 : CatList<int> = CatList::new();
+  let a = CatList::from_list(List::from_vec(~[1, 2]));
+  let b = c0.push_back(3).push_back(4).cons(0);
+  let ab = a.append(&b);
+  let items : ~[int] = ab.iter().map(|x| *x).collect();
+  assert!(items == ~[1, 2, 0, 3, 4]);
+  assert!(ab.len() == 5);
+  assert!(a.len() == 2);
+  assert!(ab.append(&c0) == ab);
+  assert!(c0.append(&ab) == ab);
+  assert!(c0.iter().next().is_none());
+  let mut many = CatList::new();
+  for i in range(0, 1000) {
+    many = many.append(&CatList::from_list(List::from_vec(~[i])));
+  }
+  assert!(many.iter().count() == 1000);
+  let collected : CatList<int> = range(0, 1000).collect();
+  assert!(collected == many);
+}
+}
+
+}
+
+pub mod ralist {
+
+use std::rc::Rc;
+use persistent::list::{List, ListBuilder, ListIterator, Nil, Cons};
+
+/// Persistent skew binary random-access list (Okasaki): cons, head and
+/// tail are O(1) like a cons list's, and get and set are O(log n).
+/// It's a list of complete binary trees whose sizes are "skew binary"
+/// digits (2^k - 1, in increasing order, only the first two ever equal).
+pub struct RandomAccessList<T> {
+  // (size, tree) pairs, smallest first; members are in preorder,
+  // one tree after another.
+  priv trees : List<(uint, Rc<Tree<T>>)>,
+  priv len : uint
+}
+
+enum Tree<T> {
+  Leaf(T),
+  Node(T, Rc<Tree<T>>, Rc<Tree<T>>)
+}
+
+// Cloning is just sharing the list of trees.
+impl<T> Clone for RandomAccessList<T> {
+  fn clone(&self) -> RandomAccessList<T> {
+    RandomAccessList{trees: self.trees.clone(), len: self.len}
+  }
+}
+
+fn tree_get<'a, T>(size: uint, index: uint, tree: &'a Rc<Tree<T>>) -> &'a T {
+  let mut size = size;
+  let mut index = index;
+  let mut tree = tree;
+  loop {
+    match *tree.borrow() {
+      Leaf(ref x) => return x,
+      Node(ref x, ref a, ref b) => {
+        if index == 0 {
+          return x;
+        }
+        size /= 2;
+        if index <= size {
+          index -= 1;
+          tree = a;
+        } else {
+          index -= 1 + size;
+          tree = b;
+        }
+      }
+    }
+  }
+}
+
+fn tree_set<T: Clone+Freeze>(size: uint, index: uint, tree: &Rc<Tree<T>>, x: T) -> Rc<Tree<T>> {
+  match *tree.borrow() {
+    Leaf(_) => Rc::new(Leaf(x)),
+    Node(ref y, ref a, ref b) => {
+      let half = size / 2;
+      if index == 0 {
+        Rc::new(Node(x, a.clone(), b.clone()))
+      } else if index <= half {
+        Rc::new(Node(y.clone(), tree_set(half, index - 1, a, x), b.clone()))
+      } else {
+        Rc::new(Node(y.clone(), a.clone(), tree_set(half, index - 1 - half, b, x)))
+      }
+    }
+  }
+}
+
+impl<T: Freeze> RandomAccessList<T> {
+  /// Create an empty list.
+  pub fn new() -> RandomAccessList<T> {
+    RandomAccessList{trees: List::nil(), len: 0}
+  }
+  /// Create a list from the head and the rest of the list.
+  pub fn cons(x: T, xs: &RandomAccessList<T>) -> RandomAccessList<T> {
+    let trees = match *xs.trees.node() {
+      Cons((w1, ref t1), ref rest) => match *rest.node() {
+        Cons((w2, ref t2), ref rest) if w1 == w2 =>
+          List::cons((1 + w1 + w2, Rc::new(Node(x, t1.clone(), t2.clone()))), rest.clone()),
+        _ => List::cons((1, Rc::new(Leaf(x))), xs.trees.clone())
+      },
+      Nil => List::cons((1, Rc::new(Leaf(x))), List::nil())
+    };
+    RandomAccessList{trees: trees, len: xs.len + 1}
+  }
+  /// The list after the first member, if the list isn't empty.
+  pub fn tail(&self) -> Option<RandomAccessList<T>> {
+    match *self.trees.node() {
+      Nil => None,
+      Cons((w, ref t), ref rest) => {
+        let trees = match *t.borrow() {
+          Leaf(_) => rest.clone(),
+          Node(_, ref a, ref b) =>
+            List::cons((w / 2, a.clone()), List::cons((w / 2, b.clone()), rest.clone()))
+        };
+        Some(RandomAccessList{trees: trees, len: self.len - 1})
+      }
+    }
+  }
+}
+
+impl<T: Clone+Freeze> RandomAccessList<T> {
+  /// Create a list with the member at index replaced by x.
+  /// Fails if index is out of bounds.
+  pub fn set(&self, index: uint, x: T) -> RandomAccessList<T> {
+    if index >= self.len {
+      fail!("RandomAccessList::set: index {} out of bounds for length {}", index, self.len);
+    }
+    let mut before = ListBuilder::new();
+    let mut rest = &self.trees;
+    let mut index = index;
+    loop {
+      match *rest.node() {
+        Nil => fail!(),
+        Cons((w, ref t), ref xs) => {
+          if index < w {
+            let trees = List::cons((w, tree_set(w, index, t, x)), xs.clone());
+            return RandomAccessList{trees: before.finish_onto(trees), len: self.len};
+          }
+          index -= w;
+          before.push((w, t.clone()));
+          rest = xs;
+        }
+      }
+    }
+  }
+}
+
+impl<T> RandomAccessList<T> {
+  /// The first member, if the list isn't empty.
+  pub fn head<'t>(&'t self) -> Option<&'t T> {
+    self.get(0)
+  }
+  /// The member at index, if index is in bounds.
+  pub fn get<'t>(&'t self, index: uint) -> Option<&'t T> {
+    let mut index = index;
+    for &(w, ref t) in self.trees.iter() {
+      if index < w {
+        return Some(tree_get(w, index, t));
+      }
+      index -= w;
+    }
+    None
+  }
+  /// Iterate over the members in order.
+  pub fn iter<'t>(&'t self) -> RandomAccessListIterator<'t, T> {
+    RandomAccessListIterator{trees: self.trees.iter(), stack: ~[]}
+  }
+}
+
+impl<T> Container for RandomAccessList<T> {
+  fn len(&self) -> uint {
+    self.len
+  }
+}
+
+impl<T: Eq> Eq for RandomAccessList<T> {
+  fn eq(&self, other: &RandomAccessList<T>) -> bool {
+    self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| *a == *b)
+  }
+}
+
+impl<T: Freeze> Default for RandomAccessList<T> {
+  fn default() -> RandomAccessList<T> {
+    RandomAccessList::new()
+  }
+}
+
+impl<A: Freeze> FromIterator<A> for RandomAccessList<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> RandomAccessList<A> {
+    let members : ~[A] = iter.collect();
+    let mut result = RandomAccessList::new();
+    for x in members.move_rev_iter() {
+      result = RandomAccessList::cons(x, &result);
+    }
+    result
+  }
+}
+
+/// Iterator over a RandomAccessList's members, in order.
+pub struct RandomAccessListIterator<'self, T> {
+  priv trees : ListIterator<'self, (uint, Rc<Tree<T>>)>,
+  // Subtrees of the current tree still to visit, the next one last.
+  priv stack : ~[&'self Tree<T>]
+}
+
+impl<'self, T> Iterator<&'self T> for RandomAccessListIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    let depth = self.stack.len();
+    let tree = if depth == 0 {
+      match self.trees.next() {
+        None => return None,
+        Some(&(_, ref t)) => t.borrow()
+      }
+    } else {
+      let t = self.stack[depth - 1];
+      self.stack.truncate(depth - 1);
+      t
+    };
+    match *tree {
+      Leaf(ref x) => Some(x),
+      Node(ref x, ref a, ref b) => {
+        self.stack.push(b.borrow());
+        self.stack.push(a.borrow());
+        Some(x)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::RandomAccessList;
+#[test]
+fn test_ralist() {
+  let r0 : RandomAccessList<int> = RandomAccessList::new();
+  assert!(r0.head().is_none());
+  assert!(r0.tail().is_none());
+  let r2 = RandomAccessList::cons(1, &RandomAccessList::cons(2, &r0));
+  assert!(r2.head() == Some(&1));
+  assert!(r2.tail().unwrap().head() == Some(&2));
+  let big : RandomAccessList<uint> = range(0u, 1000).collect();
+  assert!(big.len() == 1000);
+  for i in range(0u, 1000) {
+    assert!(big.get(i) == Some(&i));
+  }
+  assert!(big.get(1000).is_none());
+  let items : ~[uint] = big.iter().map(|x| *x).collect();
+  let expected : ~[uint] = range(0u, 1000).collect();
+  assert!(items == expected);
+  let changed = big.set(777, 0);
+  assert!(changed.get(777) == Some(&0));
+  assert!(big.get(777) == Some(&777));
+  assert!(changed.get(776) == Some(&776));
+  let mut rest = big.clone();
+  for i in range(0u, 1000) {
+    assert!(rest.head() == Some(&i));
+    assert!(rest.len() == 1000 - i);
+    rest = rest.tail().unwrap();
+  }
+  assert!(rest.is_empty());
+}
+}
+
+}
+
+pub mod stream {
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::util;
+use persistent::list::List;
+
+/// Persistent lazy list: each tail is computed the first time it's
+/// needed, and then remembered, so every version that shares it
+/// shares the computed result too.  Streams can be infinite.
+///
+/// The functions given to the combinators are plain `fn`s rather
+/// than closures, since they're kept around until the stream is forced.
+pub struct Stream<T> {
+  priv cell : Rc<RefCell<Lazy<T>>>
+}
+
+enum Lazy<T> {
+  Forced(Option<(T, Stream<T>)>),
+  Suspended(~Suspension<T>:),
+  // Being computed right now.
+  Forcing
+}
+
+// Something that can compute the first member and the rest of a
+// stream.  The combinators are made of these.  It's private so that
+// no suspension can hold a way to reach the stream it will be the
+// result of (see Stream::make).
+trait Suspension<T> {
+  /// Compute the first member and the rest of the stream,
+  /// or None if it's empty.
+  fn force(&self) -> Option<(T, Stream<T>)>;
+}
+
+impl<T> Clone for Stream<T> {
+  fn clone(&self) -> Stream<T> {
+    Stream{cell: self.cell.clone()}
+  }
+}
+
+impl<T: Clone+Freeze+'static> Stream<T> {
+  fn make(lazy: Lazy<T>) -> Stream<T> {
+    // RefCell isn't Freeze, so Rc::new won't do.  A stream can't end
+    // up referring to itself, which would leak: the only suspensions
+    // are the combinators below, which hold plain fns and streams that
+    // were made before them, and make their results from those; and
+    // members are Freeze, so none can be changed afterwards to point
+    // at a stream that holds it.
+    Stream{cell: unsafe { Rc::new_unchecked(RefCell::new(lazy)) }}
+  }
+  /// Create an empty stream.
+  pub fn empty() -> Stream<T> {
+    Stream::make(Forced(None))
+  }
+  /// Create a stream from the head and the rest of the stream.
+  pub fn cons(x: T, xs: Stream<T>) -> Stream<T> {
+    Stream::make(Forced(Some((x, xs))))
+  }
+  // Create a stream that will be (and remember) whatever
+  // suspension.force() gives, once it's needed.
+  fn suspend(suspension: ~Suspension<T>:) -> Stream<T> {
+    Stream::make(Suspended(suspension))
+  }
+  /// The infinite stream seed, f(seed), f(f(seed)), ...
+  pub fn iterate(f: fn(&T) -> T, seed: T) -> Stream<T> {
+    Stream::cons(seed.clone(), Stream::suspend(~Iterate{f: f, previous: seed} as ~Suspension<T>:))
+  }
+  /// The stream f(0), f(1), f(2), ..., up to but not including
+  /// the first None.
+  pub fn from_fn(f: fn(uint) -> Option<T>) -> Stream<T> {
+    Stream::suspend(~FromFn{f: f, index: 0} as ~Suspension<T>:)
+  }
+  /// A stream of the List's members.
+  pub fn from_list(list: &List<T>) -> Stream<T> {
+    let members : ~[&T] = list.iter().collect();
+    let mut result = Stream::empty();
+    for x in members.rev_iter() {
+      result = Stream::cons((*x).clone(), result);
+    }
+    result
+  }
+  /// The first member and the rest of the stream, or None if it's
+  /// empty; computed now if it hasn't been already.
+  pub fn uncons(&self) -> Option<(T, Stream<T>)> {
+    let suspension = {
+      let mut lazy = self.cell.borrow().borrow_mut();
+      match util::replace(lazy.get(), Forcing) {
+        Forced(result) => {
+          *lazy.get() = Forced(result.clone());
+          return result;
+        }
+        Suspended(suspension) => suspension,
+        Forcing => fail!("Stream: a stream's tail needed the stream itself")
+      }
+    };
+    // Nothing is borrowed while forcing, which may force other streams.
+    let result = suspension.force();
+    *self.cell.borrow().borrow_mut().get() = Forced(result.clone());
+    result
+  }
+  /// The first member, if the stream isn't empty.
+  pub fn head(&self) -> Option<T> {
+    match self.uncons() { None => None, Some((x, _)) => Some(x) }
+  }
+  /// The rest of the stream, if it isn't empty.
+  pub fn tail(&self) -> Option<Stream<T>> {
+    match self.uncons() { None => None, Some((_, xs)) => Some(xs) }
+  }
+  /// Lazily, the first n members of the stream (or all of them,
+  /// if there are fewer).
+  pub fn take(&self, n: uint) -> Stream<T> {
+    if n == 0 {
+      Stream::empty()
+    } else {
+      Stream::suspend(~Take{source: self.clone(), n: n} as ~Suspension<T>:)
+    }
+  }
+  /// Lazily, f of each member.
+  pub fn map<U: Clone+Freeze+'static>(&self, f: fn(&T) -> U) -> Stream<U> {
+    Stream::suspend(~Map{source: self.clone(), f: f} as ~Suspension<U>:)
+  }
+  /// Lazily, the members for which pred is true.  (Forcing it looks
+  /// ahead until the next such member, forever if there isn't one.)
+  pub fn filter(&self, pred: fn(&T) -> bool) -> Stream<T> {
+    Stream::suspend(~Filter{source: self.clone(), pred: pred} as ~Suspension<T>:)
+  }
+  /// Iterate over (copies of) the members, forcing them as it goes.
+  pub fn iter(&self) -> StreamIterator<T> {
+    StreamIterator{rest: self.clone()}
+  }
+  /// A List of the members.  Never returns if the stream is infinite.
+  pub fn to_list(&self) -> List<T> {
+    self.iter().collect()
+  }
+}
+
+struct Iterate<T> { f: fn(&T) -> T, previous: T }
+impl<T: Clone+Freeze+'static> Suspension<T> for Iterate<T> {
+  fn force(&self) -> Option<(T, Stream<T>)> {
+    let next = (self.f)(&self.previous);
+    let rest = ~Iterate{f: self.f, previous: next.clone()} as ~Suspension<T>:;
+    Some((next, Stream::suspend(rest)))
+  }
+}
+
+struct FromFn<T> { f: fn(uint) -> Option<T>, index: uint }
+impl<T: Clone+Freeze+'static> Suspension<T> for FromFn<T> {
+  fn force(&self) -> Option<(T, Stream<T>)> {
+    match (self.f)(self.index) {
+      None => None,
+      Some(x) => {
+        let rest = ~FromFn{f: self.f, index: self.index + 1} as ~Suspension<T>:;
+        Some((x, Stream::suspend(rest)))
+      }
+    }
+  }
+}
+
+struct Take<T> { source: Stream<T>, n: uint }
+impl<T: Clone+Freeze+'static> Suspension<T> for Take<T> {
+  fn force(&self) -> Option<(T, Stream<T>)> {
+    match self.source.uncons() {
+      None => None,
+      Some((x, xs)) => Some((x, xs.take(self.n - 1)))
+    }
+  }
+}
+
+struct Map<T, U> { source: Stream<T>, f: fn(&T) -> U }
+impl<T: Clone+Freeze+'static, U: Clone+Freeze+'static> Suspension<U> for Map<T, U> {
+  fn force(&self) -> Option<(U, Stream<U>)> {
+    match self.source.uncons() {
+      None => None,
+      Some((x, xs)) => Some(((self.f)(&x), xs.map(self.f)))
+    }
+  }
+}
+
+struct Filter<T> { source: Stream<T>, pred: fn(&T) -> bool }
+impl<T: Clone+Freeze+'static> Suspension<T> for Filter<T> {
+  fn force(&self) -> Option<(T, Stream<T>)> {
+    let mut rest = self.source.clone();
+    loop {
+      match rest.uncons() {
+        None => return None,
+        Some((x, xs)) => {
+          if (self.pred)(&x) {
+            return Some((x, xs.filter(self.pred)));
+          }
+          rest = xs;
+        }
+      }
+    }
+  }
+}
+
+/// Iterator over a Stream's members.
+pub struct StreamIterator<T> {
+  priv rest : Stream<T>
+}
+
+impl<T: Clone+Freeze+'static> Iterator<T> for StreamIterator<T> {
+  fn next(&mut self) -> Option<T> {
+    match self.rest.uncons() {
+      None => None,
+      Some((x, xs)) => {
+        self.rest = xs;
+        Some(x)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::{Stream, Suspension};
+use persistent::list::List;
+use std::cell::Cell;
+
+fn double(x: &int) -> int { *x * 2 }
+fn is_odd(x: &int) -> bool { *x % 2 == 1 }
+fn succ(x: &int) -> int { *x + 1 }
+fn below_three(i: uint) -> Option<uint> { if i < 3 { Some(i) } else { None } }
+
+#[test]
+fn test_stream() {
+  let naturals = Stream::iterate(succ, 0);
+  let odd_doubled = naturals.filter(is_odd).map(double).take(3);
+  assert!(odd_doubled.to_list() == List::from_vec(~[2, 6, 10]));
+  assert!(naturals.take(4).to_list() == List::from_vec(~[0, 1, 2, 3]));
+  assert!(Stream::from_fn(below_three).to_list() == List::from_vec(~[0u, 1, 2]));
+  let s : Stream<int> = Stream::empty();
+  assert!(s.head().is_none());
+  let l = List::from_vec(~[4, 5]);
+  assert!(Stream::from_list(&l).to_list() == l);
+  assert!(naturals.tail().unwrap().head() == Some(1));
+}
+
+// Counts how many times it has been forced.
+struct Counted { count: @Cell<int> }
+impl Suspension<int> for Counted {
+  fn force(&self) -> Option<(int, Stream<int>)> {
+    self.count.set(self.count.get() + 1);
+    Some((7, Stream::empty()))
+  }
+}
+
+#[test]
+fn test_memoized() {
+  let count = @Cell::new(0);
+  let s = Stream::suspend(~Counted{count: count} as ~Suspension<int>:);
+  let shared = s.clone();
+  assert!(s.head() == Some(7));
+  assert!(shared.head() == Some(7));
+  assert!(s.tail().unwrap().head().is_none());
+  assert!(count.get() == 1);
+}
+}
+
+}
+
+pub mod fingertree {
+
+use std::rc::Rc;
+
+/// A type with an associative `plus` that has `zero` as its identity.
+pub trait Monoid {
+  fn zero() -> Self;
+  fn plus(&self, other: &Self) -> Self;
+}
+
+/// Members of a FingerTree<V, T> each have a measure of type V, and
+/// a tree or part of one measures the sum of its members' measures.
+pub trait Measured<V> {
+  fn measure(&self) -> V;
+}
+
+/// Persistent finger tree (Hinze and Paterson), annotated with
+/// measures: the ends are O(1) to look at and amortized O(1) to add
+/// to or remove from, and split and concat are O(log n).
+///
+/// With size as the measure it is a sequence that can be
+/// indexed and split by position; with max priority it's a priority
+/// queue; with ordered keys it's an ordered sequence or interval map.
+pub struct FingerTree<V, T> {
+  priv tree : Rc<Tree<V, T>>
+}
+
+// A member, or one of the 2-3 nodes that the deeper levels of the
+// tree are made of, with its measure remembered.  (Every level uses
+// this one type, since Rust can't instantiate the nested
+// FingerTree<Node<T>> the usual definition layers on.)
+enum Item<V, T> {
+  Leaf(T),
+  Node(V, ~[Rc<Item<V, T>>])
+}
+
+// Deep holds measure, prefix digit (1-4 items), middle tree
+// (of nodes), suffix digit (1-4 items).
+enum Tree<V, T> {
+  Empty,
+  Single(Rc<Item<V, T>>),
+  Deep(V, ~[Rc<Item<V, T>>], Rc<Tree<V, T>>, ~[Rc<Item<V, T>>])
+}
+
+impl<V: Clone, T> Clone for Tree<V, T> {
+  fn clone(&self) -> Tree<V, T> {
+    match *self {
+      Empty => Empty,
+      Single(ref x) => Single(x.clone()),
+      Deep(ref v, ref pr, ref m, ref sf) => Deep(v.clone(), pr.clone(), m.clone(), sf.clone())
+    }
+  }
+}
+
+impl<V, T> Clone for FingerTree<V, T> {
+  fn clone(&self) -> FingerTree<V, T> {
+    FingerTree{tree: self.tree.clone()}
+  }
+}
+
+fn item_measure<V: Monoid+Clone, T: Measured<V>>(item: &Rc<Item<V, T>>) -> V {
+  match *item.borrow() {
+    Leaf(ref x) => x.measure(),
+    Node(ref v, _) => v.clone()
+  }
+}
+
+fn digit_measure<V: Monoid+Clone, T: Measured<V>>(items: &[Rc<Item<V, T>>]) -> V {
+  let mut v : V = Monoid::zero();
+  for item in items.iter() {
+    v = v.plus(&item_measure(item));
+  }
+  v
+}
+
+fn tree_measure<V: Monoid+Clone, T: Measured<V>>(t: &Tree<V, T>) -> V {
+  match *t {
+    Empty => Monoid::zero(),
+    Single(ref x) => item_measure(x),
+    Deep(ref v, _, _, _) => v.clone()
+  }
+}
+
+fn leaf<'t, V, T>(item: &'t Rc<Item<V, T>>) -> &'t T {
+  match *item.borrow() {
+    Leaf(ref x) => x,
+    Node(_, _) => fail!("FingerTree: expected a member, found a node")
+  }
+}
+
+fn children<V, T>(item: &Rc<Item<V, T>>) -> ~[Rc<Item<V, T>>] {
+  match *item.borrow() {
+    Node(_, ref kids) => kids.clone(),
+    Leaf(_) => fail!("FingerTree: expected a node, found a member")
+  }
+}
+
+fn node<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(kids: ~[Rc<Item<V, T>>]) -> Rc<Item<V, T>> {
+  let v = digit_measure(kids);
+  Rc::new(Node(v, kids))
+}
+
+fn deep<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(
+    pr: ~[Rc<Item<V, T>>], m: Rc<Tree<V, T>>, sf: ~[Rc<Item<V, T>>]) -> Tree<V, T> {
+  let v = digit_measure(pr).plus(&tree_measure(m.borrow())).plus(&digit_measure(sf));
+  Deep(v, pr, m, sf)
+}
+
+fn to_tree<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(items: &[Rc<Item<V, T>>]) -> Tree<V, T> {
+  let mut t = Empty;
+  for item in items.iter() {
+    t = push_back(&t, item.clone());
+  }
+  t
+}
+
+fn push_front<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(a: Rc<Item<V, T>>, t: &Tree<V, T>) -> Tree<V, T> {
+  match *t {
+    Empty => Single(a),
+    Single(ref b) => deep(~[a], Rc::new(Empty), ~[b.clone()]),
+    Deep(_, ref pr, ref m, ref sf) => {
+      if pr.len() == 4 {
+        let rest = node(pr.slice_from(1).to_owned());
+        deep(~[a, pr[0].clone()], Rc::new(push_front(rest, m.borrow())), sf.clone())
+      } else {
+        let mut new_pr = ~[a];
+        new_pr.push_all(*pr);
+        deep(new_pr, m.clone(), sf.clone())
+      }
+    }
+  }
+}
+
+fn push_back<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(t: &Tree<V, T>, a: Rc<Item<V, T>>) -> Tree<V, T> {
+  match *t {
+    Empty => Single(a),
+    Single(ref b) => deep(~[b.clone()], Rc::new(Empty), ~[a]),
+    Deep(_, ref pr, ref m, ref sf) => {
+      if sf.len() == 4 {
+        let rest = node(sf.slice_to(3).to_owned());
+        deep(pr.clone(), Rc::new(push_back(m.borrow(), rest)), ~[sf[3].clone(), a])
+      } else {
+        let mut new_sf = sf.clone();
+        new_sf.push(a);
+        deep(pr.clone(), m.clone(), new_sf)
+      }
+    }
+  }
+}
+
+// A tree from a prefix that may have run out.
+fn deep_l<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(
+    pr: &[Rc<Item<V, T>>], m: &Rc<Tree<V, T>>, sf: &[Rc<Item<V, T>>]) -> Tree<V, T> {
+  if !pr.is_empty() {
+    return deep(pr.to_owned(), m.clone(), sf.to_owned());
+  }
+  match view_front(m.borrow()) {
+    None => to_tree(sf),
+    Some((n, rest)) => deep(children(&n), Rc::new(rest), sf.to_owned())
+  }
+}
+
+// A tree from a suffix that may have run out.
+fn deep_r<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(
+    pr: &[Rc<Item<V, T>>], m: &Rc<Tree<V, T>>, sf: &[Rc<Item<V, T>>]) -> Tree<V, T> {
+  if !sf.is_empty() {
+    return deep(pr.to_owned(), m.clone(), sf.to_owned());
+  }
+  match view_back(m.borrow()) {
+    None => to_tree(pr),
+    Some((rest, n)) => deep(pr.to_owned(), Rc::new(rest), children(&n))
+  }
+}
+
+fn view_front<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(t: &Tree<V, T>) -> Option<(Rc<Item<V, T>>, Tree<V, T>)> {
+  match *t {
+    Empty => None,
+    Single(ref x) => Some((x.clone(), Empty)),
+    Deep(_, ref pr, ref m, ref sf) => Some((pr[0].clone(), deep_l(pr.slice_from(1), m, *sf)))
+  }
+}
+
+fn view_back<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(t: &Tree<V, T>) -> Option<(Tree<V, T>, Rc<Item<V, T>>)> {
+  match *t {
+    Empty => None,
+    Single(ref x) => Some((Empty, x.clone())),
+    Deep(_, ref pr, ref m, ref sf) => {
+      let last = sf.len() - 1;
+      Some((deep_r(*pr, m, sf.slice_to(last)), sf[last].clone()))
+    }
+  }
+}
+
+// Group 2 or more items into 2-3 nodes.
+fn nodes<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(items: &[Rc<Item<V, T>>]) -> ~[Rc<Item<V, T>>] {
+  let mut result = ~[];
+  let mut rest = items;
+  loop {
+    match rest.len() {
+      2 | 3 => {
+        result.push(node(rest.to_owned()));
+        return result;
+      }
+      4 => {
+        result.push(node(rest.slice(0, 2).to_owned()));
+        result.push(node(rest.slice(2, 4).to_owned()));
+        return result;
+      }
+      _ => {
+        result.push(node(rest.slice(0, 3).to_owned()));
+        rest = rest.slice_from(3);
+      }
+    }
+  }
+}
+
+// t1 ++ items ++ t2.
+fn app3<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(
+    t1: &Tree<V, T>, items: &[Rc<Item<V, T>>], t2: &Tree<V, T>) -> Tree<V, T> {
+  match (t1, t2) {
+    (&Empty, _) => {
+      let mut t = t2.clone();
+      for item in items.rev_iter() {
+        t = push_front(item.clone(), &t);
+      }
+      t
+    }
+    (_, &Empty) => {
+      let mut t = t1.clone();
+      for item in items.iter() {
+        t = push_back(&t, item.clone());
+      }
+      t
+    }
+    (&Single(ref x), _) => push_front(x.clone(), &app3(&Empty, items, t2)),
+    (_, &Single(ref x)) => push_back(&app3(t1, items, &Empty), x.clone()),
+    (&Deep(_, ref pr1, ref m1, ref sf1), &Deep(_, ref pr2, ref m2, ref sf2)) => {
+      let mut middle = sf1.clone();
+      middle.push_all(items);
+      middle.push_all(*pr2);
+      let m = app3(m1.borrow(), nodes(middle), m2.borrow());
+      deep(pr1.clone(), Rc::new(m), sf2.clone())
+    }
+  }
+}
+
+// Split items at the first one that makes pred true of
+// i plus the measures so far (or at the last one).
+fn split_digit<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(
+    pred: |&V| -> bool, i: &V, items: &[Rc<Item<V, T>>])
+    -> (~[Rc<Item<V, T>>], Rc<Item<V, T>>, ~[Rc<Item<V, T>>]) {
+  let mut acc = i.clone();
+  let last = items.len() - 1;
+  for (k, item) in items.iter().enumerate() {
+    acc = acc.plus(&item_measure(item));
+    if k == last || pred(&acc) {
+      return (items.slice_to(k).to_owned(), item.clone(), items.slice_from(k + 1).to_owned());
+    }
+  }
+  fail!("FingerTree: split of an empty digit")
+}
+
+// Split a non-empty tree at the first item that makes pred true of i
+// plus the measures so far (or at the last one).
+fn split_tree<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze>(
+    pred: |&V| -> bool, i: &V, t: &Tree<V, T>)
+    -> (Tree<V, T>, Rc<Item<V, T>>, Tree<V, T>) {
+  match *t {
+    Empty => fail!("FingerTree: split of an empty tree"),
+    Single(ref x) => (Empty, x.clone(), Empty),
+    Deep(_, ref pr, ref m, ref sf) => {
+      let vpr = i.plus(&digit_measure(*pr));
+      if pred(&vpr) {
+        let (l, x, r) = split_digit(|v| pred(v), i, *pr);
+        return (to_tree(l), x, deep_l(r, m, *sf));
+      }
+      let vm = vpr.plus(&tree_measure(m.borrow()));
+      if pred(&vm) {
+        let (ml, xs, mr) = split_tree(|v| pred(v), &vpr, m.borrow());
+        let (l, x, r) = split_digit(|v| pred(v), &vpr.plus(&tree_measure(&ml)), children(&xs));
+        (deep_r(*pr, &Rc::new(ml), l), x, deep_l(r, &Rc::new(mr), *sf))
+      } else {
+        let (l, x, r) = split_digit(|v| pred(v), &vm, *sf);
+        (deep_r(*pr, m, l), x, to_tree(r))
+      }
+    }
+  }
+}
+
+impl<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze> FingerTree<V, T> {
+  fn wrap(t: Tree<V, T>) -> FingerTree<V, T> {
+    FingerTree{tree: Rc::new(t)}
+  }
+  /// Create an empty finger tree.
+  pub fn new() -> FingerTree<V, T> {
+    FingerTree::wrap(Empty)
+  }
+  /// Create a finger tree with x in front of everything self has.
+  pub fn push_front(&self, x: T) -> FingerTree<V, T> {
+    FingerTree::wrap(push_front(Rc::new(Leaf(x)), self.tree.borrow()))
+  }
+  /// Create a finger tree with x after everything self has.
+  pub fn push_back(&self, x: T) -> FingerTree<V, T> {
+    FingerTree::wrap(push_back(self.tree.borrow(), Rc::new(Leaf(x))))
+  }
+  /// Everything but the front member, if there is one.
+  pub fn pop_front(&self) -> Option<FingerTree<V, T>> {
+    match view_front(self.tree.borrow()) {
+      None => None,
+      Some((_, rest)) => Some(FingerTree::wrap(rest))
+    }
+  }
+  /// Everything but the back member, if there is one.
+  pub fn pop_back(&self) -> Option<FingerTree<V, T>> {
+    match view_back(self.tree.borrow()) {
+      None => None,
+      Some((rest, _)) => Some(FingerTree::wrap(rest))
+    }
+  }
+  /// Everything self has, followed by everything other has.
+  pub fn concat(&self, other: &FingerTree<V, T>) -> FingerTree<V, T> {
+    FingerTree::wrap(app3(self.tree.borrow(), [], other.tree.borrow()))
+  }
+  /// The sum of the members' measures.
+  pub fn measure(&self) -> V {
+    tree_measure(self.tree.borrow())
+  }
+  /// Split in two at the first member whose measure, added to the
+  /// measures of the ones before it, makes pred true: the second tree
+  /// starts with that member.  If there's no such member the second
+  /// tree is empty.  pred should be monotonic: once true, staying true
+  /// as more gets added.
+  pub fn split(&self, pred: |&V| -> bool) -> (FingerTree<V, T>, FingerTree<V, T>) {
+    let t = self.tree.borrow();
+    match *t {
+      Empty => return (self.clone(), self.clone()),
+      _ => ()
+    }
+    if !pred(&tree_measure(t)) {
+      return (self.clone(), FingerTree::new());
+    }
+    let zero : V = Monoid::zero();
+    let (l, x, r) = split_tree(|v| pred(v), &zero, t);
+    (FingerTree::wrap(l), FingerTree::wrap(push_front(x, &r)))
+  }
+}
+
+impl<V, T> FingerTree<V, T> {
+  /// Return true if there are no members.
+  pub fn is_empty(&self) -> bool {
+    match *self.tree.borrow() { Empty => true, _ => false }
+  }
+  /// The front member, if there is one.
+  pub fn front<'t>(&'t self) -> Option<&'t T> {
+    match *self.tree.borrow() {
+      Empty => None,
+      Single(ref x) => Some(leaf(x)),
+      Deep(_, ref pr, _, _) => Some(leaf(&pr[0]))
+    }
+  }
+  /// The back member, if there is one.
+  pub fn back<'t>(&'t self) -> Option<&'t T> {
+    match *self.tree.borrow() {
+      Empty => None,
+      Single(ref x) => Some(leaf(x)),
+      Deep(_, _, _, ref sf) => Some(leaf(&sf[sf.len() - 1]))
+    }
+  }
+  /// Iterate over the members, front to back.
+  pub fn iter<'t>(&'t self) -> FingerTreeIterator<'t, V, T> {
+    FingerTreeIterator{stack: ~[WholeTree(self.tree.borrow())], backward: false}
+  }
+  /// Iterate over the members, back to front.
+  pub fn rev_iter<'t>(&'t self) -> FingerTreeIterator<'t, V, T> {
+    FingerTreeIterator{stack: ~[WholeTree(self.tree.borrow())], backward: true}
+  }
+}
+
+impl<V: Monoid+Clone+Freeze, T: Measured<V>+Freeze> Default for FingerTree<V, T> {
+  fn default() -> FingerTree<V, T> {
+    FingerTree::new()
+  }
+}
+
+impl<V: Monoid+Clone+Freeze, A: Measured<V>+Freeze> FromIterator<A> for FingerTree<V, A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> FingerTree<V, A> {
+    let mut t = Empty;
+    for x in *iter {
+      t = push_back(&t, Rc::new(Leaf(x)));
+    }
+    FingerTree::wrap(t)
+  }
+}
+
+enum Pending<'self, V, T> {
+  WholeTree(&'self Tree<V, T>),
+  OneItem(&'self Item<V, T>)
+}
+
+/// Iterator over a FingerTree's members.
+pub struct FingerTreeIterator<'self, V, T> {
+  priv stack : ~[Pending<'self, V, T>],
+  priv backward : bool
+}
+
+impl<'self, V, T> FingerTreeIterator<'self, V, T> {
+  // Push items so that the one to visit first is on top.
+  fn push_items(&mut self, items: &'self [Rc<Item<V, T>>]) {
+    if self.backward {
+      for item in items.iter() {
+        self.stack.push(OneItem(item.borrow()));
+      }
+    } else {
+      for item in items.rev_iter() {
+        self.stack.push(OneItem(item.borrow()));
+      }
+    }
+  }
+}
+
+impl<'self, V, T> Iterator<&'self T> for FingerTreeIterator<'self, V, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    loop {
+      let depth = self.stack.len();
+      if depth == 0 {
+        return None;
+      }
+      let pending = self.stack[depth - 1];
+      self.stack.truncate(depth - 1);
+      match pending {
+        WholeTree(&Empty) => (),
+        WholeTree(&Single(ref x)) => self.stack.push(OneItem(x.borrow())),
+        WholeTree(&Deep(_, ref pr, ref m, ref sf)) => {
+          let (first, last) = if self.backward { (sf, pr) } else { (pr, sf) };
+          self.push_items(*last);
+          self.stack.push(WholeTree(m.borrow()));
+          self.push_items(*first);
+        }
+        OneItem(&Leaf(ref x)) => return Some(x),
+        OneItem(&Node(_, ref kids)) => self.push_items(*kids)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::{FingerTree, Monoid, Measured};
+
+#[deriving(Clone, Eq)]
+struct Size(uint);
+
+impl Monoid for Size {
+  fn zero() -> Size { Size(0) }
+  fn plus(&self, other: &Size) -> Size {
+    let (Size(a), Size(b)) = (*self, *other);
+    Size(a + b)
+  }
+}
+
+impl Measured<Size> for int {
+  fn measure(&self) -> Size { Size(1) }
+}
+
+#[test]
+fn test_fingertree() {
+  let empty : FingerTree<Size, int> = FingerTree::new();
+  assert!(empty.is_empty() && empty.front().is_none() && empty.pop_back().is_none());
+  let t : FingerTree<Size, int> = range(0, 100).collect();
+  assert!(t.measure() == Size(100));
+  assert!(t.front() == Some(&0) && t.back() == Some(&99));
+  let u = t.push_front(-1).push_back(100).pop_front().unwrap();
+  assert!(u.front() == Some(&0) && u.back() == Some(&100));
+  assert!(t.back() == Some(&99));
+  let (l, r) = t.split(|&Size(n)| n > 40);
+  assert!(l.measure() == Size(40) && r.measure() == Size(60));
+  assert!(l.back() == Some(&39) && r.front() == Some(&40));
+  let joined = l.concat(&r);
+  let members : ~[int] = joined.iter().map(|x| *x).collect();
+  let expected : ~[int] = range(0, 100).collect();
+  assert!(members == expected);
+  let backward : ~[int] = joined.rev_iter().map(|x| *x).collect();
+  let expected : ~[int] = range(0, 100).invert().collect();
+  assert!(backward == expected);
+  let (all, none) = t.split(|&Size(n)| n > 1000);
+  assert!(all.measure() == Size(100) && none.is_empty());
+  let mut shrinking = t.clone();
+  for i in range(0, 100) {
+    assert!(shrinking.front() == Some(&i));
+    shrinking = shrinking.pop_front().unwrap();
+  }
+  assert!(shrinking.is_empty());
+}
+}
+
+}
+
+pub mod intmap {
+
+use std::rc::Rc;
+use std::ptr;
+use persistent::traits::{PersistentMap, Change, Added, Removed, Updated};
+
+/// Persistent map with uint keys, a big-endian Patricia trie
+/// (Okasaki and Gill): lookups, insert and remove take time
+/// proportional to the key's bit length at most, union_with is fast
+/// when the maps' keys are far apart, and iteration is in key order.
+pub struct IntMap<V> {
+  priv root : Tree<V>
+}
+
+type Tree<V> = Option<Rc<Node<V>>>;
+
+enum Node<V> {
+  Tip(uint, V),
+  // Prefix (the key bits above the branching bit), branching bit,
+  // number of entries, entries with the bit clear, entries with it set.
+  Bin(uint, uint, uint, Rc<Node<V>>, Rc<Node<V>>)
+}
+
+impl<V> Clone for IntMap<V> {
+  fn clone(&self) -> IntMap<V> {
+    IntMap{root: self.root.clone()}
+  }
+}
+
+fn size<V>(n: &Rc<Node<V>>) -> uint {
+  match *n.borrow() { Tip(_, _) => 1, Bin(_, _, s, _, _) => s }
+}
+
+fn zero_bit(k: uint, m: uint) -> bool {
+  k & m == 0
+}
+
+// The bits of k above the bit m.
+fn mask(k: uint, m: uint) -> uint {
+  k & !((m - 1) | m)
+}
+
+fn match_prefix(k: uint, p: uint, m: uint) -> bool {
+  mask(k, m) == p
+}
+
+fn highest_bit(x: uint) -> uint {
+  let mut bit = 1;
+  while bit <= x >> 1 {
+    bit <<= 1;
+  }
+  bit
+}
+
+fn bin<V: Freeze>(p: uint, m: uint, l: Rc<Node<V>>, r: Rc<Node<V>>) -> Rc<Node<V>> {
+  let s = size(&l) + size(&r);
+  Rc::new(Bin(p, m, s, l, r))
+}
+
+// Combine trees with differing prefixes p1 and p2.
+fn join<V: Freeze>(p1: uint, t1: Rc<Node<V>>, p2: uint, t2: Rc<Node<V>>) -> Rc<Node<V>> {
+  let m = highest_bit(p1 ^ p2);
+  if zero_bit(p1, m) {
+    bin(mask(p1, m), m, t1, t2)
+  } else {
+    bin(mask(p1, m), m, t2, t1)
+  }
+}
+
+// n with k mapped to v, or (if k is there already) to f(v, old),
+// or to f(old, v) if v_first is false.
+fn insert_with<V: Clone+Freeze>(n: &Rc<Node<V>>, k: uint, v: &V, v_first: bool,
+                                f: |&V, &V| -> V) -> Rc<Node<V>> {
+  match *n.borrow() {
+    Tip(k2, ref old) => {
+      if k == k2 {
+        Rc::new(Tip(k, if v_first { f(v, old) } else { f(old, v) }))
+      } else {
+        join(k, Rc::new(Tip(k, v.clone())), k2, n.clone())
+      }
+    }
+    Bin(p, m, _, ref l, ref r) => {
+      if !match_prefix(k, p, m) {
+        join(k, Rc::new(Tip(k, v.clone())), p, n.clone())
+      } else if zero_bit(k, m) {
+        bin(p, m, insert_with(l, k, v, v_first, f), r.clone())
+      } else {
+        bin(p, m, l.clone(), insert_with(r, k, v, v_first, f))
+      }
+    }
+  }
+}
+
+// None if k isn't there (so nothing changes).
+fn remove<V: Freeze>(n: &Rc<Node<V>>, k: uint) -> Option<Tree<V>> {
+  match *n.borrow() {
+    Tip(k2, _) => if k == k2 { Some(None) } else { None },
+    Bin(p, m, _, ref l, ref r) => {
+      if !match_prefix(k, p, m) {
+        None
+      } else if zero_bit(k, m) {
+        match remove(l, k) {
+          None => None,
+          Some(None) => Some(Some(r.clone())),
+          Some(Some(l)) => Some(Some(bin(p, m, l, r.clone())))
+        }
+      } else {
+        match remove(r, k) {
+          None => None,
+          Some(None) => Some(Some(l.clone())),
+          Some(Some(r)) => Some(Some(bin(p, m, l.clone(), r)))
+        }
+      }
+    }
+  }
+}
+
+fn union_with<V: Clone+Freeze>(a: &Rc<Node<V>>, b: &Rc<Node<V>>, f: |&V, &V| -> V) -> Rc<Node<V>> {
+  match (a.borrow(), b.borrow()) {
+    (&Tip(k, ref x), _) => insert_with(b, k, x, true, f),
+    (_, &Tip(k, ref y)) => insert_with(a, k, y, false, f),
+    (&Bin(p, m, _, ref al, ref ar), &Bin(q, n, _, ref bl, ref br)) => {
+      if m > n {
+        // b goes inside one side of a, or beside it.
+        if !match_prefix(q, p, m) {
+          join(p, a.clone(), q, b.clone())
+        } else if zero_bit(q, m) {
+          bin(p, m, union_with(al, b, f), ar.clone())
+        } else {
+          bin(p, m, al.clone(), union_with(ar, b, f))
+        }
+      } else if m < n {
+        if !match_prefix(p, q, n) {
+          join(p, a.clone(), q, b.clone())
+        } else if zero_bit(p, n) {
+          bin(q, n, union_with(a, bl, f), br.clone())
+        } else {
+          bin(q, n, bl.clone(), union_with(a, br, f))
+        }
+      } else if p == q {
+        bin(p, m, union_with(al, bl, |x, y| f(x, y)), union_with(ar, br, f))
+      } else {
+        join(p, a.clone(), q, b.clone())
+      }
+    }
+  }
+}
+
+impl<V> IntMap<V> {
+  /// Create an empty map.
+  pub fn new() -> IntMap<V> {
+    IntMap{root: None}
+  }
+  /// Iterate over the (key, value) pairs in key order.
+  pub fn iter<'t>(&'t self) -> Entries<'t, V> {
+    match self.root {
+      None => Entries{stack: ~[]},
+      Some(ref n) => Entries{stack: ~[n.borrow()]}
+    }
+  }
+  /// Use this to pattern match on the root of the map's trie:
+  /// view::Empty, view::Tip with the map's one entry, or view::Bin
+  /// with the entries whose branching bit is clear and those whose
+  /// bit is set, as maps that share this one's nodes.
+  pub fn view<'t>(&'t self) -> view::IntMapView<'t, V> {
+    match self.root {
+      None => view::Empty,
+      Some(ref n) => match *n.borrow() {
+        Tip(k, ref v) => view::Tip(k, v),
+        Bin(_, _, _, ref l, ref r) => view::Bin(IntMap{root: Some(l.clone())}, IntMap{root: Some(r.clone())})
+      }
+    }
+  }
+}
+
+impl<V: Clone+Freeze> IntMap<V> {
+  /// Create a map that maps key to value, and everything else as self does.
+  pub fn insert(&self, key: uint, value: V) -> IntMap<V> {
+    match self.root {
+      None => IntMap{root: Some(Rc::new(Tip(key, value)))},
+      Some(ref n) => IntMap{root: Some(insert_with(n, key, &value, true, |v, _| v.clone()))}
+    }
+  }
+  /// Create a map without key, and everything else as self has it.
+  /// (If key isn't in self, the result shares everything with self.)
+  pub fn remove(&self, key: uint) -> IntMap<V> {
+    match self.root {
+      None => self.clone(),
+      Some(ref n) => match remove(n, key) {
+        None => self.clone(),
+        Some(root) => IntMap{root: root}
+      }
+    }
+  }
+  /// Create a map of every key in either map.  Where both have a key,
+  /// its value is f(self's value, other's value).
+  pub fn union_with(&self, other: &IntMap<V>, f: |&V, &V| -> V) -> IntMap<V> {
+    match (&self.root, &other.root) {
+      (&None, _) => other.clone(),
+      (_, &None) => self.clone(),
+      (&Some(ref a), &Some(ref b)) => IntMap{root: Some(union_with(a, b, f))}
+    }
+  }
+}
+
+impl<V: Eq+Clone> IntMap<V> {
+  /// Iterate over the changes that turn self into new, in key order.
+  /// Subtrees the maps share are skipped without looking inside.
+  pub fn diff_iter(&self, new: &IntMap<V>) -> Diff<V> {
+    Diff{stack: ~[Compare(self.root.clone(), new.root.clone())]}
+  }
+}
+
+impl<V> Container for IntMap<V> {
+  fn len(&self) -> uint {
+    match self.root { None => 0, Some(ref n) => size(n) }
+  }
+}
+
+impl<V> Map<uint, V> for IntMap<V> {
+  fn contains_key(&self, key: &uint) -> bool {
+    self.find(key).is_some()
+  }
+  fn find<'a>(&'a self, key: &uint) -> Option<&'a V> {
+    let mut n = match self.root { None => return None, Some(ref n) => n };
+    loop {
+      match *n.borrow() {
+        Tip(k, ref v) => return if k == *key { Some(v) } else { None },
+        Bin(p, m, _, ref l, ref r) => {
+          if !match_prefix(*key, p, m) {
+            return None;
+          }
+          n = if zero_bit(*key, m) { l } else { r };
+        }
+      }
+    }
+  }
+}
+
+impl<V: Clone+Freeze> PersistentMap<uint, V> for IntMap<V> {
+  fn empty() -> IntMap<V> {
+    IntMap::new()
+  }
+  fn insert(&self, k: uint, v: V) -> IntMap<V> {
+    self.insert(k, v)
+  }
+  fn remove(&self, k: &uint) -> IntMap<V> {
+    self.remove(*k)
+  }
+}
+
+impl<V: Eq> Eq for IntMap<V> {
+  fn eq(&self, other: &IntMap<V>) -> bool {
+    self.len() == other.len() &&
+      self.iter().zip(other.iter()).all(|((k1, v1), (k2, v2))| k1 == k2 && *v1 == *v2)
+  }
+}
+
+impl<V> Default for IntMap<V> {
+  fn default() -> IntMap<V> {
+    IntMap::new()
+  }
+}
+
+impl<V: Clone+Freeze> FromIterator<(uint, V)> for IntMap<V> {
+  fn from_iterator<T: Iterator<(uint, V)>>(iter: &mut T) -> IntMap<V> {
+    let mut map = IntMap::new();
+    for (k, v) in *iter {
+      map = map.insert(k, v);
+    }
+    map
+  }
+}
+
+/// Iterator over an IntMap's (key, value) pairs, in key order.
+pub struct Entries<'self, V> {
+  // Subtrees still to come, the next one last.
+  priv stack : ~[&'self Node<V>]
+}
+
+impl<'self, V> Iterator<(uint, &'self V)> for Entries<'self, V> {
+  fn next(&mut self) -> Option<(uint, &'self V)> {
+    loop {
+      let depth = self.stack.len();
+      if depth == 0 {
+        return None;
+      }
+      let n = self.stack[depth - 1];
+      self.stack.truncate(depth - 1);
+      match *n {
+        Tip(k, ref v) => return Some((k, v)),
+        Bin(_, _, _, ref l, ref r) => {
+          self.stack.push(r.borrow());
+          self.stack.push(l.borrow());
+        }
+      }
+    }
+  }
+}
+
+/// A look at the root of an IntMap's trie, for code that follows the
+/// trie's shape (like recursion::intmap_cata).  Only a whole map can
+/// be Empty; the halves of a Bin never are.
+pub mod view {
+use super::IntMap;
+
+pub enum IntMapView<'self, V> {
+  Empty,
+  Tip(uint, &'self V),
+  Bin(IntMap<V>, IntMap<V>)
+}
+}
+
+/// Iterator over the changes from one IntMap to another, in key order.
+pub struct Diff<V> {
+  // Work still to do, the next last.
+  priv stack : ~[DiffStep<V>]
+}
+
+enum DiffStep<V> {
+  // Find the changes from the first tree to the second.
+  Compare(Tree<V>, Tree<V>),
+  Emit(Change<uint, V>)
+}
+
+// A node's prefix and branching bit, treating a tip as the prefix
+// that is its whole key, with no branching bit.
+fn span<V>(n: &Rc<Node<V>>) -> (uint, uint) {
+  match *n.borrow() { Tip(k, _) => (k, 0), Bin(p, m, _, _, _) => (p, m) }
+}
+
+fn halves<V>(n: &Rc<Node<V>>) -> (Tree<V>, Tree<V>) {
+  match *n.borrow() {
+    Bin(_, _, _, ref l, ref r) => (Some(l.clone()), Some(r.clone())),
+    Tip(_, _) => fail!("IntMap: expected a branch, found a tip")
+  }
+}
+
+impl<V: Eq+Clone> Diff<V> {
+  // Steps are pushed in reverse, so the first to do ends up on top.
+  // The tries are canonical (the same keys always make the same
+  // shape), so wherever the maps' shapes differ, one side's subtree
+  // has keys the other lacks.
+  fn expand(&mut self, old: &Tree<V>, new: &Tree<V>) {
+    match (old, new) {
+      (&None, &None) => (),
+      (&Some(ref a), &Some(ref b)) => {
+        if ptr::to_unsafe_ptr(a.borrow()) == ptr::to_unsafe_ptr(b.borrow()) {
+          return;
+        }
+        let (p, m) = span(a);
+        let (q, n) = span(b);
+        if m == n && p == q {
+          match (a.borrow(), b.borrow()) {
+            (&Tip(k, ref x), &Tip(_, ref y)) => if *x != *y {
+              self.stack.push(Emit(Updated(k, x.clone(), y.clone())));
+            },
+            _ => {
+              let (al, ar) = halves(a);
+              let (bl, br) = halves(b);
+              self.stack.push(Compare(ar, br));
+              self.stack.push(Compare(al, bl));
+            }
+          }
+        } else if m > n && match_prefix(q, p, m) {
+          // new fits in one side of old.
+          let (al, ar) = halves(a);
+          if zero_bit(q, m) {
+            self.stack.push(Compare(ar, None));
+            self.stack.push(Compare(al, Some(b.clone())));
+          } else {
+            self.stack.push(Compare(ar, Some(b.clone())));
+            self.stack.push(Compare(al, None));
+          }
+        } else if n > m && match_prefix(p, q, n) {
+          // old fits in one side of new.
+          let (bl, br) = halves(b);
+          if zero_bit(p, n) {
+            self.stack.push(Compare(None, br));
+            self.stack.push(Compare(Some(a.clone()), bl));
+          } else {
+            self.stack.push(Compare(Some(a.clone()), br));
+            self.stack.push(Compare(None, bl));
+          }
+        } else if p < q {
+          // No keys in common: all of old's are less than new's.
+          self.stack.push(Compare(None, Some(b.clone())));
+          self.stack.push(Compare(Some(a.clone()), None));
+        } else {
+          self.stack.push(Compare(Some(a.clone()), None));
+          self.stack.push(Compare(None, Some(b.clone())));
+        }
+      }
+      (&Some(ref a), &None) => match *a.borrow() {
+        Tip(k, ref x) => self.stack.push(Emit(Removed(k, x.clone()))),
+        Bin(_, _, _, ref l, ref r) => {
+          self.stack.push(Compare(Some(r.clone()), None));
+          self.stack.push(Compare(Some(l.clone()), None));
+        }
+      },
+      (&None, &Some(ref b)) => match *b.borrow() {
+        Tip(k, ref y) => self.stack.push(Emit(Added(k, y.clone()))),
+        Bin(_, _, _, ref l, ref r) => {
+          self.stack.push(Compare(None, Some(r.clone())));
+          self.stack.push(Compare(None, Some(l.clone())));
+        }
+      }
+    }
+  }
+}
+
+impl<V: Eq+Clone> Iterator<Change<uint, V>> for Diff<V> {
+  fn next(&mut self) -> Option<Change<uint, V>> {
+    while !self.stack.is_empty() {
+      let n = self.stack.len();
+      match self.stack.swap_remove(n - 1) {
+        Emit(change) => return Some(change),
+        Compare(old, new) => self.expand(&old, &new)
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::IntMap;
+use std::uint;
+#[test]
+fn test_insert_find_remove() {
+  let m0 : IntMap<~str> = IntMap::new();
+  let m1 = m0.insert(5, ~"five").insert(uint::max_value, ~"max").insert(0, ~"zero");
+  let m2 = m1.insert(5, ~"FIVE").remove(0);
+  assert!(m1.len() == 3 && m2.len() == 2);
+  assert!(m1.find(&5) == Some(&~"five") && m2.find(&5) == Some(&~"FIVE"));
+  assert!(m1.contains_key(&0) && !m2.contains_key(&0));
+  assert!(m2.find(&uint::max_value) == Some(&~"max"));
+  assert!(m0.find(&5).is_none() && m2.find(&4).is_none());
+  assert!(m2.remove(17) == m2);
+  let keys : ~[uint] = m1.iter().map(|(k, _)| k).collect();
+  assert!(keys == ~[0, 5, uint::max_value]);
+}
+#[test]
+fn test_union_with() {
+  let evens : IntMap<uint> = range(0u, 100).map(|i| (i * 2, 1u)).collect();
+  let threes : IntMap<uint> = range(0u, 100).map(|i| (i * 3, 10u)).collect();
+  let both = evens.union_with(&threes, |a, b| *a + *b);
+  assert!(both.len() == 100 + 100 - 34);
+  assert!(both.find(&6) == Some(&11) && both.find(&4) == Some(&1) && both.find(&9) == Some(&10));
+  assert!(both.find(&7).is_none());
+  let keys : ~[uint] = both.iter().map(|(k, _)| k).collect();
+  assert!(keys.windows(2).all(|w| w[0] < w[1]));
+  assert!(evens.len() == 100);
+  assert!(evens.union_with(&IntMap::new(), |a, _| *a) == evens);
+}
+#[test]
+fn test_diff_iter() {
+  use persistent::traits::{Change, Added, Removed, Updated};
+  let m : IntMap<uint> = range(0u, 1000).map(|i| (i * 3, i)).collect();
+  let edited = m.insert(4, 4).insert(9, 0).remove(30).insert(uint::max_value, 1);
+  let changes : ~[Change<uint, uint>] = m.diff_iter(&edited).collect();
+  assert!(changes == ~[Added(4, 4), Updated(9, 3, 0), Removed(30, 10), Added(uint::max_value, 1)]);
+  assert!(m.diff_iter(&m.clone()).next().is_none() && m.diff_iter(&m.insert(9, 3)).next().is_none());
+  let evens : IntMap<uint> = range(0u, 10).map(|i| (i * 2, i)).collect();
+  let odds : IntMap<uint> = range(0u, 10).map(|i| (i * 2 + 1, i)).collect();
+  let keys : ~[uint] = evens.diff_iter(&odds).map(|c| match c {
+    Added(k, _) => k, Removed(k, _) => k, Updated(k, _, _) => k
+  }).collect();
+  let expected : ~[uint] = range(0u, 20).collect();
+  assert!(keys == expected);
+}
+#[test]
+fn test_diff_iter_random() {
+  use persistent::traits::{Added, Removed, Updated};
+  use std::rand::{Rng, task_rng};
+  let mut rng = task_rng();
+  for _ in range(0, 50) {
+    let old : IntMap<uint> = range(0, 100).map(|_| (rng.gen_range(0u, 200), rng.gen_range(0u, 3))).collect();
+    let mut new = old.clone();
+    for _ in range(0, rng.gen_range(0u, 20)) {
+      let k = rng.gen_range(0u, 200);
+      new = if rng.gen() { new.insert(k, rng.gen_range(0u, 3)) } else { new.remove(k) };
+    }
+    let mut patched = old.clone();
+    let mut last = None;
+    for change in old.diff_iter(&new) {
+      let k = match change {
+        Added(k, v) => { assert!(!old.contains_key(&k)); patched = patched.insert(k, v); k }
+        Removed(k, v) => { assert!(old.find(&k) == Some(&v)); patched = patched.remove(k); k }
+        Updated(k, x, y) => { assert!(x != y && old.find(&k) == Some(&x)); patched = patched.insert(k, y); k }
+      };
+      assert!(last.map_default(true, |l| l < k));
+      last = Some(k);
+    }
+    assert!(patched == new);
+  }
+}
+}
+
+}
+
+pub mod rope {
+
+use std::cmp::min;
+use std::fmt;
+use persistent::fingertree::{FingerTree, FingerTreeIterator, Monoid, Measured};
+
+/// Persistent string, a finger tree of chunks of text: concat,
+/// split_at, insert_str and slice are O(log n), and the ropes they
+/// make share all but a few chunks with the originals.
+///
+/// Indices are byte offsets, and have to be on char boundaries.
+pub struct Rope {
+  priv tree : FingerTree<Bytes, Chunk>
+}
+
+// Strings are split into chunks of at most this many bytes.
+static MAX_CHUNK : uint = 512;
+
+#[deriving(Clone, Eq)]
+struct Bytes(uint);
+
+impl Monoid for Bytes {
+  fn zero() -> Bytes { Bytes(0) }
+  fn plus(&self, other: &Bytes) -> Bytes {
+    let (Bytes(a), Bytes(b)) = (*self, *other);
+    Bytes(a + b)
+  }
+}
+
+// Never empty.
+struct Chunk(~str);
+
+impl Measured<Bytes> for Chunk {
+  fn measure(&self) -> Bytes {
+    let Chunk(ref s) = *self;
+    Bytes(s.len())
+  }
+}
+
+impl Clone for Rope {
+  fn clone(&self) -> Rope {
+    Rope{tree: self.tree.clone()}
+  }
+}
+
+impl Rope {
+  /// Create an empty rope.
+  pub fn new() -> Rope {
+    Rope{tree: FingerTree::new()}
+  }
+  /// Create a rope of the text of s.
+  pub fn from_slice(s: &str) -> Rope {
+    let mut tree = FingerTree::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+      let mut end = min(rest.len(), MAX_CHUNK);
+      while !rest.is_char_boundary(end) {
+        end -= 1;
+      }
+      tree = tree.push_back(Chunk(rest.slice_to(end).to_owned()));
+      rest = rest.slice_from(end);
+    }
+    Rope{tree: tree}
+  }
+  /// The text of self, followed by the text of other.
+  pub fn concat(&self, other: &Rope) -> Rope {
+    Rope{tree: self.tree.concat(&other.tree)}
+  }
+  /// The text before byte i and the text from it on.
+  /// Fails if i is past the end or not on a char boundary.
+  pub fn split_at(&self, i: uint) -> (Rope, Rope) {
+    assert!(i <= self.len());
+    let (l, r) = self.tree.split(|&Bytes(n)| n > i);
+    let Bytes(before) = l.measure();
+    if before == i {
+      return (Rope{tree: l}, Rope{tree: r});
+    }
+    let offset = i - before;
+    let rest = r.pop_front().unwrap();
+    let &Chunk(ref s) = r.front().unwrap();
+    (Rope{tree: l.push_back(Chunk(s.slice_to(offset).to_owned()))},
+     Rope{tree: rest.push_front(Chunk(s.slice_from(offset).to_owned()))})
+  }
+  /// The text with s inserted at byte i.
+  pub fn insert_str(&self, i: uint, s: &str) -> Rope {
+    let (l, r) = self.split_at(i);
+    l.concat(&Rope::from_slice(s)).concat(&r)
+  }
+  /// The text from byte begin up to byte end.
+  pub fn slice(&self, begin: uint, end: uint) -> Rope {
+    assert!(begin <= end);
+    let (_, from_begin) = self.split_at(begin);
+    let (middle, _) = from_begin.split_at(end - begin);
+    middle
+  }
+  /// Iterate over the pieces of text the rope is made of, in order.
+  pub fn chunks<'t>(&'t self) -> Chunks<'t> {
+    Chunks{iter: self.tree.iter()}
+  }
+  /// The byte index of the first occurrence of needle, if any.
+  /// Searches chunk by chunk, never copying the text into one string:
+  /// the last needle.len() - 1 bytes of each chunk are carried over to
+  /// catch matches that span chunks.
+  pub fn find(&self, needle: &str) -> Option<uint> {
+    let needle = needle.as_bytes();
+    let n = needle.len();
+    if n == 0 {
+      return Some(0);
+    }
+    // The bytes just before the chunk that a match could start in.
+    let mut carry : ~[u8] = ~[];
+    let mut start = 0;
+    for chunk in self.chunks() {
+      let bytes = chunk.as_bytes();
+      let mut window = carry.clone();
+      window.push_all(bytes.slice_to(min(n - 1, bytes.len())));
+      match find_bytes(window, needle) {
+        Some(i) if i < carry.len() => return Some(start - carry.len() + i),
+        _ => ()
+      }
+      match find_bytes(bytes, needle) {
+        Some(i) => return Some(start + i),
+        None => ()
+      }
+      carry.push_all(bytes.slice_from(bytes.len() - min(n - 1, bytes.len())));
+      if carry.len() > n - 1 {
+        carry = carry.slice_from(carry.len() - (n - 1)).to_owned();
+      }
+      start += bytes.len();
+    }
+    None
+  }
+  /// The byte index of the last occurrence of needle, if any.
+  /// Searches chunk by chunk from the end, like find.
+  pub fn rfind(&self, needle: &str) -> Option<uint> {
+    let needle = needle.as_bytes();
+    let n = needle.len();
+    if n == 0 {
+      return Some(self.len());
+    }
+    // The bytes just after the chunk that a match could end in.
+    let mut carry : ~[u8] = ~[];
+    let mut start = self.len();
+    for chunk in self.tree.rev_iter() {
+      let &Chunk(ref s) = chunk;
+      let bytes = s.as_bytes();
+      start -= bytes.len();
+      let keep = min(n - 1, bytes.len());
+      let mut window = bytes.slice_from(bytes.len() - keep).to_owned();
+      window.push_all(carry);
+      match rfind_bytes(window, needle) {
+        Some(i) if i + n > keep => return Some(start + bytes.len() - keep + i),
+        _ => ()
+      }
+      match rfind_bytes(bytes, needle) {
+        Some(i) => return Some(start + i),
+        None => ()
+      }
+      let mut next = bytes.slice_to(keep).to_owned();
+      next.push_all(carry);
+      next.truncate(n - 1);
+      carry = next;
+    }
+    None
+  }
+}
+
+// Where needle first occurs in haystack.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+  if needle.len() > haystack.len() {
+    return None;
+  }
+  range(0, haystack.len() - needle.len() + 1).find(|&i| haystack.slice(i, i + needle.len()) == needle)
+}
+
+// Where needle last occurs in haystack.
+fn rfind_bytes(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+  if needle.len() > haystack.len() {
+    return None;
+  }
+  range(0, haystack.len() - needle.len() + 1).invert().find(|&i| haystack.slice(i, i + needle.len()) == needle)
+}
+
+impl Container for Rope {
+  /// The length in bytes.
+  fn len(&self) -> uint {
+    let Bytes(n) = self.tree.measure();
+    n
+  }
+}
+
+impl Default for Rope {
+  fn default() -> Rope {
+    Rope::new()
+  }
+}
+
+impl Eq for Rope {
+  fn eq(&self, other: &Rope) -> bool {
+    self.len() == other.len() &&
+      self.chunks().flat_map(|c| c.bytes()).zip(other.chunks().flat_map(|c| c.bytes())).all(|(a, b)| a == b)
+  }
+}
+
+impl fmt::Default for Rope {
+  fn fmt(rope: &Rope, f: &mut fmt::Formatter) {
+    for chunk in rope.chunks() {
+      write!(f.buf, "{}", chunk);
+    }
+  }
+}
+
+/// Iterator over the pieces of text a Rope is made of.
+pub struct Chunks<'self> {
+  priv iter : FingerTreeIterator<'self, Bytes, Chunk>
+}
+
+impl<'self> Iterator<&'self str> for Chunks<'self> {
+  fn next(&mut self) -> Option<&'self str> {
+    match self.iter.next() {
+      None => None,
+      Some(&Chunk(ref s)) => Some(s.as_slice())
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::Rope;
+#[test]
+fn test_rope() {
+  let r = Rope::from_slice("hello world");
+  let (a, b) = r.split_at(5);
+  assert!(format!("{}", a) == ~"hello" && format!("{}", b) == ~" world");
+  let edited = r.insert_str(5, ",");
+  assert!(format!("{}", edited) == ~"hello, world");
+  assert!(format!("{}", r) == ~"hello world");
+  assert!(edited.slice(7, 12) == Rope::from_slice("world"));
+  let (none, all) = r.split_at(0);
+  assert!(none.is_empty() && all == r);
+  let (all, none) = r.split_at(11);
+  assert!(all == r && none == Rope::new());
+  assert!(a.concat(&b) == r);
+}
+#[test]
+fn test_long_rope() {
+  let text = "αβγ".repeat(1000);
+  let r = Rope::from_slice(text);
+  assert!(r.len() == 6000 && r.chunks().count() > 1);
+  assert!(r.chunks().all(|c| c.len() > 0));
+  let (a, b) = r.split_at(3000);
+  assert!(format!("{}", a.concat(&b)) == text);
+  let middle = r.slice(1000, 5000);
+  assert!(format!("{}", middle) == text.slice(1000, 5000).to_owned());
+  let inserted = r.insert_str(2, "!");
+  assert!(inserted.len() == 6001 && inserted.slice(0, 3) == Rope::from_slice("α!"));
+}
+#[test]
+fn test_find() {
+  // Concat keeps the pieces as separate chunks.
+  let r = ["ab", "ra", "c", "ad", "abra"].iter().fold(Rope::new(), |r, s| r.concat(&Rope::from_slice(*s)));
+  assert!(r.chunks().count() == 5);
+  assert!(r.find("abra") == Some(0) && r.rfind("abra") == Some(7));
+  assert!(r.find("racad") == Some(2) && r.rfind("racad") == Some(2));
+  assert!(r.find("a") == Some(0) && r.rfind("a") == Some(10));
+  assert!(r.find("bra") == Some(1) && r.rfind("bra") == Some(8));
+  assert!(r.find("abracadabra") == Some(0) && r.rfind("abracadabra") == Some(0));
+  assert!(r.find("x").is_none() && r.rfind("abracadabrax").is_none());
+  assert!(r.find("") == Some(0) && r.rfind("") == Some(11));
+  assert!(Rope::new().find("a").is_none() && Rope::new().rfind("a").is_none());
+}
+#[test]
+fn test_find_across_chunks() {
+  let mut text = "a".repeat(510);
+  text.push_str("needle!");
+  text.push_str("b".repeat(1017));
+  text.push_str("needle!");
+  let r = Rope::from_slice(text);
+  // Each needle starts 2 bytes before the end of a full-sized chunk.
+  assert!(r.chunks().count() == 4 && r.chunks().next().unwrap().len() == 512);
+  assert!(r.find("needle!") == Some(510) && r.rfind("needle!") == Some(1534));
+  assert!(r.find("aneedle!b") == Some(509) && r.rfind("aneedle!b") == Some(509));
+  assert!(r.find("bneedle") == Some(1533) && r.rfind("needle!b") == Some(510));
+  assert!(r.find("needle!!").is_none() && r.rfind("a!").is_none());
+}
+#[test]
+fn test_find_random() {
+  use std::rand::{Rng, task_rng};
+  let mut rng = task_rng();
+  for _ in range(0, 200) {
+    let mut r = Rope::new();
+    for _ in range(0, rng.gen_range(0u, 8)) {
+      let len = rng.gen_range(1u, 5);
+      let piece : ~str = range(0, len).map(|_| if rng.gen() { 'a' } else { 'b' }).collect();
+      r = r.concat(&Rope::from_slice(piece));
+    }
+    let len = rng.gen_range(1u, 6);
+    let needle : ~str = range(0, len).map(|_| if rng.gen() { 'a' } else { 'b' }).collect();
+    let text = format!("{}", r);
+    let first = range(0, text.len() + 1).find(|i| text.slice_from(*i).starts_with(needle));
+    let last = range(0, text.len() + 1).invert().find(|i| text.slice_from(*i).starts_with(needle));
+    assert!(r.find(needle) == first && r.rfind(needle) == last);
+  }
+}
+#[test]
+#[should_fail]
+fn test_split_past_end() {
+  Rope::from_slice("abc").split_at(4);
+}
+}
+
+}
+
+pub mod unionfind {
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::util;
+use std::vec;
+
+/// Persistent union-find (disjoint sets of 0 .. n-1), after Conchon
+/// and Filliâtre: union and find make new versions, and the old ones
+/// can still be asked anything.
+///
+/// Underneath are "rerooting" arrays: the version used most recently
+/// owns a plain array, and the others are chains of differences from
+/// it, so using one version at a time (as a backtracking search does)
+/// costs about what a mutable union-find would.  Using an old version
+/// first costs time proportional to the changes since.
+pub struct UnionFind {
+  priv parent : PArray<uint>,
+  priv rank : PArray<uint>
+}
+
+// Cloning is just sharing.
+impl Clone for UnionFind {
+  fn clone(&self) -> UnionFind {
+    UnionFind{parent: self.parent.clone(), rank: self.rank.clone()}
+  }
+}
+
+impl UnionFind {
+  /// Create n singleton sets, {0}, {1}, ..., {n-1}.
+  pub fn new(n: uint) -> UnionFind {
+    UnionFind{parent: PArray::from_vec(range(0, n).collect()),
+              rank: PArray::from_vec(vec::from_elem(n, 0u))}
+  }
+  fn root_of(&self, x: uint) -> uint {
+    let mut root = x;
+    loop {
+      let p = self.parent.get(root);
+      if p == root {
+        return root;
+      }
+      root = p;
+    }
+  }
+  /// The representative of x's set, and a version of self
+  /// (with the same sets) that will find it faster next time.
+  pub fn find(&self, x: uint) -> (uint, UnionFind) {
+    let root = self.root_of(x);
+    let mut parent = self.parent.clone();
+    let mut y = x;
+    while y != root {
+      let p = parent.get(y);
+      if p != root {
+        parent = parent.set(y, root);
+      }
+      y = p;
+    }
+    (root, UnionFind{parent: parent, rank: self.rank.clone()})
+  }
+  /// Return true if x and y are in the same set.
+  pub fn connected(&self, x: uint, y: uint) -> bool {
+    self.root_of(x) == self.root_of(y)
+  }
+  /// Create a version where x's and y's sets are one set.
+  pub fn union(&self, x: uint, y: uint) -> UnionFind {
+    let (rx, uf) = self.find(x);
+    let (ry, uf) = uf.find(y);
+    if rx == ry {
+      return uf;
+    }
+    let (kx, ky) = (uf.rank.get(rx), uf.rank.get(ry));
+    if kx < ky {
+      UnionFind{parent: uf.parent.set(rx, ry), rank: uf.rank}
+    } else if kx > ky {
+      UnionFind{parent: uf.parent.set(ry, rx), rank: uf.rank}
+    } else {
+      UnionFind{parent: uf.parent.set(ry, rx), rank: uf.rank.set(rx, kx + 1)}
+    }
+  }
+}
+
+impl Container for UnionFind {
+  /// The number of members (not sets).
+  fn len(&self) -> uint {
+    self.parent.len()
+  }
+}
+
+// Persistent array that's fastest for the version used last.
+struct PArray<T> {
+  cell : Rc<RefCell<Data<T>>>
+}
+
+enum Data<T> {
+  // The version that owns the array.
+  Array(~[T]),
+  // The same as the other version, except at the index.
+  Diff(uint, T, PArray<T>),
+  // Only while rerooting.
+  Moved
+}
+
+impl<T> Clone for PArray<T> {
+  fn clone(&self) -> PArray<T> {
+    PArray{cell: self.cell.clone()}
+  }
+}
+
+impl<T: Clone> PArray<T> {
+  fn make(data: Data<T>) -> PArray<T> {
+    // RefCell isn't Freeze, so Rc::new won't do.  Versions only ever
+    // point towards the one with the array, and rerooting turns one
+    // link around at a time, so there are no cycles.
+    PArray{cell: unsafe { Rc::new_unchecked(RefCell::new(data)) }}
+  }
+  fn from_vec(v: ~[T]) -> PArray<T> {
+    PArray::make(Array(v))
+  }
+  fn take(&self) -> Data<T> {
+    util::replace(self.cell.borrow().borrow_mut().get(), Moved)
+  }
+  fn put(&self, data: Data<T>) {
+    *self.cell.borrow().borrow_mut().get() = data;
+  }
+  fn is_array(&self) -> bool {
+    match *self.cell.borrow().borrow().get() { Array(_) => true, _ => false }
+  }
+  // Make self the version that owns the array.
+  fn reroot(&self) {
+    if self.is_array() {
+      return;
+    }
+    let mut path = ~[self.clone()];
+    loop {
+      let next = match *path[path.len() - 1].cell.borrow().borrow().get() {
+        Diff(_, _, ref next) => next.clone(),
+        _ => break
+      };
+      path.push(next);
+    }
+    // path ends with the version that owns the array;
+    // move the array back along it.
+    for k in range(0, path.len() - 1).invert() {
+      let (i, v) = match path[k].take() {
+        Diff(i, v, _) => (i, v),
+        _ => fail!("PArray: broken version chain")
+      };
+      let mut array = match path[k + 1].take() {
+        Array(array) => array,
+        _ => fail!("PArray: broken version chain")
+      };
+      let old = util::replace(&mut array[i], v);
+      path[k + 1].put(Diff(i, old, path[k].clone()));
+      path[k].put(Array(array));
+    }
+  }
+  fn get(&self, i: uint) -> T {
+    self.reroot();
+    match *self.cell.borrow().borrow().get() {
+      Array(ref array) => array[i].clone(),
+      _ => fail!("PArray: reroot didn't")
+    }
+  }
+  fn set(&self, i: uint, v: T) -> PArray<T> {
+    self.reroot();
+    let mut array = match self.take() {
+      Array(array) => array,
+      _ => fail!("PArray: reroot didn't")
+    };
+    let old = util::replace(&mut array[i], v);
+    let result = PArray::make(Array(array));
+    self.put(Diff(i, old, result.clone()));
+    result
+  }
+  fn len(&self) -> uint {
+    self.reroot();
+    match *self.cell.borrow().borrow().get() {
+      Array(ref array) => array.len(),
+      _ => fail!("PArray: reroot didn't")
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::UnionFind;
+#[test]
+fn test_unionfind() {
+  let uf0 = UnionFind::new(10);
+  assert!(uf0.len() == 10 && !uf0.connected(1, 2));
+  let uf1 = uf0.union(1, 2).union(3, 4);
+  let uf2 = uf1.union(2, 4).union(7, 8);
+  assert!(uf2.connected(1, 3) && uf2.connected(8, 7) && !uf2.connected(1, 7));
+  // Older versions, in any order.
+  assert!(!uf0.connected(1, 2));
+  assert!(uf1.connected(3, 4) && !uf1.connected(1, 3));
+  assert!(uf2.connected(4, 1));
+  let (r, uf3) = uf2.find(3);
+  assert!(uf2.connected(r, 1) && uf3.connected(r, 2));
+  let (r4, _) = uf3.find(4);
+  assert!(r4 == r);
+  let branch = uf1.union(5, 6);
+  assert!(branch.connected(5, 6) && !uf2.connected(5, 6) && !branch.connected(1, 3));
+}
+#[test]
+fn test_long_chain() {
+  let mut uf = UnionFind::new(1000);
+  let mut versions = ~[];
+  for i in range(1u, 1000) {
+    versions.push(uf.clone());
+    uf = uf.union(i - 1, i);
+  }
+  assert!(uf.connected(0, 999));
+  assert!(versions[500].connected(0, 499) && !versions[500].connected(0, 500));
+  assert!(versions[0].len() == 1000 && !versions[0].connected(0, 1));
+  assert!(uf.connected(999, 0));
+}
+}
+
+}
+
+pub mod bag {
+
+use std::cmp::min;
+use persistent::treemap::{TreeMap, Entries};
+
+/// Persistent multiset: a TreeMap from each member to how many times
+/// it's in the bag.  len() counts members with their multiplicities.
+pub struct Bag<T> {
+  priv counts : TreeMap<T, uint>,
+  priv len : uint
+}
+
+impl<T> Clone for Bag<T> {
+  fn clone(&self) -> Bag<T> {
+    Bag{counts: self.counts.clone(), len: self.len}
+  }
+}
+
+impl<T: TotalOrd> Bag<T> {
+  /// Create an empty bag.
+  pub fn new() -> Bag<T> {
+    Bag{counts: TreeMap::new(), len: 0}
+  }
+  /// How many times x is in the bag.
+  pub fn count(&self, x: &T) -> uint {
+    match self.counts.find(x) { None => 0, Some(n) => *n }
+  }
+}
+
+impl<T: TotalOrd+Clone+Freeze> Bag<T> {
+  /// Create a bag with x in it n more times than self has.
+  pub fn insert_n(&self, x: T, n: uint) -> Bag<T> {
+    if n == 0 {
+      return self.clone();
+    }
+    let c = self.count(&x);
+    Bag{counts: self.counts.insert(x, c + n), len: self.len + n}
+  }
+  /// Create a bag with x in it once more than self has.
+  pub fn insert(&self, x: T) -> Bag<T> {
+    self.insert_n(x, 1)
+  }
+  /// Create a bag with x in it n fewer times than self has
+  /// (or not at all, if self doesn't have it more than n times).
+  pub fn remove_n(&self, x: &T, n: uint) -> Bag<T> {
+    let c = self.count(x);
+    if c == 0 || n == 0 {
+      self.clone()
+    } else if c <= n {
+      Bag{counts: self.counts.remove(x), len: self.len - c}
+    } else {
+      Bag{counts: self.counts.insert(x.clone(), c - n), len: self.len - n}
+    }
+  }
+  /// Create a bag with x in it once fewer than self has.
+  pub fn remove_one(&self, x: &T) -> Bag<T> {
+    self.remove_n(x, 1)
+  }
+  /// Create a bag with everything in both bags, the multiplicities added.
+  pub fn sum(&self, other: &Bag<T>) -> Bag<T> {
+    let (big, small) = if self.counts.len() >= other.counts.len() { (self, other) } else { (other, self) };
+    let mut result = big.clone();
+    for (x, n) in small.iter() {
+      result = result.insert_n(x.clone(), n);
+    }
+    result
+  }
+  /// Create a bag of self's members, each in it as many fewer times
+  /// as it is in other (or not at all, if other has it as many times).
+  pub fn difference(&self, other: &Bag<T>) -> Bag<T> {
+    let mut result = self.clone();
+    if self.counts.len() <= other.counts.len() {
+      for (x, n) in self.iter() {
+        result = result.remove_n(x, min(n, other.count(x)));
+      }
+    } else {
+      for (x, n) in other.iter() {
+        result = result.remove_n(x, n);
+      }
+    }
+    result
+  }
+}
+
+impl<T> Bag<T> {
+  /// Iterate over the distinct members in order, with how many times
+  /// each is in the bag.
+  pub fn iter<'t>(&'t self) -> BagItems<'t, T> {
+    BagItems{entries: self.counts.iter()}
+  }
+  /// The number of distinct members.
+  pub fn distinct_len(&self) -> uint {
+    self.counts.len()
+  }
+}
+
+impl<T> Container for Bag<T> {
+  fn len(&self) -> uint {
+    self.len
+  }
+}
+
+impl<T: TotalOrd> Eq for Bag<T> {
+  fn eq(&self, other: &Bag<T>) -> bool {
+    self.len == other.len && self.counts == other.counts
+  }
+}
+
+impl<T: TotalOrd> Default for Bag<T> {
+  fn default() -> Bag<T> {
+    Bag::new()
+  }
+}
+
+impl<A: TotalOrd+Clone+Freeze> FromIterator<A> for Bag<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> Bag<A> {
+    let mut bag = Bag::new();
+    for x in *iter {
+      bag = bag.insert(x);
+    }
+    bag
+  }
+}
+
+/// Iterator over a Bag's distinct members, in order, with their counts.
+pub struct BagItems<'self, T> {
+  priv entries : Entries<'self, T, uint>
+}
+
+impl<'self, T> Iterator<(&'self T, uint)> for BagItems<'self, T> {
+  fn next(&mut self) -> Option<(&'self T, uint)> {
+    match self.entries.next() {
+      None => None,
+      Some((x, n)) => Some((x, *n))
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::Bag;
+#[test]
+fn test_bag() {
+  let b0 : Bag<char> = Bag::new();
+  let b1 : Bag<char> = "mississippi".chars().collect();
+  assert!(b1.len() == 11 && b1.distinct_len() == 4);
+  assert!(b1.count(&'s') == 4 && b1.count(&'m') == 1 && b1.count(&'z') == 0);
+  let b2 = b1.remove_one(&'m').remove_one(&'s').remove_one(&'z');
+  assert!(b2.len() == 9 && b2.count(&'m') == 0 && b2.count(&'s') == 3);
+  assert!(b1.count(&'m') == 1);
+  let counts : ~[(char, uint)] = b2.iter().map(|(c, n)| (*c, n)).collect();
+  assert!(counts == ~[('i', 4), ('p', 2), ('s', 3)]);
+  let sum = b1.sum(&b2);
+  assert!(sum.len() == 20 && sum.count(&'s') == 7 && sum.count(&'m') == 1);
+  assert!(sum.difference(&b2) == b1);
+  assert!(b2.difference(&b1) == b0);
+  assert!(b1.difference(&b0) == b1 && b0.sum(&b1) == b1);
+  assert!(b1.insert_n('q', 3).remove_n(&'q', 5) == b1);
+}
+}
+
+}
+
+pub mod intervalmap {
+
+use persistent::fingertree::{FingerTree, FingerTreeIterator, Monoid, Measured};
+
+/// Persistent map from half-open intervals [lo, hi) to values, a
+/// finger tree ordered by lo and annotated with the greatest hi
+/// (Hinze and Paterson): insert is O(log n), and a query takes
+/// O(log n) per interval found.  Intervals may overlap or repeat.
+pub struct IntervalMap<K, V> {
+  priv tree : FingerTree<Bounds<K>, Entry<K, V>>
+}
+
+struct Entry<K, V> {
+  lo : K,
+  hi : K,
+  value : V
+}
+
+// The greatest lo (which, the entries being ordered by lo, is the
+// last one's), the greatest hi, and how many entries.
+#[deriving(Clone)]
+struct Bounds<K> {
+  max_lo : Option<K>,
+  max_hi : Option<K>,
+  count : uint
+}
+
+fn greater<K: TotalOrd+Clone>(a: &Option<K>, b: &Option<K>) -> Option<K> {
+  match (a, b) {
+    (&None, _) => b.clone(),
+    (_, &None) => a.clone(),
+    (&Some(ref x), &Some(ref y)) => Some(if x.cmp(y) == Less { y.clone() } else { x.clone() })
+  }
+}
+
+// m >= k, with None as less than anything.
+fn at_least<K: TotalOrd>(m: &Option<K>, k: &K) -> bool {
+  match *m { None => false, Some(ref x) => x.cmp(k) != Less }
+}
+
+// m > k, with None as less than anything.
+fn above<K: TotalOrd>(m: &Option<K>, k: &K) -> bool {
+  match *m { None => false, Some(ref x) => x.cmp(k) == Greater }
+}
+
+impl<K: TotalOrd+Clone> Monoid for Bounds<K> {
+  fn zero() -> Bounds<K> {
+    Bounds{max_lo: None, max_hi: None, count: 0}
+  }
+  fn plus(&self, other: &Bounds<K>) -> Bounds<K> {
+    Bounds{max_lo: greater(&self.max_lo, &other.max_lo),
+           max_hi: greater(&self.max_hi, &other.max_hi),
+           count: self.count + other.count}
+  }
+}
+
+impl<K: TotalOrd+Clone, V> Measured<Bounds<K>> for Entry<K, V> {
+  fn measure(&self) -> Bounds<K> {
+    Bounds{max_lo: Some(self.lo.clone()), max_hi: Some(self.hi.clone()), count: 1}
+  }
+}
+
+impl<K, V> Clone for IntervalMap<K, V> {
+  fn clone(&self) -> IntervalMap<K, V> {
+    IntervalMap{tree: self.tree.clone()}
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> IntervalMap<K, V> {
+  /// Create an empty map.
+  pub fn new() -> IntervalMap<K, V> {
+    IntervalMap{tree: FingerTree::new()}
+  }
+  /// Create a map with everything self has, and [lo, hi) mapped to value.
+  /// Fails if hi < lo.
+  pub fn insert(&self, lo: K, hi: K, value: V) -> IntervalMap<K, V> {
+    assert!(lo.cmp(&hi) != Greater);
+    let (before, after) = self.tree.split(|b| at_least(&b.max_lo, &lo));
+    let entry = Entry{lo: lo, hi: hi, value: value};
+    IntervalMap{tree: before.push_back(entry).concat(&after)}
+  }
+  /// The (lo, hi, value) of every interval that contains point,
+  /// in order of lo.
+  pub fn find_containing(&self, point: &K) -> ~[(K, K, V)] {
+    let (starting_by, _) = self.tree.split(|b| above(&b.max_lo, point));
+    ending_after(starting_by, point)
+  }
+  /// The (lo, hi, value) of every interval that overlaps [lo, hi),
+  /// in order of lo.
+  pub fn find_overlapping(&self, lo: &K, hi: &K) -> ~[(K, K, V)] {
+    let (starting_before, _) = self.tree.split(|b| at_least(&b.max_lo, hi));
+    ending_after(starting_before, lo)
+  }
+}
+
+// The entries of t whose hi is greater than k.
+fn ending_after<K: TotalOrd+Clone+Freeze, V: Clone+Freeze>(
+    t: FingerTree<Bounds<K>, Entry<K, V>>, k: &K) -> ~[(K, K, V)] {
+  let mut result = ~[];
+  let mut rest = t;
+  while above(&rest.measure().max_hi, k) {
+    let (_, found) = rest.split(|b| above(&b.max_hi, k));
+    {
+      let e = found.front().unwrap();
+      result.push((e.lo.clone(), e.hi.clone(), e.value.clone()));
+    }
+    rest = found.pop_front().unwrap();
+  }
+  result
+}
+
+impl<K, V> IntervalMap<K, V> {
+  /// Iterate over the (lo, hi, value) of each interval, in order of lo.
+  pub fn iter<'t>(&'t self) -> Intervals<'t, K, V> {
+    Intervals{iter: self.tree.iter()}
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> Container for IntervalMap<K, V> {
+  fn len(&self) -> uint {
+    self.tree.measure().count
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> Default for IntervalMap<K, V> {
+  fn default() -> IntervalMap<K, V> {
+    IntervalMap::new()
+  }
+}
+
+/// Iterator over an IntervalMap's intervals, in order of lo.
+pub struct Intervals<'self, K, V> {
+  priv iter : FingerTreeIterator<'self, Bounds<K>, Entry<K, V>>
+}
+
+impl<'self, K, V> Iterator<(&'self K, &'self K, &'self V)> for Intervals<'self, K, V> {
+  fn next(&mut self) -> Option<(&'self K, &'self K, &'self V)> {
+    match self.iter.next() {
+      None => None,
+      Some(e) => Some((&e.lo, &e.hi, &e.value))
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::IntervalMap;
+#[test]
+fn test_intervalmap() {
+  let m0 : IntervalMap<int, &'static str> = IntervalMap::new();
+  let m = m0.insert(10, 20, "a").insert(0, 5, "b").insert(15, 30, "c").insert(3, 12, "d");
+  assert!(m.len() == 4 && m0.is_empty());
+  assert!(m.find_containing(&11) == ~[(3, 12, "d"), (10, 20, "a")]);
+  assert!(m.find_containing(&20) == ~[(15, 30, "c")]);
+  assert!(m.find_containing(&30).is_empty() && m.find_containing(&-1).is_empty());
+  assert!(m.find_overlapping(&4, &10) == ~[(0, 5, "b"), (3, 12, "d")]);
+  assert!(m.find_overlapping(&-5, &100).len() == 4);
+  let los : ~[int] = m.iter().map(|(lo, _, _)| *lo).collect();
+  assert!(los == ~[0, 3, 10, 15]);
+  let m2 = m.insert(11, 11, "empty");
+  assert!(m2.find_containing(&11).len() == 2 && m.len() == 4);
+}
+#[test]
+fn test_many_intervals() {
+  let mut m = IntervalMap::new();
+  for i in range(0, 500) {
+    m = m.insert(i, i + 10, i);
+  }
+  let found = m.find_containing(&250);
+  assert!(found.len() == 10);
+  assert!(found.iter().all(|&(lo, hi, v)| lo <= 250 && 250 < hi && v == lo));
+}
+}
+
+}
+
+pub mod interned {
+
+use std::hashmap::HashMap;
+use std::ptr;
+use persistent::list::List;
+
+/// Hash-consing for Lists: an Interner hands out lists in which
+/// structurally equal sublists are the same nodes, so that equal lists
+/// (from the same interner) share memory and are ptr_eq.
+///
+/// The table holds its nodes strongly (Rc has no weak references),
+/// so everything interned lives as long as the Interner does; drop
+/// or clear it to let go.
+pub struct Interner<T> {
+  priv nil : List<T>,
+  // (head, address of the interned tail's node) -> the interned list.
+  priv table : HashMap<(T, uint), List<T>>
+}
+
+fn address<T>(list: &List<T>) -> uint {
+  ptr::to_unsafe_ptr(list.node()) as uint
+}
+
+impl<T: IterBytes+Eq+Clone+Freeze> Interner<T> {
+  /// Create an interner with nothing in it yet.
+  pub fn new() -> Interner<T> {
+    Interner{nil: List::nil(), table: HashMap::new()}
+  }
+  /// The interned empty list.
+  pub fn nil(&self) -> List<T> {
+    self.nil.clone()
+  }
+  /// The interned list of x followed by xs, which has to have come
+  /// from this interner.  O(1) expected time; only allocates if it's
+  /// a list the interner hasn't seen.
+  pub fn cons(&mut self, x: T, xs: &List<T>) -> List<T> {
+    let key = (x, address(xs));
+    self.table.find_or_insert_with(key, |&(ref x, _)| List::cons(x.clone(), xs.clone())).clone()
+  }
+  /// The interned list with the same members as list (which can come
+  /// from anywhere).
+  pub fn intern(&mut self, list: &List<T>) -> List<T> {
+    let mut result = self.nil();
+    for x in list.rev_iter() {
+      result = self.cons(x.clone(), &result);
+    }
+    result
+  }
+  /// Let go of everything interned so far.  Lists interned before
+  /// this and after it are no longer shared with each other.
+  pub fn clear(&mut self) {
+    self.table.clear();
+    self.nil = List::nil();
+  }
+}
+
+impl<T: IterBytes+Eq> Container for Interner<T> {
+  /// The number of distinct non-empty lists interned.
+  fn len(&self) -> uint {
+    self.table.len()
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::Interner;
+use persistent::list::List;
+#[test]
+fn test_interner() {
+  let mut interner = Interner::new();
+  let a = interner.intern(&List::from_vec(~[1, 2, 3]));
+  let b = interner.intern(&List::from_vec(~[0, 2, 3]));
+  assert!(interner.len() == 4);
+  assert!(a.drop(1).ptr_eq(&b.drop(1)));
+  let nil = interner.nil();
+  let three = interner.cons(3, &nil);
+  let two_three = interner.cons(2, &three);
+  let again = interner.cons(1, &two_three);
+  assert!(again.ptr_eq(&a) && again == List::from_vec(~[1, 2, 3]));
+  assert!(interner.len() == 4);
+  assert!(interner.intern(&List::nil()).ptr_eq(&nil));
+  interner.clear();
+  assert!(interner.is_empty() && !interner.intern(&a).ptr_eq(&a));
+}
+}
+
+}
+
+/**
+Random structures, for property testing: Rand for the persistent
+types (so `rng.gen()` makes them), Shrink for making a failing case
+smaller, and quickcheck to put them together.  Code built on these
+structures can test itself with them too.
+*/
+pub mod arbitrary {
+
+use std::rand::{Rand, Rng, task_rng};
+use persistent::list::{List, ListBuilder};
+use persistent::vector::{Vector, VectorBuilder};
+use persistent::treemap::{TreeMap, TreeMapBuilder};
+
+// Random structures have at most this many members.
+static MAX_RANDOM_LEN : uint = 20;
+
+impl<T: Rand+Freeze> Rand for List<T> {
+  fn rand<R: Rng>(rng: &mut R) -> List<T> {
+    let mut builder = ListBuilder::new();
+    for _ in range(0, rng.gen_range(0, MAX_RANDOM_LEN + 1)) {
+      builder.push(rng.gen());
+    }
+    builder.finish()
+  }
+}
+
+impl<T: Rand+Freeze> Rand for Vector<T> {
+  fn rand<R: Rng>(rng: &mut R) -> Vector<T> {
+    let mut builder = VectorBuilder::new();
+    for _ in range(0, rng.gen_range(0, MAX_RANDOM_LEN + 1)) {
+      builder.push(rng.gen());
+    }
+    builder.finish()
+  }
+}
+
+impl<K: Rand+TotalOrd+Clone+Freeze, V: Rand+Clone+Freeze> Rand for TreeMap<K, V> {
+  fn rand<R: Rng>(rng: &mut R) -> TreeMap<K, V> {
+    let mut builder = TreeMapBuilder::new();
+    for _ in range(0, rng.gen_range(0, MAX_RANDOM_LEN + 1)) {
+      builder.insert(rng.gen(), rng.gen());
+    }
+    builder.finish()
+  }
+}
+
+/// Values that can be made smaller, to simplify a counterexample.
+pub trait Shrink {
+  /// Values a bit smaller than self (none, if it's as small as it gets).
+  fn shrink(&self) -> ~[Self];
+}
+
+impl Shrink for int {
+  fn shrink(&self) -> ~[int] {
+    if *self == 0 { ~[] } else if *self / 2 == 0 { ~[0] } else { ~[0, *self / 2] }
+  }
+}
+
+impl Shrink for uint {
+  fn shrink(&self) -> ~[uint] {
+    if *self == 0 { ~[] } else if *self / 2 == 0 { ~[0] } else { ~[0, *self / 2] }
+  }
+}
+
+impl<T: Clone+Freeze> Shrink for List<T> {
+  /// The empty list, and the list without each one of its members.
+  fn shrink(&self) -> ~[List<T>] {
+    if self.is_empty() {
+      return ~[];
+    }
+    let mut smaller = ~[List::nil()];
+    for i in range(0, self.len()) {
+      let (before, after) = self.split_at(i);
+      smaller.push(before.append(&after.drop(1)));
+    }
+    smaller
+  }
+}
+
+impl<T: Clone+Freeze> Shrink for Vector<T> {
+  /// The empty vector, and the vector without each one of its members.
+  fn shrink(&self) -> ~[Vector<T>] {
+    if self.is_empty() {
+      return ~[];
+    }
+    let mut smaller = ~[Vector::new()];
+    for i in range(0, self.len()) {
+      smaller.push(self.iter().enumerate().filter(|&(j, _)| j != i).map(|(_, x)| x.clone()).collect());
+    }
+    smaller
+  }
+}
+
+impl<K: TotalOrd+Clone+Freeze, V: Clone+Freeze> Shrink for TreeMap<K, V> {
+  /// The empty map, and the map without each one of its keys.
+  fn shrink(&self) -> ~[TreeMap<K, V>] {
+    if self.is_empty() {
+      return ~[];
+    }
+    let mut smaller = ~[TreeMap::new()];
+    for (k, _) in self.iter() {
+      smaller.push(self.remove(k));
+    }
+    smaller
+  }
+}
+
+/// Try prop on the given number of random values.  None if it held
+/// for all of them; otherwise a counterexample, shrunk as far as it
+/// will go while still failing.
+pub fn quickcheck<T: Rand+Shrink>(tests: uint, prop: |&T| -> bool) -> Option<T> {
+  let mut rng = task_rng();
+  for _ in range(0, tests) {
+    let x : T = rng.gen();
+    if !prop(&x) {
+      let mut smallest = x;
+      loop {
+        let mut smaller = None;
+        for candidate in smallest.shrink().move_iter() {
+          if !prop(&candidate) {
+            smaller = Some(candidate);
+            break;
+          }
+        }
+        match smaller {
+          None => return Some(smallest),
+          Some(s) => smallest = s
+        }
+      }
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod test {
+use super::{quickcheck, Shrink};
+use persistent::list::List;
+use persistent::vector::Vector;
+use persistent::treemap::TreeMap;
+use std::hash::Hash;
+
+#[test]
+fn test_reverse_reverse() {
+  assert!(quickcheck(200, |l: &List<int>| l.reverse().reverse() == *l).is_none());
+}
+
+#[test]
+fn test_old_versions_unchanged() {
+  assert!(quickcheck(200, |l: &List<int>| {
+    let before = l.to_vec();
+    let longer = List::cons(7, l.clone());
+    let _ = (longer.filter(|x| *x > 0), l.append(&longer), l.take(3));
+    l.to_vec() == before && longer.drop(1) == *l
+  }).is_none());
+  assert!(quickcheck(200, |v: &Vector<int>| {
+    let before : ~[int] = v.iter().map(|x| *x).collect();
+    let w = v.push(1);
+    let _ = if v.is_empty() { w.clone() } else { v.set(0, 99) };
+    let after : ~[int] = v.iter().map(|x| *x).collect();
+    after == before && w.len() == v.len() + 1
+  }).is_none());
+  assert!(quickcheck(200, |m: &TreeMap<int, int>| {
+    let before : ~[(int, int)] = m.iter().map(|(k, v)| (*k, *v)).collect();
+    let m2 = m.insert(7, 7).remove(&3);
+    let after : ~[(int, int)] = m.iter().map(|(k, v)| (*k, *v)).collect();
+    after == before && m2.find(&7) == Some(&7) && !m2.contains_key(&3)
+  }).is_none());
+}
+
+#[test]
+fn test_equal_lists_hash_equal() {
+  assert!(quickcheck(200, |l: &List<int>| {
+    let rebuilt = List::from_vec(l.to_vec());
+    rebuilt == *l && !rebuilt.ptr_eq(l) && rebuilt.hash() == l.hash()
+  }).is_none());
+}
+
+#[test]
+fn test_shrinking() {
+  let counterexample = quickcheck(200, |l: &List<int>| l.len() < 3);
+  assert!(counterexample.unwrap().len() == 3);
+  assert!(List::from_vec(~[1, 2]).shrink() ==
+          ~[List::nil(), List::from_vec(~[2]), List::from_vec(~[1])]);
+}
+}
+
+}
+
+/**
+Undo and redo over versions of any value, meant for the persistent
+structures: every committed version is kept, which costs little when
+the versions share most of their structure.
+*/
+pub mod history {
+
+/// Names a version in a History.
+#[deriving(Clone, Eq)]
+pub struct VersionId(uint);
+
+/// Every version committed so far, and which of them is current.
+/// Versions form a tree: committing after an undo starts a new
+/// branch, but the undone versions are still there for checkout.
+pub struct History<T> {
+  priv versions : ~[T],
+  // The version each one was committed on top of.
+  priv parents : ~[Option<uint>],
+  priv current : uint,
+  // Versions that undo moved away from, the next to redo last.
+  priv undone : ~[uint]
+}
+
+impl<T> History<T> {
+  /// Start a history whose first version is initial.
+  pub fn new(initial: T) -> History<T> {
+    History{versions: ~[initial], parents: ~[None], current: 0, undone: ~[]}
+  }
+  /// The current version.
+  pub fn current<'t>(&'t self) -> &'t T {
+    &self.versions[self.current]
+  }
+  /// Which version is current.
+  pub fn current_id(&self) -> VersionId {
+    VersionId(self.current)
+  }
+  /// The version with id, if it's in this history.
+  pub fn get<'t>(&'t self, id: VersionId) -> Option<&'t T> {
+    let VersionId(i) = id;
+    if i < self.versions.len() { Some(&self.versions[i]) } else { None }
+  }
+  /// Make version the current one, a child of the one that was current.
+  /// There is nothing left to redo after this.
+  pub fn commit(&mut self, version: T) -> VersionId {
+    self.versions.push(version);
+    self.parents.push(Some(self.current));
+    self.current = self.versions.len() - 1;
+    self.undone.truncate(0);
+    VersionId(self.current)
+  }
+  /// Go back to the version the current one was committed on top of.
+  /// False (and nothing changes) if the current version is the first.
+  pub fn undo(&mut self) -> bool {
+    match self.parents[self.current] {
+      None => false,
+      Some(parent) => {
+        self.undone.push(self.current);
+        self.current = parent;
+        true
+      }
+    }
+  }
+  /// Go forward to the version the last undo went back from.
+  /// False (and nothing changes) if there's nothing to redo.
+  pub fn redo(&mut self) -> bool {
+    let n = self.undone.len();
+    if n == 0 {
+      return false;
+    }
+    self.current = self.undone[n - 1];
+    self.undone.truncate(n - 1);
+    true
+  }
+  /// Make the version with id current (there's nothing to redo after
+  /// this).  Fails if id isn't in this history.
+  pub fn checkout(&mut self, id: VersionId) {
+    let VersionId(i) = id;
+    if i >= self.versions.len() {
+      fail!("History::checkout: no version {}", i);
+    }
+    self.current = i;
+    self.undone.truncate(0);
+  }
+}
+
+impl<T> Container for History<T> {
+  /// How many versions there are (undone ones included).
+  fn len(&self) -> uint {
+    self.versions.len()
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::History;
+use persistent::list::List;
+use persistent::treemap::TreeMap;
+
+#[test]
+fn test_undo_redo() {
+  let p0 = List::nil();
+  let p1 = List::cons(1, p0.clone());
+  let mut h = History::new(p0);
+  let first = h.current_id();
+  let one = h.commit(p1.clone());
+  h.commit(List::cons(2, p1.clone()));
+  assert!(*h.current() == List::from_vec(~[2, 1]));
+  assert!(h.undo() && *h.current() == List::from_vec(~[1]));
+  assert!(h.undo() && h.current().is_empty() && !h.undo());
+  assert!(h.redo() && h.current_id() == one);
+  h.commit(List::cons(3, p1));
+  assert!(!h.redo() && *h.current() == List::from_vec(~[3, 1]) && h.len() == 4);
+  h.checkout(first);
+  assert!(h.current().is_empty() && h.get(one) == Some(&List::from_vec(~[1])));
+}
+
+#[test]
+fn test_many_versions_share() {
+  let mut h = History::new(TreeMap::new());
+  let mut ids = ~[];
+  for i in range(0u, 300) {
+    let next = h.current().insert(i % 50, i);
+    ids.push(h.commit(next));
+  }
+  assert!(h.len() == 301 && h.current().len() == 50);
+  assert!(h.undo() && h.current().find(&48) == Some(&298) && h.current().find(&49) == Some(&249));
+  // Consecutive versions differ by one path through the tree.
+  let older = h.get(ids[298]).unwrap();
+  let newer = h.get(ids[299]).unwrap();
+  assert!(newer.shared_node_count_with(older) >= 40);
+}
+
+#[test]
+#[should_fail]
+fn test_checkout_missing() {
+  use super::VersionId;
+  let mut h = History::new(0);
+  h.checkout(VersionId(1));
+}
+}
+
+}
+
+pub mod buffer {
+
+use persistent::rope::Rope;
+use persistent::history::{History, VersionId};
+
+/// Editable text that remembers every version of itself, for undo and
+/// redo.  Each version is a Rope sharing all but a few chunks with the
+/// one before it, so keeping them all costs about the size of the edits.
+///
+/// Edits made between begin_transaction and end_transaction become one
+/// version, which undo takes back all at once.
+pub struct UndoableBuffer {
+  priv history : History<Rope>,
+  // The text with the open transaction's edits so far, if it has any.
+  priv pending : Option<Rope>,
+  // How many begin_transactions haven't been ended yet.
+  priv depth : uint
+}
+
+impl UndoableBuffer {
+  /// Create an empty buffer.
+  pub fn new() -> UndoableBuffer {
+    UndoableBuffer::from_rope(Rope::new())
+  }
+  /// Create a buffer whose first version is the text of s.
+  pub fn from_slice(s: &str) -> UndoableBuffer {
+    UndoableBuffer::from_rope(Rope::from_slice(s))
+  }
+  /// Create a buffer whose first version is text.
+  pub fn from_rope(text: Rope) -> UndoableBuffer {
+    UndoableBuffer{history: History::new(text), pending: None, depth: 0}
+  }
+  /// The text, with the edits of any open transaction.
+  pub fn text<'t>(&'t self) -> &'t Rope {
+    match self.pending {
+      Some(ref text) => text,
+      None => self.history.current()
+    }
+  }
+  /// Replace the text from byte begin up to byte end with text
+  /// (so begin == end inserts, and an empty text deletes).
+  /// Fails if the range is backwards, past the end, or not on char
+  /// boundaries.
+  pub fn edit(&mut self, begin: uint, end: uint, text: &str) {
+    assert!(begin <= end);
+    let edited = {
+      let (before, rest) = self.text().split_at(begin);
+      let (_, after) = rest.split_at(end - begin);
+      before.concat(&after).insert_str(begin, text)
+    };
+    if self.depth > 0 {
+      self.pending = Some(edited);
+    } else {
+      self.history.commit(edited);
+    }
+  }
+  /// Start grouping edits into one version.  Transactions nest: the
+  /// version is made when the outermost one ends.
+  pub fn begin_transaction(&mut self) {
+    self.depth += 1;
+  }
+  /// End the innermost transaction.  Fails if none is open.
+  pub fn end_transaction(&mut self) {
+    if self.depth == 0 {
+      fail!("UndoableBuffer::end_transaction: no transaction is open");
+    }
+    self.depth -= 1;
+    if self.depth == 0 {
+      match self.pending.take() {
+        None => (),
+        Some(text) => { self.history.commit(text); }
+      }
+    }
+  }
+  /// Go back to the text before the last edit or transaction.
+  /// False (and nothing changes) if there's nothing to undo.
+  /// Fails in a transaction.
+  pub fn undo(&mut self) -> bool {
+    self.not_in_transaction("undo");
+    self.history.undo()
+  }
+  /// Go forward to the text the last undo went back from.
+  /// False (and nothing changes) if there's nothing to redo.
+  /// Fails in a transaction.
+  pub fn redo(&mut self) -> bool {
+    self.not_in_transaction("redo");
+    self.history.redo()
+  }
+  /// Which version of the text is current.
+  pub fn version(&self) -> VersionId {
+    self.history.current_id()
+  }
+  /// The version of the text with id, if there is one, including ones
+  /// on branches that undo-then-edit left behind.
+  pub fn get<'t>(&'t self, id: VersionId) -> Option<&'t Rope> {
+    self.history.get(id)
+  }
+  /// Make the version with id current.  Fails if there's no such
+  /// version, or in a transaction.
+  pub fn checkout(&mut self, id: VersionId) {
+    self.not_in_transaction("checkout");
+    self.history.checkout(id)
+  }
+  fn not_in_transaction(&self, what: &str) {
+    if self.depth > 0 {
+      fail!("UndoableBuffer::{}: a transaction is open", what);
+    }
+  }
+}
+
+impl Container for UndoableBuffer {
+  /// The length of the text in bytes.
+  fn len(&self) -> uint {
+    self.text().len()
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::UndoableBuffer;
+use persistent::rope::Rope;
+
+fn text(b: &UndoableBuffer) -> ~str {
+  format!("{}", *b.text())
+}
+
+#[test]
+fn test_edit_undo_redo() {
+  let mut b = UndoableBuffer::from_slice("hello world");
+  let first = b.version();
+  b.edit(5, 5, ",");
+  b.edit(7, 12, "there");
+  let second = b.version();
+  b.edit(0, 0, "oh, ");
+  assert!(text(&b) == ~"oh, hello, there");
+  assert!(b.undo() && text(&b) == ~"hello, there");
+  assert!(b.undo() && b.undo() && text(&b) == ~"hello world" && !b.undo());
+  assert!(b.redo() && b.redo() && b.version() == second && text(&b) == ~"hello, there");
+  // Old versions are still there, unchanged.
+  assert!(*b.get(first).unwrap() == Rope::from_slice("hello world"));
+  b.edit(0, 12, "");
+  assert!(b.is_empty() && *b.get(second).unwrap() == Rope::from_slice("hello, there"));
+}
+
+#[test]
+fn test_undo_across_branch() {
+  let mut b = UndoableBuffer::from_slice("a");
+  b.edit(1, 1, "b");
+  b.edit(2, 2, "c");
+  let abc = b.version();
+  assert!(b.undo() && text(&b) == ~"ab");
+  // Editing after undo starts a new branch: nothing to redo...
+  b.edit(2, 2, "x");
+  assert!(!b.redo() && text(&b) == ~"abx");
+  // ...and undo goes back through the branch point.
+  assert!(b.undo() && text(&b) == ~"ab");
+  assert!(b.undo() && text(&b) == ~"a");
+  // The abandoned branch can still be checked out.
+  b.checkout(abc);
+  assert!(text(&b) == ~"abc");
+  assert!(b.undo() && text(&b) == ~"ab");
+}
+
+#[test]
+fn test_transaction() {
+  let mut b = UndoableBuffer::from_slice("one two");
+  b.begin_transaction();
+  b.edit(0, 3, "1");
+  b.begin_transaction();
+  b.edit(2, 5, "2");
+  b.end_transaction();
+  // Still open, so still one version.
+  assert!(text(&b) == ~"1 2" && b.get(b.version()) == Some(&Rope::from_slice("one two")));
+  b.end_transaction();
+  assert!(text(&b) == ~"1 2");
+  assert!(b.undo() && text(&b) == ~"one two" && !b.undo());
+  // An empty transaction makes no version.
+  b.begin_transaction();
+  b.end_transaction();
+  assert!(!b.undo() && b.redo() && text(&b) == ~"1 2");
+}
+
+#[test]
+#[should_fail]
+fn test_undo_in_transaction() {
+  let mut b = UndoableBuffer::from_slice("abc");
+  b.begin_transaction();
+  b.edit(0, 1, "");
+  b.undo();
+}
+
+#[test]
+fn test_long_text() {
+  let original = "0123456789".repeat(1000);
+  let mut b = UndoableBuffer::from_slice(original);
+  for i in range(0u, 100) {
+    b.edit(i * 100, i * 100 + 1, "x");
+  }
+  assert!(b.len() == 10000 && text(&b).char_at(500) == 'x' && text(&b).char_at(501) == '1');
+  for _ in range(0, 100) {
+    assert!(b.undo());
+  }
+  assert!(text(&b) == original);
+}
+}
+
+}
+
+/**
+Versions of the structures that can be shared between tasks:
+the same designs, with the nodes in `Arc`s instead of `Rc`s.
+Members have to be Send as well as Freeze.
+*/
+pub mod sync {
+
+pub mod list {
+
+use extra::arc::Arc;
+use std::fmt;
+
+/// Persistent cons/nil list, like `persistent::list::List`, whose
+/// nodes are atomically reference-counted so that clones of it
+/// can be sent to other tasks.
+pub struct List<T> {
+  priv node : Arc<Node<T>>
+}
+
+pub enum Node<T> {
+  Nil,
+  Cons(T, List<T>)
+}
+
+// Cloning a list is just sharing its first node.
+impl<T: Send+Freeze> Clone for List<T> {
+  fn clone(&self) -> List<T> {
+    List{node: self.node.clone()}
+  }
+}
+
+impl<T: Send+Freeze> List<T> {
+  /// Create an empty list
+  pub fn nil() -> List<T> {
+    List::new(Nil)
+  }
+  /// Create a list from the head and the rest of the list
+  pub fn cons(x: T, xs: List<T>) -> List<T> {
+    List::new(Cons(x, xs))
+  }
+  /// Create a list from a node (you probably won't need this function).
+  pub fn new(node: Node<T>) -> List<T> {
+    List{node: Arc::new(node)}
+  }
+  /// Create a list of the vector's members, in the same order,
+  /// moving them rather than copying.
+  pub fn from_vec(members: ~[T]) -> List<T> {
+    let mut result = List::nil();
+    for x in members.move_rev_iter() {
+      result = List::cons(x, result);
+    }
+    result
+  }
+  /// The list's first node.
+  pub fn node<'t>(&'t self) -> &'t Node<T> {
+    self.node.get()
+  }
+  /// The first member, if the list isn't empty.
+  pub fn head<'t>(&'t self) -> Option<&'t T> {
+    match *self.node() { Nil => None, Cons(ref x, _) => Some(x) }
+  }
+  /// The rest of the list, if it isn't empty.
+  pub fn tail<'t>(&'t self) -> Option<&'t List<T>> {
+    match *self.node() { Nil => None, Cons(_, ref xs) => Some(xs) }
+  }
+  /// Iterate over the members, front to back.
+  pub fn iter<'t>(&'t self) -> ListIterator<'t, T> {
+    ListIterator{rest: self}
+  }
+}
+
+impl<T: Send+Freeze> Container for List<T> {
+  fn len(&self) -> uint {
+    self.iter().count()
+  }
+  fn is_empty(&self) -> bool {
+    match *self.node() { Nil => true, Cons(_, _) => false }
+  }
+}
+
+impl<T: Send+Freeze+Eq> Eq for List<T> {
+  fn eq(&self, other: &List<T>) -> bool {
+    let (mut a, mut b) = (self.iter(), other.iter());
+    loop {
+      match (a.next(), b.next()) {
+        (None, None) => return true,
+        (Some(x), Some(y)) => if *x != *y { return false },
+        _ => return false
+      }
+    }
+  }
+}
+
+impl<T: Send+Freeze> Default for List<T> {
+  fn default() -> List<T> {
+    List::nil()
+  }
+}
+
+impl<A: Send+Freeze> FromIterator<A> for List<A> {
+  fn from_iterator<T: Iterator<A>>(iter: &mut T) -> List<A> {
+    let members : ~[A] = iter.collect();
+    List::from_vec(members)
+  }
+}
+
+impl<T: Send+Freeze+fmt::Default> fmt::Default for List<T> {
+  fn fmt(list: &List<T>, f: &mut fmt::Formatter) {
+    write!(f.buf, "[");
+    for (i, x) in list.iter().enumerate() {
+      if i != 0 {
+        write!(f.buf, ", ");
+      }
+      write!(f.buf, "{}", *x);
+    }
+    write!(f.buf, "]");
+  }
+}
+
+/// Iterator over a sync List's members.
+pub struct ListIterator<'self, T> {
+  priv rest : &'self List<T>
+}
+
+impl<'self, T: Send+Freeze> Iterator<&'self T> for ListIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    match *self.rest.node() {
+      Nil => None,
+      Cons(ref x, ref xs) => {
+        self.rest = xs;
+        Some(x)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::List;
+use std::task;
+#[test]
+fn test_shared_between_tasks() {
+  let base = List::from_vec(~[1, 2, 3]);
+  let longer = List::cons(0, base.clone());
+  let sent = longer.clone();
+  let sum = match task::try(proc() { sent.iter().fold(0, |a, x| a + *x) }) {
+    Ok(sum) => sum,
+    Err(_) => fail!("the task failed")
+  };
+  assert!(sum == 6);
+  assert!(longer.len() == 4 && *longer.tail().unwrap() == base);
+  assert!(format!("{}", longer) == ~"[0, 1, 2, 3]");
+  let collected : List<int> = range(1, 4).collect();
+  assert!(collected == base);
+}
+
+// These only compile if the bound holds, so a change that makes
+// sync::list::List stop being Send or Freeze breaks the build here.
+fn assert_send<T: Send>() {}
+fn assert_freeze<T: Freeze>() {}
+
+#[test]
+fn test_thread_safety() {
+  assert_send::<List<int>>();
+  assert_freeze::<List<int>>();
+  assert_send::<List<~str>>();
+  // persistent::list::List is deliberately not Send: its Rc counts
+  // aren't atomic.  (There's no negative bound to pin that with;
+  // assert_send::<persistent::list::List<int>>() must not compile.)
+}
+}
+
+}
+
+}
+
+/**
+A persistent cons list whose nodes live in storage the caller provides,
+for when there's no heap at all.
+
+Nodes are never changed or freed once made, so every ArenaList stays
+valid (and unchanged) for as long as its Arena exists; the price is
+that the storage fills up.  Lists are just indices into the arena:
+they're only meaningful to the arena that made them.
+
+To build and tear down many short-lived lists without going to the
+allocator for every node, give the arena storage once (a
+`~[Slot<T>]` from `vec::from_fn` works, for any kind of member) and
+clear() it between batches: that drops every list at once and
+recycles all the slots.
+
+```
+let mut storage = [arenalist::Slot::new(), ..16];
+let mut arena = arenalist::Arena::new(storage.mut_slice(0, 16));
+let nil = arena.nil();
+let xs = arena.cons(1, nil).unwrap();
+```
+
+(Making the storage with `[Slot::new(), ..N]` needs members that are
+implicitly copyable, like numbers.)
+*/
+pub mod arenalist {
+
+/// Storage for one list node.
+pub struct Slot<T> {
+  priv node : Option<(T, ArenaList)>
+}
+
+impl<T> Slot<T> {
+  /// An unused slot.
+  pub fn new() -> Slot<T> {
+    Slot{node: None}
+  }
+}
+
+/// A list in some Arena.  Copying one is free.
+#[deriving(Clone, Eq)]
+pub struct ArenaList {
+  // Index of the first node, or None for the empty list.
+  priv first : Option<uint>,
+  // How many times the arena had been cleared when this was made.
+  priv generation : uint
+}
+
+/// Makes lists in a fixed amount of caller-provided storage.
+pub struct Arena<'self, T> {
+  priv slots : &'self mut [Slot<T>],
+  priv used : uint,
+  priv generation : uint
+}
+
+impl<'self, T> Arena<'self, T> {
+  /// Make lists in these slots.  Whatever the slots held is forgotten.
+  pub fn new(slots: &'self mut [Slot<T>]) -> Arena<'self, T> {
+    for slot in slots.mut_iter() {
+      slot.node = None;
+    }
+    Arena{slots: slots, used: 0, generation: 0}
+  }
+  /// The empty list (which takes no storage).
+  pub fn nil(&self) -> ArenaList {
+    ArenaList{first: None, generation: self.generation}
+  }
+  /// Create a list from the head and the rest of the list,
+  /// or give x back if the arena is full.
+  pub fn cons(&mut self, x: T, xs: ArenaList) -> Result<ArenaList, T> {
+    self.check_generation(xs);
+    if self.used == self.slots.len() {
+      return Err(x);
+    }
+    let i = self.used;
+    self.slots[i].node = Some((x, xs));
+    self.used += 1;
+    Ok(ArenaList{first: Some(i), generation: self.generation})
+  }
+  /// First member of the list, if it isn't empty.
+  pub fn head<'t>(&'t self, list: ArenaList) -> Option<&'t T> {
+    self.check_generation(list);
+    match list.first {
+      None => None,
+      Some(i) => match self.slots[i].node {
+        Some((ref x, _)) => Some(x),
+        None => fail!("ArenaList from a different arena")
+      }
+    }
+  }
+  /// The list after the first member, if it isn't empty.
+  pub fn tail(&self, list: ArenaList) -> Option<ArenaList> {
+    self.check_generation(list);
+    match list.first {
+      None => None,
+      Some(i) => match self.slots[i].node {
+        Some((_, xs)) => Some(xs),
+        None => fail!("ArenaList from a different arena")
+      }
+    }
+  }
+  /// Drop every list made so far, all at once, so that all the
+  /// storage can be used again.  Using a list made before the
+  /// clear fails (rather than finding whatever is in its slot now).
+  pub fn clear(&mut self) {
+    for slot in self.slots.mut_slice_to(self.used).mut_iter() {
+      slot.node = None;
+    }
+    self.used = 0;
+    self.generation += 1;
+  }
+  fn check_generation(&self, list: ArenaList) {
+    if list.generation != self.generation {
+      fail!("ArenaList from before its arena was cleared");
+    }
+  }
+  /// Iterate over the list's members.
+  pub fn iter<'t>(&'t self, list: ArenaList) -> ArenaIterator<'t, T> {
+    ArenaIterator{arena: self, rest: list}
+  }
+  /// How many nodes can still be made.
+  pub fn remaining(&self) -> uint {
+    self.slots.len() - self.used
+  }
+}
+
+/// Iterator over the members of an ArenaList.
+pub struct ArenaIterator<'self, T> {
+  priv arena : &'self Arena<'self, T>,
+  priv rest : ArenaList
+}
+
+impl<'self, T> Iterator<&'self T> for ArenaIterator<'self, T> {
+  fn next(&mut self) -> Option<&'self T> {
+    let x = self.arena.head(self.rest);
+    if x.is_some() {
+      self.rest = self.arena.tail(self.rest).unwrap();
+    }
+    x
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::{Arena, Slot};
+#[test]
+fn test_arena_list() {
+  let mut storage = [Slot::new(), ..3];
+  let mut arena = Arena::new(storage.mut_slice(0, 3));
+  let p0 = arena.nil();
+  let p1 = arena.cons(1, p0).unwrap();
+  let p2a = arena.cons(2, p1).unwrap();
+  let p2b = arena.cons(3, p1).unwrap();
+  assert!(arena.cons(4, p2b) == Err(4));
+  assert!(arena.remaining() == 0);
+  let a : ~[int] = arena.iter(p2a).map(|x| *x).collect();
+  let b : ~[int] = arena.iter(p2b).map(|x| *x).collect();
+  assert!(a == ~[2, 1]);
+  assert!(b == ~[3, 1]);
+  assert!(arena.head(p0).is_none());
+  assert!(arena.tail(p2a) == Some(p1));
+}
+#[test]
+fn test_clear() {
+  use std::vec;
+  let mut storage : ~[Slot<~str>] = vec::from_fn(2, |_| Slot::new());
+  let mut arena = Arena::new(storage.mut_slice(0, 2));
+  for round in range(0, 3) {
+    let nil = arena.nil();
+    let p1 = arena.cons(round.to_str(), nil).unwrap();
+    let p2 = arena.cons(~"x", p1).unwrap();
+    assert!(arena.remaining() == 0);
+    let members : ~[~str] = arena.iter(p2).map(|s| s.clone()).collect();
+    assert!(members == ~[~"x", round.to_str()]);
+    arena.clear();
+    assert!(arena.remaining() == 2);
+  }
+}
+#[test]
+#[should_fail]
+fn test_use_after_clear() {
+  let mut storage = [Slot::new(), ..2];
+  let mut arena = Arena::new(storage.mut_slice(0, 2));
+  let nil = arena.nil();
+  let p1 = arena.cons(1, nil).unwrap();
+  arena.clear();
+  arena.head(p1);
+}
+}
+
+}
+
+pub mod ordfloat {
+
+use std::cast;
+use std::to_bytes::{IterBytes, Cb};
+
+/// An f64 with a total order (IEEE 754's totalOrder), so it can be a key
+/// in structures that need TotalOrd:
+/// -NaN < -infinity < ... < -0.0 < +0.0 < ... < +infinity < +NaN.
+/// Unlike with plain f64 comparisons, every NaN equals itself (and
+/// only NaNs with the very same bits), and -0.0 and +0.0 differ.
+#[deriving(Clone)]
+pub struct TotalF64(f64);
+
+impl TotalF64 {
+  /// Wrap a float.
+  pub fn new(x: f64) -> TotalF64 {
+    TotalF64(x)
+  }
+  /// The wrapped float.
+  pub fn get(&self) -> f64 {
+    let TotalF64(x) = *self;
+    x
+  }
+  // Signed integers that compare the way totalOrder says
+  // the floats do: negative floats' magnitude bits are flipped
+  // so that bigger magnitudes come first.
+  fn key(&self) -> i64 {
+    let bits : i64 = unsafe { cast::transmute(self.get()) };
+    bits ^ ((((bits >> 63) as u64) >> 1) as i64)
+  }
+}
+
+impl TotalEq for TotalF64 {
+  fn equals(&self, other: &TotalF64) -> bool { self.key() == other.key() }
+}
+
+impl TotalOrd for TotalF64 {
+  fn cmp(&self, other: &TotalF64) -> Ordering { self.key().cmp(&other.key()) }
+}
+
+impl Eq for TotalF64 {
+  fn eq(&self, other: &TotalF64) -> bool { self.key() == other.key() }
+}
+
+impl Ord for TotalF64 {
+  fn lt(&self, other: &TotalF64) -> bool { self.key() < other.key() }
+}
+
+impl IterBytes for TotalF64 {
+  fn iter_bytes(&self, lsb0: bool, f: Cb) -> bool {
+    self.key().iter_bytes(lsb0, f)
+  }
+}
+
+#[cfg(test)]
+mod test {
+use super::TotalF64;
+use std::f64;
+use std::cast;
+
+fn from_bits(bits: u64) -> TotalF64 {
+  TotalF64::new(unsafe { cast::transmute(bits) })
+}
+
+#[test]
+fn test_total_order() {
+  // f64::NaN's sign bit depends on the platform, so make NaNs from bits.
+  let nan = from_bits(0x7ff8000000000000u64);
+  let neg_nan = from_bits(0xfff8000000000000u64);
+  let inf = TotalF64::new(f64::infinity);
+  let neg_inf = TotalF64::new(f64::neg_infinity);
+  let zero = TotalF64::new(0.0);
+  let neg_zero = TotalF64::new(-0.0);
+  let sorted = ~[neg_nan, neg_inf, TotalF64::new(-1.5), neg_zero, zero,
+                 TotalF64::new(1e-300), TotalF64::new(2.0), inf, nan];
+  for (a, b) in sorted.iter().zip(sorted.slice_from(1).iter()) {
+    assert!(*a < *b);
+    assert!(a.cmp(b) == Less);
+  }
+  assert!(nan == nan);
+  let other_nan = from_bits(0x7ff8000000000001u64);
+  assert!(nan != other_nan && nan.cmp(&other_nan) == Less);
+  assert!(other_nan == other_nan);
+  assert!(neg_zero != zero);
+  assert!(TotalF64::new(2.0).get() == 2.0);
+}
+}
+
+}
+
+/**
+Generic folds and unfolds over List (catamorphisms and friends),
+and folds over the shapes of a TreeMap's tree and an IntMap's trie.
+
+None of these use recursion, so they are safe on lists of any length,
+even where the natural way to write the algorithm would recurse once
+per member.
+*/
+pub mod recursion {
+
+use persistent::list::{List, ListBuilder, Nil, Cons};
+use persistent::treemap::TreeMap;
+use persistent::treemap::view::{Empty, Branch};
+use persistent::intmap::IntMap;
+use intmap_view = persistent::intmap::view;
+
+/// Catamorphism (right fold): replaces every Cons with cons and the
+/// Nil with nil, so cons gets the member and the folded rest.
+pub fn cata<T, R>(list: &List<T>, nil: R, cons: |&T, R| -> R) -> R {
+  let members : ~[&T] = list.iter().collect();
+  let mut acc = nil;
+  for x in members.rev_iter() {
+    acc = cons(*x, acc);
+  }
+  acc
+}
+
+/// Paramorphism: like cata, but cons also gets the rest of the list
+/// itself, not just the folded rest.
+pub fn para<T, R>(list: &List<T>, nil: R, cons: |&T, &List<T>, R| -> R) -> R {
+  let mut spine : ~[(&T, &List<T>)] = ~[];
+  let mut rest = list;
+  loop {
+    match *rest.node() {
+      Nil => break,
+      Cons(ref x, ref xs) => {
+        spine.push((x, xs));
+        rest = xs;
+      }
+    }
+  }
+  let mut acc = nil;
+  for &(x, xs) in spine.rev_iter() {
+    acc = cons(x, xs, acc);
+  }
+  acc
+}
+
+/// Anamorphism (unfold): builds a list from a seed, where step gives
+/// the next member and the next seed, or None to end the list.
+pub fn ana<S, T: Freeze>(seed: S, step: |S| -> Option<(T, S)>) -> List<T> {
+  let mut result = ListBuilder::new();
+  let mut next = step(seed);
+  while next.is_some() {
+    let (x, s) = next.unwrap();
+    result.push(x);
+    next = step(s);
+  }
+  result.finish()
+}
+
+/// Hylomorphism: the same as cata(ana(seed, step), nil, cons),
+/// without building the list in between.
+pub fn hylo<S, T, R>(seed: S, step: |S| -> Option<(T, S)>, nil: R, cons: |T, R| -> R) -> R {
+  let mut members = ~[];
+  let mut next = step(seed);
+  while next.is_some() {
+    let (x, s) = next.unwrap();
+    members.push(x);
+    next = step(s);
+  }
+  let mut acc = nil;
+  for x in members.move_rev_iter() {
+    acc = cons(x, acc);
+  }
+  acc
+}
+
+// Work left for tree_para: a subtree to fold, or the folded subtrees
+// of a node (on the results stack) to combine.
+enum TreeStep<K, V> {
+  Visit(TreeMap<K, V>),
+  Combine(TreeMap<K, V>)
+}
+
+/// Catamorphism over a TreeMap's tree (through its view): replaces
+/// every empty subtree with empty() and every node with branch of its
+/// entry and its folded left and right subtrees.  The result depends
+/// on the tree's shape, not only on its entries, unless branch
+/// doesn't care how they're grouped (like a sum).
+pub fn tree_cata<K, V, R>(map: &TreeMap<K, V>, empty: || -> R, branch: |&K, &V, R, R| -> R) -> R {
+  tree_para(map, empty, |k, v, _, l, _, r| branch(k, v, l, r))
+}
+
+/// Paramorphism over a TreeMap's tree: like tree_cata, but branch
+/// also gets the left and right subtrees themselves, each before its
+/// folded result.
+pub fn tree_para<K, V, R>(map: &TreeMap<K, V>, empty: || -> R,
+                          branch: |&K, &V, &TreeMap<K, V>, R, &TreeMap<K, V>, R| -> R) -> R {
+  let mut todo = ~[Visit(map.clone())];
+  let mut results : ~[R] = ~[];
+  while !todo.is_empty() {
+    let n = todo.len();
+    let step = todo.swap_remove(n - 1);
+    match step {
+      Visit(subtree) => match subtree.view() {
+        Empty => results.push(empty()),
+        Branch(_, _, left, right) => {
+          todo.push(Combine(subtree.clone()));
+          todo.push(Visit(right));
+          todo.push(Visit(left));
+        }
+      },
+      Combine(subtree) => {
+        let n = results.len();
+        let right = results.swap_remove(n - 1);
+        let left = results.swap_remove(n - 2);
+        match subtree.view() {
+          Branch(k, v, ref l, ref r) => results.push(branch(k, v, l, left, r, right)),
+          Empty => fail!("tree_para: combining an empty tree")
+        }
+      }
+    }
+  }
+  let n = results.len();
+  results.swap_remove(n - 1)
+}
+
+// Work left for intmap_cata: a trie to fold, or two folded halves
+// (on the results stack) to combine.
+enum IntMapStep<V> {
+  VisitTrie(IntMap<V>),
+  CombineHalves
+}
+
+/// Catamorphism over an IntMap's trie (through its view): replaces
+/// the empty map with empty(), every entry with tip of it, and every
+/// branch with bin of its folded halves (keys with the branching bit
+/// clear, then set).
+pub fn intmap_cata<V, R>(map: &IntMap<V>, empty: || -> R, tip: |uint, &V| -> R, bin: |R, R| -> R) -> R {
+  let mut todo = ~[VisitTrie(map.clone())];
+  let mut results : ~[R] = ~[];
+  while !todo.is_empty() {
+    let n = todo.len();
+    match todo.swap_remove(n - 1) {
+      VisitTrie(trie) => match trie.view() {
+        intmap_view::Empty => results.push(empty()),
+        intmap_view::Tip(k, v) => results.push(tip(k, v)),
+        intmap_view::Bin(low, high) => {
+          todo.push(CombineHalves);
+          todo.push(VisitTrie(high));
+          todo.push(VisitTrie(low));
+        }
+      },
+      CombineHalves => {
+        let n = results.len();
+        let high = results.swap_remove(n - 1);
+        let low = results.swap_remove(n - 2);
+        results.push(bin(low, high));
+      }
+    }
+  }
+  let n = results.len();
+  results.swap_remove(n - 1)
+}
+
+#[cfg(test)]
+mod test {
+use super::{cata, para, ana, hylo, tree_cata, tree_para, intmap_cata};
+use persistent::list::List;
+use persistent::treemap::TreeMap;
+use persistent::intmap::IntMap;
+#[test]
+fn test_schemes() {
+  let p3 = List::from_vec(~[1, 2, 3]);
+  assert!(cata(&p3, ~"", |x, s| format!("{}{}", *x, s)) == ~"123");
+  // Each member with the length of what comes after it:
+  let lens = para(&p3, ~[], |x, xs, acc| ~[(*x, xs.len())] + acc);
+  assert!(lens == ~[(1, 2), (2, 1), (3, 0)]);
+  let countdown = ana(3, |n| if n == 0 { None } else { Some((n, n - 1)) });
+  assert!(countdown == List::from_vec(~[3, 2, 1]));
+  assert!(hylo(4, |n| if n == 0 { None } else { Some((n, n - 1)) }, 1, |x, acc| x * acc) == 24);
+  let long = ana(0, |n| if n == 10000 { None } else { Some((n, n + 1)) });
+  assert!(cata(&long, 0, |_, acc| acc + 1) == 10000);
+}
+#[test]
+fn test_tree_cata() {
+  let mut m = TreeMap::new();
+  for i in range(1, 101) {
+    m = m.insert(i, i * 2);
+  }
+  assert!(tree_cata(&m, || 0, |_, v, l, r| *v + l + r) == 10100);
+  let height = tree_cata(&m, || 0u, |_, _, l, r| 1 + if l > r { l } else { r });
+  assert!(height >= 7 && height <= 9);
+  let in_order = tree_cata(&m, || ~[], |k, _, l, r| l + ~[*k] + r);
+  let expected : ~[int] = range(1, 101).collect();
+  assert!(in_order == expected);
+  let empty : TreeMap<int, int> = TreeMap::new();
+  assert!(tree_cata(&empty, || 5, |_, _, l, r| l + r) == 5);
+}
+#[test]
+fn test_tree_para() {
+  let m : TreeMap<int, ()> = range(0, 1000).map(|i| (i * 7 % 1000, ())).collect();
+  // Every node's key is between its subtrees' keys.
+  let ordered = tree_para(&m, || true, |k, _, left, l, right, r| {
+    l && r && left.iter().all(|(x, _)| x < k) && right.iter().all(|(x, _)| x > k)
+  });
+  assert!(ordered);
+  // Subtree sizes passed in agree with the sizes folded up.
+  let size = tree_para(&m, || 0u, |_, _, left, l, right, r| {
+    assert!(left.len() == l && right.len() == r);
+    l + r + 1
+  });
+  assert!(size == 1000);
+}
+#[test]
+fn test_intmap_cata() {
+  let m : IntMap<uint> = range(0u, 100).map(|i| (i * 3, i)).collect();
+  assert!(intmap_cata(&m, || 0, |_, v| *v, |l, h| l + h) == 4950);
+  let keys = intmap_cata(&m, || ~[], |k, _| ~[k], |l, h| l + h);
+  let expected : ~[uint] = range(0u, 100).map(|i| i * 3).collect();
+  assert!(keys == expected);
+  let depth = intmap_cata(&m, || 0u, |_, _| 1, |l, h| 1 + if l > h { l } else { h });
+  assert!(depth > 7 && depth <= 10);
+  let empty : IntMap<uint> = IntMap::new();
+  assert!(intmap_cata(&empty, || 5, |_, _| 1, |l, h| l + h) == 5);
+}
+}
+
+}
+
+pub mod incremental {
+
+use persistent::list::{List, Nil, Cons};
+use std::hashmap::HashMap;
+use std::ptr;
+
+/// Remembers the result of a right fold for every list node it has
+/// folded, so that folding a new version of a list only does work for
+/// the nodes in front of the part it shares with versions already
+/// folded.  A memo must always be used with the same init and f.
+///
+/// The memo keeps every node it has folded alive; clear it (or drop it)
+/// when old versions are no longer interesting.
+pub struct FoldMemo<T, R> {
+  // Keyed by node address.  The List is held so that the address
+  // can't be reused by a different node while it is in here.
+  priv results : HashMap<uint, (List<T>, R)>
+}
+
+impl<T: Clone+Freeze, R: Clone> FoldMemo<T, R> {
+  /// Create a memo that remembers nothing yet.
+  pub fn new() -> FoldMemo<T, R> {
+    FoldMemo{results: HashMap::new()}
+  }
+  /// Right fold: f(first, f(second, ... f(last, init))), reusing the
+  /// remembered result for the longest suffix that has been folded before.
+  pub fn foldr(&mut self, list: &List<T>, init: &R, f: |&T, &R| -> R) -> R {
+    let mut spine : ~[&List<T>] = ~[];
+    let mut rest = list;
+    let mut known = None;
+    while known.is_none() {
+      match self.results.find(&address(rest)) {
+        Some(&(_, ref r)) => { known = Some(r.clone()); }
+        None => match *rest.node() {
+          Nil => { known = Some(init.clone()); }
+          Cons(_, ref xs) => {
+            spine.push(rest);
+            rest = xs;
+          }
+        }
+      }
+    }
+    let mut acc = known.unwrap();
+    for node in spine.rev_iter() {
+      match *node.node() {
+        Nil => fail!(),
+        Cons(ref x, _) => {
+          acc = f(x, &acc);
+          self.results.insert(address(*node), ((*node).clone(), acc.clone()));
+        }
+      }
+    }
+    acc
+  }
+  /// How many list nodes have a remembered result.
+  pub fn len(&self) -> uint {
+    self.results.len()
+  }
+  /// Forget everything, releasing the nodes it kept alive.
+  pub fn clear(&mut self) {
+    self.results.clear();
+  }
+}
+
+fn address<T>(list: &List<T>) -> uint {
+  ptr::to_unsafe_ptr(list.node()) as uint
+}
+
+#[cfg(test)]
+mod test {
+use super::FoldMemo;
+use persistent::list::List;
+#[test]
+fn test_foldr() {
+  let mut memo = FoldMemo::new();
+  let mut calls = 0;
+  let v1 = List::from_vec(~[1, 2, 3]);
+  assert!(memo.foldr(&v1, &0, |x, acc| { calls += 1; *x + *acc }) == 6);
+  assert!(calls == 3);
+  let v2 = List::cons(10, v1.clone());
+  assert!(memo.foldr(&v2, &0, |x, acc| { calls += 1; *x + *acc }) == 16);
+  assert!(calls == 4);
+  // Equal but not shared, so nothing is reused:
+  let v3 = List::from_vec(~[1, 2, 3]);
+  assert!(memo.foldr(&v3, &0, |x, acc| { calls += 1; *x + *acc }) == 6);
+  assert!(calls == 7);
+  assert!(memo.len() == 7);
+  memo.clear();
+  assert!(memo.len() == 0);
+}
 }
 
 }
@@ -189,12 +9234,7 @@ fn main() {
   for i in p2b.iter() {
     println(format!("{}", *i))
   }
-  // Is there a way not to use the temporary var name here?
-  // "for i in p2b.reverse().iter()" didn't work (I guess the
-  // lifetime of p2b.reverse() there didn't include the body of the
-  // for loop).
-  let x = p2b.reverse();
-  for i in x.iter() {
+  for i in p2b.rev_iter() {
     println(format!("{}", *i))
   }
 }